@@ -2,12 +2,36 @@
 
 set -e
 
+# With signing.pem (an Ed25519 key) present, the loader only boots kernels it signed
+if [[ -f signing.pem ]]; then
+    export UNIX_V11_PUBKEY=$(openssl pkey -in signing.pem -pubout -outform DER | tail -c 32 | xxd -p -c 32)
+fi
+
 cd efi;    cargo build -r --target aarch64-unknown-uefi; cd ..
 cd kernel; cargo build -r --target aarch64-unknown-none; cd ..
 
 mkdir -p dist/efi/boot
 cp target/aarch64-unknown-uefi/release/unix-v11-efi.efi dist/efi/boot/bootaa64.efi
-cp target/aarch64-unknown-none/release/unix-v11-kernel dist/unix-v11
+
+# Seal the kernel with notes the loader checks: SHA-256 of the image, then its signature
+kernel=target/aarch64-unknown-none/release/unix-v11-kernel
+shasum -a 256 $kernel | cut -d' ' -f1 | xxd -r -p > target/unix-v11.sha256
+{
+    cat $kernel
+    printf '\x08\x00\x00\x00\x20\x00\x00\x00\x01\x00\x00\x00UNIXv11\x00'
+    cat target/unix-v11.sha256
+    if [[ -f signing.pem ]]; then
+        printf '\x08\x00\x00\x00\x40\x00\x00\x00\x02\x00\x00\x00UNIXv11\x00'
+        openssl pkeyutl -sign -inkey signing.pem -rawin -in target/unix-v11.sha256
+    fi
+} > target/unix-v11.sealed
+
+# Compress when lz4 is around; the loader recognises the frame and inflates it
+if (( $+commands[lz4] )); then
+    lz4 -q -f -9 --content-size target/unix-v11.sealed dist/unix-v11
+else
+    cp target/unix-v11.sealed dist/unix-v11
+fi
 
 dd if=/dev/zero of=unixv11.disk bs=1m count=64
 diskno=$(hdiutil attach -imagekey diskimage-class=CRawDiskImage -nomount unixv11.disk | head -n 1 | awk '{print $1}')
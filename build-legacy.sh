@@ -0,0 +1,28 @@
+#!/bin/zsh
+
+set -e
+
+cd legacy; cargo build -r; cd ..
+cd kernel; cargo build -r --target x86_64-unknown-none; cd ..
+
+# The stage runs where the boot sector puts it, so it goes on disk as a flat image
+host=$(rustc -vV | sed -n 's/^host: //p')
+objcopy=$(rustc --print sysroot)/lib/rustlib/$host/bin/rust-objcopy
+$objcopy -O binary target/x86_64-unknown-none/release/unix-v11-legacy target/unix-v11-legacy.bin
+
+kernel=target/x86_64-unknown-none/release/unix-v11-kernel
+stage2_sectors=$(( ($(wc -c < target/unix-v11-legacy.bin) + 511) / 512 ))
+kernel_sectors=$(( ($(wc -c < $kernel) + 511) / 512 ))
+kernel_lba=$(( 1 + stage2_sectors ))
+
+nasm -f bin -DSTAGE2_SECTORS=$stage2_sectors -DKERNEL_LBA=$kernel_lba -DKERNEL_SECTORS=$kernel_sectors \
+ Ignitor_legacy.asm -o target/ignitor.bin
+
+# Boot sector, stage, kernel, back to back
+dd if=/dev/zero of=unixv11-legacy.disk bs=1M count=64
+dd if=target/ignitor.bin of=unixv11-legacy.disk conv=notrunc
+dd if=target/unix-v11-legacy.bin of=unixv11-legacy.disk bs=512 seek=1 conv=notrunc
+dd if=$kernel of=unixv11-legacy.disk bs=512 seek=$kernel_lba conv=notrunc
+
+qemu-system-x86_64 -cpu Skylake-Client -machine q35 -smp 1 \
+ -drive file=unixv11-legacy.disk,format=raw -m 512M -serial stdio
@@ -1,5 +1,8 @@
 use aarch64_cpu::{asm::wfi, registers::DAIF};
 use tock_registers::interfaces::{Readable, Writeable};
+use xmas_elf::header::Machine;
+
+pub const MACHINE: Machine = Machine::AArch64;
 
 pub fn halt() {
     DAIF.set(DAIF.get() | 0b1111);
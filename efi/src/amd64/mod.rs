@@ -1,4 +1,7 @@
 use x86_64::instructions::{hlt, interrupts};
+use xmas_elf::header::Machine;
+
+pub const MACHINE: Machine = Machine::X86_64;
 
 pub fn halt() {
     interrupts::disable();
@@ -0,0 +1,59 @@
+use crate::menu;
+use uefi::{
+    boot::{image_handle, open_protocol_exclusive}, cstr16,
+    proto::{loaded_image::LoadedImage, media::file::{Directory, File, FileAttribute, FileMode}}
+};
+
+pub const CMDLINE_MAX: usize = 1024;
+
+/// Fills `buf` with the kernel command line and returns its length.
+/// UEFI LoadOptions win over `\unix-v11.cfg`; either may be absent.
+/// Boot menu directives in the file are not part of the command line.
+pub fn load(root: &mut Directory, buf: &mut [u8; CMDLINE_MAX]) -> usize {
+    let len = load_options(buf);
+    if len > 0 { return len; }
+    return read_cfg(root, buf);
+}
+
+fn load_options(buf: &mut [u8]) -> usize {
+    let Ok(image) = open_protocol_exclusive::<LoadedImage>(image_handle()) else { return 0; };
+    let Ok(options) = image.load_options_as_cstr16() else { return 0; };
+
+    let mut len = 0;
+    for &ch in options.iter().take(buf.len()) {
+        let ch = u16::from(ch);
+        buf[len] = if ch < 0x80 { ch as u8 } else { b'?' };
+        len += 1;
+    }
+
+    // The UEFI shell passes the image path as the first word
+    let first_end = buf[..len].iter().position(|&b| b == b' ').unwrap_or(len);
+    if first_end >= 4 && buf[first_end - 4..first_end].eq_ignore_ascii_case(b".efi") {
+        let rest = (first_end + 1).min(len);
+        buf.copy_within(rest..len, 0);
+        len -= rest;
+    }
+    return len;
+}
+
+fn read_cfg(root: &mut Directory, buf: &mut [u8]) -> usize {
+    let file = root.open(cstr16!("\\unix-v11.cfg"), FileMode::Read, FileAttribute::empty());
+    let Some(mut file) = file.ok().and_then(|file| file.into_regular_file()) else { return 0; };
+    let Ok(read) = file.read(buf) else { return 0; };
+
+    // Join non-comment lines with spaces, compacting in place
+    let (mut src, mut dst) = (0, 0);
+    while src < read {
+        let end = buf[src..read].iter().position(|&b| b == b'\n').map_or(read, |i| src + i);
+        let line = &buf[src..end];
+        let start = src + line.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(line.len());
+        let stop = src + line.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(0, |i| i + 1);
+        if start < stop && buf[start] != b'#' && !menu::is_directive(&buf[start..stop]) {
+            if dst > 0 { buf[dst] = b' '; dst += 1; }
+            buf.copy_within(start..stop, dst);
+            dst += stop - start;
+        }
+        src = end + 1;
+    }
+    return dst;
+}
@@ -0,0 +1,75 @@
+use crate::{path::Path, reloc::RelocError};
+use core::fmt;
+use uefi::Status;
+use unix_v11_ember::EmberError;
+use xmas_elf::header::Machine;
+
+#[derive(Debug)]
+pub enum LoaderError {
+    Volume(Status),
+    NotFound(Path),
+    NoKernels(Path),
+    Read(Path, Status),
+    EmptyFile(Path),
+    OutOfMemory(usize),
+    Decompress(&'static str),
+    Elf(&'static str),
+    Machine(Machine),
+    NotDynamic,
+    NoSegments,
+    SegmentBounds(usize),
+    TooManySegments(usize),
+    Entry(u64),
+    Integrity(&'static str),
+    Reloc(RelocError),
+    Ember(EmberError)
+}
+
+impl LoaderError {
+    /// The status handed back to firmware, so it can move on to the next boot option.
+    pub fn status(&self) -> Status {
+        match self {
+            Self::Volume(status) | Self::Read(_, status) => *status,
+            Self::NotFound(_) | Self::NoKernels(_) => Status::NOT_FOUND,
+            Self::OutOfMemory(_) => Status::OUT_OF_RESOURCES,
+            Self::Machine(_) => Status::UNSUPPORTED,
+            Self::Integrity(_) => Status::SECURITY_VIOLATION,
+            Self::Ember(EmberError::Overflow) => Status::BUFFER_TOO_SMALL,
+            _ => Status::LOAD_ERROR
+        }
+    }
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Volume(status) => write!(f, "cannot open the boot volume ({:?})", status),
+            Self::NotFound(path) => write!(f, "{} not found on the boot volume", path),
+            Self::NoKernels(path) => write!(f, "no kernel images in {}", path),
+            Self::Read(path, status) => write!(f, "cannot read {} ({:?})", path, status),
+            Self::EmptyFile(path) => write!(f, "{} is empty", path),
+            Self::OutOfMemory(pages) => write!(f, "cannot allocate {} pages", pages),
+            Self::Decompress(reason) => write!(f, "cannot decompress kernel: {}", reason),
+            Self::Elf(reason) => write!(f, "kernel is not a valid ELF file: {}", reason),
+            Self::Machine(machine) => write!(
+                f, "kernel is built for {:?}, this loader runs on {:?}", machine, crate::arch::MACHINE
+            ),
+            Self::NotDynamic => write!(f, "kernel is not position independent (ET_DYN)"),
+            Self::NoSegments => write!(f, "kernel has no loadable segments"),
+            Self::SegmentBounds(index) => write!(f, "kernel segment {} lies outside the file", index),
+            Self::TooManySegments(max) => write!(f, "kernel has more than {} loadable segments", max),
+            Self::Entry(entry) => write!(f, "kernel entry point {:#x} lies outside the image", entry),
+            Self::Integrity(reason) => write!(f, "kernel failed its integrity check: {}", reason),
+            Self::Reloc(err) => write!(f, "cannot relocate kernel: {}", err),
+            Self::Ember(err) => write!(f, "cannot build the handoff: {}", err)
+        }
+    }
+}
+
+impl From<RelocError> for LoaderError {
+    fn from(err: RelocError) -> Self { Self::Reloc(err) }
+}
+
+impl From<EmberError> for LoaderError {
+    fn from(err: EmberError) -> Self { Self::Ember(err) }
+}
@@ -0,0 +1,34 @@
+use uefi::{
+    boot::{get_handle_for_protocol, open_protocol_exclusive},
+    proto::console::gop::{GraphicsOutput, PixelFormat}
+};
+use unix_v11_ember::Framebuffer;
+
+/// Describes the current GOP mode, or `None` if there is no linear framebuffer.
+/// Opening GOP exclusively may detach the firmware console, so call this last.
+pub fn query() -> Option<Framebuffer> {
+    let handle = get_handle_for_protocol::<GraphicsOutput>().ok()?;
+    let mut gop = open_protocol_exclusive::<GraphicsOutput>(handle).ok()?;
+    let info = gop.current_mode_info();
+
+    let (red_mask, green_mask, blue_mask) = match info.pixel_format() {
+        PixelFormat::Rgb => (0x0000ff, 0x00ff00, 0xff0000),
+        PixelFormat::Bgr => (0xff0000, 0x00ff00, 0x0000ff),
+        PixelFormat::Bitmask => {
+            let mask = info.pixel_bitmask()?;
+            (mask.red, mask.green, mask.blue)
+        },
+        PixelFormat::BltOnly => return None
+    };
+
+    let (width, height) = info.resolution();
+    let mut frame_buffer = gop.frame_buffer();
+    return Some(Framebuffer {
+        base: frame_buffer.as_mut_ptr() as u64,
+        size: frame_buffer.size() as u64,
+        width: width as u32,
+        height: height as u32,
+        stride: info.stride() as u32,
+        red_mask, green_mask, blue_mask
+    });
+}
@@ -0,0 +1,116 @@
+// LZ4 frame decompression, as written by `lz4 --content-size`.
+// Checksums are skipped: the kernel's own SHA-256 note covers its contents.
+
+const FRAME_MAGIC: u32 = 0x184d2204;
+
+const FLG_VERSION: u8          = 0b11 << 6;
+const FLG_BLOCK_CHECKSUM: u8   = 1 << 4;
+const FLG_CONTENT_SIZE: u8     = 1 << 3;
+const FLG_CONTENT_CHECKSUM: u8 = 1 << 2;
+const FLG_DICT_ID: u8          = 1 << 0;
+const VERSION_01: u8           = 0b01 << 6;
+
+const BLOCK_UNCOMPRESSED: u32 = 1 << 31;
+const MIN_MATCH: usize = 4;
+
+struct Frame {
+    flags: u8,
+    content_size: usize,
+    data_start: usize
+}
+
+fn read_u32(src: &[u8], offset: usize) -> Result<u32, &'static str> {
+    let bytes = src.get(offset..offset + 4).ok_or("truncated frame")?;
+    return Ok(u32::from_le_bytes(bytes.try_into().unwrap()));
+}
+
+pub fn is_frame(src: &[u8]) -> bool { read_u32(src, 0) == Ok(FRAME_MAGIC) }
+
+fn parse_header(src: &[u8]) -> Result<Frame, &'static str> {
+    if !is_frame(src) { return Err("not an LZ4 frame"); }
+    let flags = *src.get(4).ok_or("truncated frame")?;
+    if flags & FLG_VERSION != VERSION_01 { return Err("unsupported frame version"); }
+    if flags & FLG_DICT_ID != 0 { return Err("frames with a dictionary are not supported"); }
+    if flags & FLG_CONTENT_SIZE == 0 { return Err("frame has no content size, compress with --content-size"); }
+
+    let size = src.get(6..14).ok_or("truncated frame")?;
+    let content_size = u64::from_le_bytes(size.try_into().unwrap());
+    return Ok(Frame {
+        flags,
+        content_size: usize::try_from(content_size).map_err(|_| "content size too large")?,
+        data_start: 15 // magic, FLG, BD, content size, HC
+    });
+}
+
+/// Size of the data once decompressed, taken from the frame header.
+pub fn content_size(src: &[u8]) -> Result<usize, &'static str> {
+    return Ok(parse_header(src)?.content_size);
+}
+
+fn read_length(src: &[u8], pos: &mut usize, mut len: usize) -> Result<usize, &'static str> {
+    if len != 15 { return Ok(len); }
+    loop {
+        let byte = *src.get(*pos).ok_or("truncated block")?;
+        *pos += 1;
+        len = len.checked_add(byte as usize).ok_or("length overflow")?;
+        if byte != 255 { return Ok(len); }
+    }
+}
+
+/// Decodes one block into `dst[out..]`; matches may reach back into earlier blocks.
+fn decompress_block(src: &[u8], dst: &mut [u8], mut out: usize) -> Result<usize, &'static str> {
+    let mut pos = 0;
+    while pos < src.len() {
+        let token = src[pos];
+        pos += 1;
+
+        let literals = read_length(src, &mut pos, (token >> 4) as usize)?;
+        let literal = src.get(pos..pos + literals).ok_or("truncated literals")?;
+        dst.get_mut(out..out + literals).ok_or("output overrun")?.copy_from_slice(literal);
+        pos += literals;
+        out += literals;
+        if pos == src.len() { break; } // The last sequence carries literals only
+
+        let offset = src.get(pos..pos + 2).ok_or("truncated match")?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        pos += 2;
+        if offset == 0 || offset > out { return Err("match offset out of range"); }
+
+        let len = read_length(src, &mut pos, (token & 0xf) as usize)? + MIN_MATCH;
+        if out + len > dst.len() { return Err("output overrun"); }
+        // Matches may overlap their own output, so copy forwards byte by byte
+        for i in out..out + len { dst[i] = dst[i - offset]; }
+        out += len;
+    }
+    return Ok(out);
+}
+
+/// Decompresses a whole frame into `dst`, which must hold `content_size` bytes.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Result<usize, &'static str> {
+    let frame = parse_header(src)?;
+    if dst.len() < frame.content_size { return Err("output buffer too small"); }
+    let dst = &mut dst[..frame.content_size];
+
+    let mut pos = frame.data_start;
+    let mut out = 0;
+    loop {
+        let header = read_u32(src, pos)?;
+        pos += 4;
+        if header == 0 { break; } // End mark
+
+        let size = (header & !BLOCK_UNCOMPRESSED) as usize;
+        let block = src.get(pos..pos + size).ok_or("truncated block")?;
+        if header & BLOCK_UNCOMPRESSED != 0 {
+            dst.get_mut(out..out + size).ok_or("output overrun")?.copy_from_slice(block);
+            out += size;
+        } else {
+            out = decompress_block(block, dst, out)?;
+        }
+        pos += size;
+        if frame.flags & FLG_BLOCK_CHECKSUM != 0 { pos += 4; }
+    }
+    if frame.flags & FLG_CONTENT_CHECKSUM != 0 { read_u32(src, pos)?; }
+
+    if out != frame.content_size { return Err("decompressed size does not match the frame header"); }
+    return Ok(out);
+}
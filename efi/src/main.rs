@@ -7,19 +7,30 @@
 #![no_std]
 #![no_main]
 
-mod ember;
+mod cmdline;
+mod error;
+mod gop;
+mod lz4;
+mod menu;
+mod path;
+mod reloc;
+mod verify;
 
-use core::panic::PanicInfo;
-use ember::{Ember, RAMDescriptor};
+use core::{convert::Infallible, panic::PanicInfo, ptr::NonNull};
+use error::LoaderError;
+use path::{Path, PATH_MAX};
 use uefi::{
-    boot::{allocate_pages, exit_boot_services, get_image_file_system, image_handle, AllocateType, MemoryType},
-    cstr16, entry, mem::memory_map::MemoryMap, println,
-    proto::media::file::{File, FileAttribute, FileInfo, FileMode},
-    table::{cfg, system_table_raw}, Status
+    boot::{self, allocate_pages, exit_boot_services, get_image_file_system, image_handle, AllocateType, MemoryType},
+    entry, guid, mem::memory_map::MemoryMap, println,
+    proto::media::file::{Directory, File, FileAttribute, FileInfo, FileMode},
+    system, table::{cfg, system_table_raw}, Status
 };
-use xmas_elf::{program::Type, ElfFile};
+use unix_v11_ember::{memtype, tag, Acpi, Dtb, EfiSystem, EmberWriter, Initrd, Kernel, RAMLayout, Segment, Smbios, Stack};
+use xmas_elf::{header::{self, Class}, program::Type, ElfFile};
 
 const PAGE_4KIB: usize = 0x1000;
+const MAX_SEGMENTS: usize = 16;
+const MAX_OWNED: usize = 8; // A boot holds six allocations at most
 
 macro_rules! arch {
     ($arch:literal, $modname:ident) => {
@@ -32,27 +43,165 @@ arch!("x86_64", amd64);
 arch!("aarch64", aarch64);
 arch!("riscv64", riscv64);
 
-#[repr(C)]
-pub struct RelaEntry {
-    offset: u64,
-    info: u64,
-    addend: u64,
-}
-
-#[cfg(target_arch = "x86_64")]  const R_RELATIVE: u64 = 8;
-#[cfg(target_arch = "aarch64")] const R_RELATIVE: u64 = 1027;
-#[cfg(target_arch = "riscv64")] const R_RELATIVE: u64 = 3;
-
 pub fn align_up(val: usize, align: usize) -> usize {
     if align == 0 { return val; }
     return val + (align - val % align) % align;
 }
 
+const DTB_GUID: uefi::Guid = guid!("b1b621d5-f19c-41a5-830b-d9152c69aae0");
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_MAX_SIZE: usize = 0x200000;
+
+/// Pages a boot attempt took from firmware. Dropping this hands them back, so a
+/// failed attempt leaves the next boot option the memory it started with.
+struct OwnedPages {
+    ranges: [(usize, usize); MAX_OWNED],
+    count: usize
+}
+
+impl OwnedPages {
+    const fn new() -> Self {
+        return Self { ranges: [(0, 0); MAX_OWNED], count: 0 };
+    }
+
+    fn alloc(&mut self, mem_ty: MemoryType, pages: usize) -> Result<*mut u8, LoaderError> {
+        assert!(self.count < MAX_OWNED, "too many loader allocations");
+        let ptr = allocate_pages(AllocateType::AnyPages, mem_ty, pages)
+            .map(|ptr| ptr.as_ptr())
+            .map_err(|_| LoaderError::OutOfMemory(pages))?;
+        self.ranges[self.count] = (ptr as usize, pages);
+        self.count += 1;
+        return Ok(ptr);
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        let Some(idx) = self.ranges[..self.count].iter().position(|&(addr, _)| addr == ptr as usize) else { return; };
+        let (_, pages) = self.ranges[idx];
+        self.count -= 1;
+        self.ranges[idx] = self.ranges[self.count];
+        let _ = unsafe { boot::free_pages(NonNull::new_unchecked(ptr), pages) };
+    }
+
+    /// Leaves everything allocated: the kernel owns it from here on.
+    fn keep(mut self) {
+        self.count = 0;
+    }
+}
+
+impl Drop for OwnedPages {
+    fn drop(&mut self) {
+        for &(addr, pages) in &self.ranges[..self.count] {
+            let _ = unsafe { boot::free_pages(NonNull::new_unchecked(addr as *mut u8), pages) };
+        }
+    }
+}
+
+/// Copies the firmware FDT into its own region so it outlives boot services.
+fn copy_dtb(owned: &mut OwnedPages, dtb_ptr: usize) -> Option<(usize, usize)> {
+    let header = unsafe { core::slice::from_raw_parts(dtb_ptr as *const u32, 2) };
+    let magic = u32::from_be(header[0]);
+    let size = u32::from_be(header[1]) as usize;
+    if magic != FDT_MAGIC {
+        println!("Ignoring device tree: bad magic {:#x}", magic);
+        return None;
+    }
+    if size < 0x28 || size > FDT_MAX_SIZE {
+        println!("Ignoring device tree: bad size {:#x}", size);
+        return None;
+    }
+
+    let pages = align_up(size, PAGE_4KIB) / PAGE_4KIB;
+    let mem_ty = MemoryType::custom(memtype::DEVICE_TREE);
+    let copy = owned.alloc(mem_ty, pages).ok()?;
+    unsafe { core::ptr::copy(dtb_ptr as *const u8, copy, size); }
+    return Some((copy as usize, size));
+}
+
+/// Reads a whole file into freshly allocated pages of the given type.
+fn load_file(
+    owned: &mut OwnedPages, root: &mut Directory, path: Path, mem_ty: MemoryType
+) -> Result<&'static mut [u8], LoaderError> {
+    let mut path_buf = [0u16; PATH_MAX + 1];
+    let path16 = path.to_cstr16(&mut path_buf).ok_or(LoaderError::NotFound(path))?;
+    let file = root.open(path16, FileMode::Read, FileAttribute::empty()).map_err(|err| match err.status() {
+        Status::NOT_FOUND => LoaderError::NotFound(path),
+        status => LoaderError::Read(path, status)
+    })?;
+    let mut file = file.into_regular_file().ok_or(LoaderError::NotFound(path))?;
+
+    let mut info_buf = [0u8; 512];
+    let info = file.get_info::<FileInfo>(&mut info_buf).map_err(|err| LoaderError::Read(path, err.status()))?;
+    let file_size = info.file_size() as usize;
+    if file_size == 0 { return Err(LoaderError::EmptyFile(path)); }
+
+    let file_pages = align_up(file_size, PAGE_4KIB) / PAGE_4KIB;
+    let file_ptr = owned.alloc(mem_ty, file_pages)?;
+    let file_binary = unsafe { core::slice::from_raw_parts_mut(file_ptr, file_size) };
+    let read = file.read(file_binary).map_err(|err| LoaderError::Read(path, err.status()))?;
+    if read != file_size { return Err(LoaderError::Read(path, Status::END_OF_FILE)); }
+    return Ok(file_binary);
+}
+
+/// Inflates an LZ4-compressed image into fresh pages and frees the compressed copy.
+/// Anything else is returned untouched.
+fn decompress(owned: &mut OwnedPages, path: Path, file: &'static mut [u8]) -> Result<&'static mut [u8], LoaderError> {
+    if !lz4::is_frame(file) { return Ok(file); }
+    let size = lz4::content_size(file).map_err(LoaderError::Decompress)?;
+    if size == 0 { return Err(LoaderError::Decompress("frame is empty")); }
+
+    let pages = align_up(size, PAGE_4KIB) / PAGE_4KIB;
+    let image = unsafe { core::slice::from_raw_parts_mut(owned.alloc(MemoryType::LOADER_DATA, pages)?, size) };
+    lz4::decompress(file, image).map_err(LoaderError::Decompress)?;
+    println!(
+        "Decompressed {}: {} KiB -> {} KiB ({}%)",
+        path, file.len() / 1024, size / 1024, file.len() * 100 / size
+    );
+
+    owned.free(file.as_mut_ptr());
+    return Ok(image);
+}
+
+/// Rejects kernels this loader cannot place, before anything is copied.
+fn check_elf(elf: &ElfFile, file_size: usize) -> Result<(), LoaderError> {
+    header::sanity_check(elf).map_err(LoaderError::Elf)?;
+    if elf.header.pt1.class() != Class::SixtyFour { return Err(LoaderError::Elf("not a 64-bit image")); }
+
+    let machine = elf.header.pt2.machine().as_machine();
+    if machine != arch::MACHINE { return Err(LoaderError::Machine(machine)); }
+    if elf.header.pt2.type_().as_type() != header::Type::SharedObject { return Err(LoaderError::NotDynamic); }
+
+    for (index, ph) in elf.program_iter().enumerate() {
+        if ph.get_type() != Ok(Type::Load) { continue; }
+        let file_end = ph.offset().checked_add(ph.file_size());
+        let mem_end = ph.virtual_addr().checked_add(ph.mem_size());
+        if file_end.is_none_or(|end| end > file_size as u64) || mem_end.is_none() || ph.file_size() > ph.mem_size() {
+            return Err(LoaderError::SegmentBounds(index));
+        }
+    }
+    return Ok(());
+}
+
+fn wait_for_key() {
+    system::with_stdin(|stdin| {
+        let Some(event) = stdin.wait_for_key_event() else { return; };
+        let _ = boot::wait_for_event(&mut [event]);
+        let _ = stdin.read_key();
+    });
+}
+
 #[entry]
 fn ignite() -> Status {
-    let systemtable = system_table_raw().unwrap();
-    let (mut acpi_ptr, mut dtb_ptr) = (0, 0);
-    unsafe {
+    let Err(err) = boot();
+    println!("Cannot boot Research UNIX Version 11: {}", err);
+    println!("Press any key to return to the firmware");
+    wait_for_key();
+    return err.status();
+}
+
+fn boot() -> Result<Infallible, LoaderError> {
+    let (mut acpi_ptr, mut dtb_ptr, mut smbios_ptr) = (0, 0, 0);
+    let systemtable = system_table_raw();
+    if let Some(systemtable) = systemtable { unsafe {
         let config_ptr = systemtable.as_ref().configuration_table;
         let config_size = systemtable.as_ref().number_of_configuration_table_entries;
         let config = core::slice::from_raw_parts(config_ptr, config_size);
@@ -60,38 +209,52 @@ fn ignite() -> Status {
         for cfg in config.iter() {
             let isacpi = cfg.vendor_guid == cfg::ACPI_GUID && acpi_ptr == 0;
             let isacpi2 = cfg.vendor_guid == cfg::ACPI2_GUID;
-            let isdtb = cfg.vendor_guid == cfg::SMBIOS3_GUID;
-            if isacpi || isacpi2 { acpi_ptr = cfg.vendor_table as usize; }
-            if isdtb             { dtb_ptr  = cfg.vendor_table as usize; }
+            let isdtb = cfg.vendor_guid == DTB_GUID;
+            let issmbios = cfg.vendor_guid == cfg::SMBIOS_GUID && smbios_ptr == 0;
+            let issmbios3 = cfg.vendor_guid == cfg::SMBIOS3_GUID;
+            if isacpi || isacpi2     { acpi_ptr   = cfg.vendor_table as usize; }
+            if isdtb                 { dtb_ptr    = cfg.vendor_table as usize; }
+            if issmbios || issmbios3 { smbios_ptr = cfg.vendor_table as usize; }
         }
-    }
+    } }
+    // Everything allocated from here on goes back to firmware if the boot fails
+    let mut owned = OwnedPages::new();
+    let dtb = if dtb_ptr != 0 { copy_dtb(&mut owned, dtb_ptr) } else { None };
 
-    let mut filesys_protocol = get_image_file_system(image_handle()).unwrap();
-    let mut root = filesys_protocol.open_volume().unwrap();
+    let mut filesys_protocol = get_image_file_system(image_handle())
+        .map_err(|err| LoaderError::Volume(err.status()))?;
+    let mut root = filesys_protocol.open_volume().map_err(|err| LoaderError::Volume(err.status()))?;
 
-    let mut file = root.open(
-        cstr16!("\\unix-v11"), FileMode::Read, FileAttribute::empty()
-    ).unwrap().into_regular_file().unwrap();
-
-    let mut info_buf = [0u8; 512];
-    let info = file.get_info::<FileInfo>(&mut info_buf).unwrap();
-    let file_size = info.file_size() as usize;
+    let mut cmdline_buf = [0u8; cmdline::CMDLINE_MAX];
+    let mut cmdline_len = cmdline::load(&mut root, &mut cmdline_buf);
+    let kernel_path = menu::select(&mut root, &mut cmdline_buf, &mut cmdline_len)?;
 
-    let file_pages = align_up(file_size, PAGE_4KIB) / PAGE_4KIB;
-    let file_ptr = allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, file_pages).unwrap();
-    let file_binary = unsafe { core::slice::from_raw_parts_mut(file_ptr.as_ptr(), file_size) };
-    file.read(file_binary).unwrap();
+    // Each kernel may bring its own initrd, named after it
+    let initrd_path = kernel_path.with_suffix(".initrd").ok_or(LoaderError::NotFound(kernel_path))?;
+    let kernel_file = load_file(&mut owned, &mut root, kernel_path, MemoryType::LOADER_DATA)?;
+    let file_binary = decompress(&mut owned, kernel_path, kernel_file)?;
+    let initrd = match load_file(&mut owned, &mut root, initrd_path, MemoryType::custom(memtype::INITRD)) {
+        Ok(initrd) => Some(initrd),
+        Err(LoaderError::NotFound(_)) => None,
+        Err(err) => return Err(err)
+    };
 
-    let elf = ElfFile::new(file_binary).unwrap();
+    let elf = ElfFile::new(file_binary).map_err(LoaderError::Elf)?;
+    check_elf(&elf, file_binary.len())?;
+    let integrity = verify::verify(&elf)?;
 
     let kernel_size = elf.program_iter()
         .filter(|ph| ph.get_type() == Ok(Type::Load))
         .map(|ph| ph.virtual_addr() + ph.mem_size())
-        .max().unwrap() as usize;
+        .max().ok_or(LoaderError::NoSegments)? as usize;
+    let entry = elf.header.pt2.entry_point();
+    if entry >= kernel_size as u64 { return Err(LoaderError::Entry(entry)); }
 
     let kernel_pages = align_up(kernel_size, PAGE_4KIB) / PAGE_4KIB;
-    let kernel_base = allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_CODE, kernel_pages).unwrap().as_ptr() as usize;
+    let kernel_base = owned.alloc(MemoryType::LOADER_CODE, kernel_pages)? as usize;
 
+    let mut segments = [Segment { addr: 0, size: 0, flags: 0 }; MAX_SEGMENTS];
+    let mut segment_count = 0;
     for ph in elf.program_iter() {
         if let Ok(Type::Load) = ph.get_type() {
             let offset = ph.offset() as usize;
@@ -103,32 +266,48 @@ fn ignite() -> Status {
                 core::ptr::copy(file_binary[offset..offset + file_size].as_ptr(), phys_addr, file_size);
                 core::ptr::write_bytes(phys_addr.add(file_size), 0, mem_size - file_size);
             }
+
+            if segment_count == MAX_SEGMENTS { return Err(LoaderError::TooManySegments(MAX_SEGMENTS)); }
+            segments[segment_count] = Segment {
+                addr: phys_addr as u64, size: mem_size as u64, flags: ph.flags().0 as u64
+            };
+            segment_count += 1;
         }
     }
 
-    let rela = elf.find_section_by_name(".rela.dyn").unwrap();
-    let rela_ptr = (kernel_base + rela.address() as usize) as *mut RelaEntry;
-    let entry_count = rela.size() as usize / size_of::<RelaEntry>();
-    for i in 0..entry_count {
-        let entry = unsafe { &*rela_ptr.add(i) };
-        let ty = entry.info & 0xffffffff;
-        if ty == R_RELATIVE {
-            let reloc_addr = (kernel_base + entry.offset as usize) as *mut u64;
-            unsafe { *reloc_addr = kernel_base as u64 + entry.addend; }
-        }
+    reloc::relocate(&elf, kernel_base)?;
+
+    let ember_ptr = owned.alloc(MemoryType::LOADER_DATA, 1)?;
+    let ember_buf = unsafe { core::slice::from_raw_parts_mut(ember_ptr, PAGE_4KIB) };
+    let mut ember = EmberWriter::new(ember_buf)?;
+    if acpi_ptr != 0 { ember.push(&Acpi { rsdp: acpi_ptr as u64 })?; }
+    if let Some((ptr, size)) = dtb { ember.push(&Dtb { ptr: ptr as u64, size: size as u64 })?; }
+    if smbios_ptr != 0 { ember.push(&Smbios { ptr: smbios_ptr as u64 })?; }
+    if let Some(systemtable) = systemtable { ember.push(&EfiSystem { table: systemtable.as_ptr() as u64 })?; }
+    if cmdline_len > 0 { ember.push_raw(tag::CMDLINE, &cmdline_buf[..cmdline_len])?; }
+    if let Some(initrd) = initrd {
+        ember.push(&Initrd { ptr: initrd.as_ptr() as u64, size: initrd.len() as u64 })?;
     }
+    ember.push(&Kernel { base: kernel_base as u64, size: kernel_size as u64 })?;
+    ember.push(&integrity)?;
+    ember.push_slice(&segments[..segment_count])?;
+    if let Some(framebuffer) = gop::query() { ember.push(&framebuffer)?; }
 
-    let entrypoint = elf.header.pt2.entry_point() as usize + kernel_base;
-    let spark: extern "efiapi" fn(Ember) -> ! = unsafe { core::mem::transmute(entrypoint) };
+    owned.keep();
+    let entrypoint = entry as usize + kernel_base;
+    let spark: extern "efiapi" fn(*const u8) -> ! = unsafe { core::mem::transmute(entrypoint) };
     let efi_ram_layout = unsafe { exit_boot_services(Some(MemoryType::LOADER_DATA)) };
     let stack_base = arch::stack_ptr();
-    let ember = Ember {
-        layout_ptr: efi_ram_layout.buffer().as_ptr() as *const RAMDescriptor,
-        layout_len: efi_ram_layout.len(),
-        acpi_ptr, dtb_ptr,
-        stack_base, kernel_base, kernel_size
-    };
-    spark(ember);
+
+    // Boot services are gone: these pushes cannot fail with the 4 KiB buffer above
+    ember.push(&Stack { base: stack_base as u64 }).unwrap();
+    ember.push(&RAMLayout {
+        ptr: efi_ram_layout.buffer().as_ptr() as u64,
+        len: efi_ram_layout.len() as u64,
+        desc_size: efi_ram_layout.meta().desc_size as u64
+    }).unwrap();
+    let ember = ember.finish().unwrap();
+    spark(ember.as_ptr());
 }
 
 #[panic_handler]
@@ -0,0 +1,198 @@
+use crate::{cmdline::CMDLINE_MAX, error::LoaderError, path::{Path, PATH_MAX}};
+use core::time::Duration;
+use uefi::{
+    boot, cstr16, print, println,
+    proto::console::text::{Key, ScanCode},
+    proto::media::file::{Directory, File, FileAttribute, FileMode, FileType},
+    system
+};
+
+/// A plain file here is booted directly; a directory holds a choice of kernels.
+const KERNEL_PATH: &str = "\\unix-v11";
+const MAX_ENTRIES: usize = 16;
+const DEFAULT_TIMEOUT: u32 = 5;
+const TICK: Duration = Duration::from_millis(100);
+const TICKS_PER_SEC: u32 = 10;
+
+// Companion files that share the directory with kernel images
+const SKIPPED_SUFFIXES: &[&str] = &[".initrd", ".cfg"];
+
+/// Lines of `\unix-v11.cfg` starting with these words configure the menu,
+/// rather than adding to the kernel command line.
+const DIRECTIVES: &[&[u8]] = &[b"timeout", b"default"];
+
+pub fn is_directive(line: &[u8]) -> bool {
+    let word = line.split(|b| b.is_ascii_whitespace()).next().unwrap_or(&[]);
+    return DIRECTIVES.contains(&word);
+}
+
+struct Config {
+    timeout: u32,
+    default: Option<Path>
+}
+
+fn read_config(root: &mut Directory) -> Config {
+    let mut config = Config { timeout: DEFAULT_TIMEOUT, default: None };
+    let file = root.open(cstr16!("\\unix-v11.cfg"), FileMode::Read, FileAttribute::empty());
+    let Some(mut file) = file.ok().and_then(|file| file.into_regular_file()) else { return config; };
+    let mut buf = [0u8; CMDLINE_MAX];
+    let Ok(read) = file.read(&mut buf) else { return config; };
+
+    for line in buf[..read].split(|&b| b == b'\n') {
+        let Ok(line) = core::str::from_utf8(line) else { continue; };
+        let mut words = line.split_ascii_whitespace();
+        match (words.next(), words.next()) {
+            (Some("timeout"), Some(secs)) => config.timeout = secs.parse().unwrap_or(config.timeout),
+            (Some("default"), Some(name)) => config.default = Path::from_parts(&[name]),
+            _ => {}
+        }
+    }
+    return config;
+}
+
+struct Menu {
+    entries: [Path; MAX_ENTRIES],
+    count: usize
+}
+
+impl Menu {
+    fn entry(&self, index: usize) -> Path { self.entries[index] }
+
+    fn scan(dir: &mut Directory) -> Self {
+        let mut menu = Self { entries: [Path::empty(); MAX_ENTRIES], count: 0 };
+        let mut info_buf = [0u8; 512];
+        while let Ok(Some(info)) = dir.read_entry(&mut info_buf) {
+            if menu.count == MAX_ENTRIES { break; }
+            if info.is_directory() { continue; }
+
+            // Names that are not plain ASCII cannot be shown or edited, so skip them
+            let mut name_buf = [0u8; PATH_MAX];
+            let mut len = 0;
+            for &ch in info.file_name().iter().take(PATH_MAX) {
+                let ch = u16::from(ch);
+                if ch >= 0x80 { len = 0; break; }
+                name_buf[len] = ch as u8;
+                len += 1;
+            }
+            let Ok(name) = core::str::from_utf8(&name_buf[..len]) else { continue; };
+            if name.is_empty() || name.starts_with('.') { continue; }
+            if SKIPPED_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) { continue; }
+
+            let Some(path) = Path::from_parts(&[KERNEL_PATH, "\\", name]) else { continue; };
+            menu.entries[menu.count] = path;
+            menu.count += 1;
+        }
+
+        // Directory order is up to the firmware; sort so the numbering is stable
+        menu.entries[..menu.count].sort_unstable_by(|a, b| a.as_str().cmp(b.as_str()));
+        return menu;
+    }
+
+    fn draw(&self, selected: usize, cmdline: &[u8]) {
+        system::with_stdout(|stdout| { let _ = stdout.clear(); });
+        println!("Research UNIX Version 11");
+        println!();
+        for index in 0..self.count {
+            let marker = if index == selected { '>' } else { ' ' };
+            println!("{} {}) {}", marker, index + 1, self.entry(index).file_name());
+        }
+        println!();
+        println!("Command line: {}", core::str::from_utf8(cmdline).unwrap_or(""));
+        println!("Up/Down or 1-9 to choose, e to edit the command line, Enter to boot");
+    }
+}
+
+fn read_key(block: bool) -> Option<Key> {
+    return system::with_stdin(|stdin| {
+        if block {
+            let event = stdin.wait_for_key_event()?;
+            boot::wait_for_event(&mut [event]).ok()?;
+        }
+        return stdin.read_key().ok().flatten();
+    });
+}
+
+fn is_char(key: &Key, ch: char) -> bool {
+    return matches!(key, Key::Printable(c) if u16::from(*c) == ch as u16);
+}
+
+/// Line editor over the command line; Escape restores what was there before.
+fn edit(cmdline: &mut [u8; CMDLINE_MAX], len: &mut usize) {
+    let (saved, saved_len) = (*cmdline, *len);
+    print!("\r\nEdit: {}", core::str::from_utf8(&cmdline[..*len]).unwrap_or(""));
+    loop {
+        let Some(key) = read_key(true) else { continue; };
+        match key {
+            Key::Special(ScanCode::ESCAPE) => { (*cmdline, *len) = (saved, saved_len); return; },
+            Key::Printable(ch) => match u16::from(ch) {
+                0x0d => return,
+                0x08 => if *len > 0 { *len -= 1; print!("\x08 \x08"); },
+                ch @ 0x20..0x7f if *len < CMDLINE_MAX => {
+                    cmdline[*len] = ch as u8;
+                    *len += 1;
+                    print!("{}", ch as u8 as char);
+                },
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Picks the kernel to boot, letting the user adjust the command line on the way.
+pub fn select(
+    root: &mut Directory, cmdline: &mut [u8; CMDLINE_MAX], cmdline_len: &mut usize
+) -> Result<Path, LoaderError> {
+    let kernel_path = Path::from_parts(&[KERNEL_PATH]).unwrap();
+    let mut path_buf = [0u16; PATH_MAX + 1];
+    let kernel_path16 = kernel_path.to_cstr16(&mut path_buf).unwrap();
+    let handle = root.open(kernel_path16, FileMode::Read, FileAttribute::empty());
+    let Some(Ok(FileType::Dir(mut dir))) = handle.ok().map(|handle| handle.into_type()) else {
+        return Ok(kernel_path);
+    };
+
+    let menu = Menu::scan(&mut dir);
+    if menu.count == 0 { return Err(LoaderError::NoKernels(kernel_path)); }
+
+    let config = read_config(root);
+    let mut selected = config.default
+        .and_then(|name| (0..menu.count).find(|&i| menu.entry(i).file_name() == name.as_str()))
+        .unwrap_or(0);
+    if config.timeout == 0 { return Ok(menu.entry(selected)); }
+
+    // Count down until the first key press; after that, wait for Enter
+    let mut ticks = Some(config.timeout * TICKS_PER_SEC);
+    menu.draw(selected, &cmdline[..*cmdline_len]);
+    loop {
+        let Some(key) = read_key(ticks.is_none()) else {
+            match ticks {
+                Some(0) => break,
+                Some(left) => {
+                    if left % TICKS_PER_SEC == 0 {
+                        print!("\rBooting {} in {}s ", menu.entry(selected).file_name(), left / TICKS_PER_SEC);
+                    }
+                    boot::stall(TICK);
+                    ticks = Some(left - 1);
+                },
+                None => {}
+            }
+            continue;
+        };
+
+        ticks = None;
+        match key {
+            _ if is_char(&key, '\r') => break,
+            _ if is_char(&key, 'e') => edit(cmdline, cmdline_len),
+            Key::Special(ScanCode::UP) => selected = selected.checked_sub(1).unwrap_or(menu.count - 1),
+            Key::Special(ScanCode::DOWN) => selected = (selected + 1) % menu.count,
+            Key::Printable(ch) => {
+                let digit = u16::from(ch).wrapping_sub(b'1' as u16) as usize;
+                if digit < menu.count { selected = digit; }
+            },
+            _ => {}
+        }
+        menu.draw(selected, &cmdline[..*cmdline_len]);
+    }
+    println!();
+    return Ok(menu.entry(selected));
+}
@@ -0,0 +1,53 @@
+use core::fmt;
+use uefi::CStr16;
+
+pub const PATH_MAX: usize = 128;
+
+/// An ASCII path on the boot volume, built without an allocator.
+#[derive(Clone, Copy)]
+pub struct Path {
+    buf: [u8; PATH_MAX],
+    len: usize
+}
+
+impl Path {
+    pub const fn empty() -> Self { Self { buf: [0; PATH_MAX], len: 0 } }
+
+    /// Joins `parts`; `None` if the result is too long or not printable ASCII.
+    pub fn from_parts(parts: &[&str]) -> Option<Self> {
+        let mut path = Self::empty();
+        for part in parts {
+            let end = path.len + part.len();
+            if end > PATH_MAX || !part.bytes().all(|b| b.is_ascii_graphic() || b == b' ') { return None; }
+            path.buf[path.len..end].copy_from_slice(part.as_bytes());
+            path.len = end;
+        }
+        return Some(path);
+    }
+
+    pub fn as_str(&self) -> &str {
+        return core::str::from_utf8(&self.buf[..self.len]).unwrap_or("");
+    }
+
+    /// The last component, as shown in the boot menu.
+    pub fn file_name(&self) -> &str {
+        let path = self.as_str();
+        return path.rsplit('\\').next().unwrap_or(path);
+    }
+
+    pub fn with_suffix(&self, suffix: &str) -> Option<Self> {
+        return Self::from_parts(&[self.as_str(), suffix]);
+    }
+
+    pub fn to_cstr16<'a>(&self, buf: &'a mut [u16; PATH_MAX + 1]) -> Option<&'a CStr16> {
+        return CStr16::from_str_with_buf(self.as_str(), buf).ok();
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(self.as_str()) }
+}
+
+impl fmt::Debug for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{:?}", self.as_str()) }
+}
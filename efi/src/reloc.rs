@@ -0,0 +1,257 @@
+use core::fmt;
+use xmas_elf::{program::Type, ElfFile};
+
+#[repr(C)]
+struct DynEntry {
+    tag: i64,
+    val: u64
+}
+
+#[repr(C)]
+struct RelaEntry {
+    offset: u64,
+    info: u64,
+    addend: i64
+}
+
+#[repr(C)]
+struct SymEntry {
+    name: u32,
+    info: u8,
+    _other: u8,
+    shndx: u16,
+    value: u64,
+    _size: u64
+}
+
+const DT_NULL: i64     = 0;
+const DT_PLTRELSZ: i64 = 2;
+const DT_STRTAB: i64   = 5;
+const DT_SYMTAB: i64   = 6;
+const DT_RELA: i64     = 7;
+const DT_RELASZ: i64   = 8;
+const DT_RELAENT: i64  = 9;
+const DT_SYMENT: i64   = 11;
+const DT_REL: i64      = 17;
+const DT_PLTREL: i64   = 20;
+const DT_JMPREL: i64   = 23;
+const DT_RELRSZ: i64   = 35;
+const DT_RELR: i64     = 36;
+const DT_RELRENT: i64  = 37;
+
+const STN_UNDEF: u32 = 0;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16   = 0xfff1;
+const STB_WEAK: u8   = 2;
+
+#[cfg(target_arch = "x86_64")]  mod ty {
+    pub const R_NONE: u32      = 0;
+    pub const R_64: u32        = 1;
+    pub const R_GLOB_DAT: u32  = 6;
+    pub const R_JUMP_SLOT: u32 = 7;
+    pub const R_RELATIVE: u32  = 8;
+}
+#[cfg(target_arch = "aarch64")] mod ty {
+    pub const R_NONE: u32      = 0;
+    pub const R_64: u32        = 257;
+    pub const R_GLOB_DAT: u32  = 1025;
+    pub const R_JUMP_SLOT: u32 = 1026;
+    pub const R_RELATIVE: u32  = 1027;
+}
+#[cfg(target_arch = "riscv64")] mod ty {
+    pub const R_NONE: u32      = 0;
+    pub const R_64: u32        = 2;
+    pub const R_GLOB_DAT: u32  = 2; // RISC-V fills GOT slots with R_RISCV_64
+    pub const R_JUMP_SLOT: u32 = 5;
+    pub const R_RELATIVE: u32  = 3;
+}
+use ty::*;
+
+const MAX_REPORTED: usize = 8;
+
+#[derive(Debug)]
+pub enum RelocError {
+    Unsupported { types: [u32; MAX_REPORTED], type_count: usize, count: usize },
+    UndefinedSymbol(&'static str),
+    NoSymbolTable,
+    RelTable,
+    EntrySize(u64)
+}
+
+impl fmt::Display for RelocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unsupported { types, type_count, count } => {
+                write!(f, "{} relocations of unsupported type:", count)?;
+                for ty in &types[..*type_count] { write!(f, " {}", ty)?; }
+                Ok(())
+            },
+            Self::UndefinedSymbol(name) => write!(f, "undefined symbol `{}`", name),
+            Self::NoSymbolTable => write!(f, "symbol relocation without DT_SYMTAB"),
+            Self::RelTable => write!(f, "DT_REL tables are not supported, link with RELA"),
+            Self::EntrySize(size) => write!(f, "unexpected relocation entry size {}", size)
+        }
+    }
+}
+
+#[derive(Default)]
+struct Dynamic {
+    rela: u64, rela_size: u64, rela_ent: u64,
+    jmprel: u64, pltrel_size: u64, pltrel: i64,
+    relr: u64, relr_size: u64, relr_ent: u64,
+    symtab: u64, syment: u64, strtab: u64,
+    has_rel: bool
+}
+
+impl Dynamic {
+    fn read(base: usize, addr: u64) -> Self {
+        let mut dynamic = Self::default();
+        let mut entry = (base + addr as usize) as *const DynEntry;
+        loop {
+            let DynEntry { tag, val } = unsafe { entry.read() };
+            match tag {
+                DT_NULL     => break,
+                DT_RELA     => dynamic.rela = val,
+                DT_RELASZ   => dynamic.rela_size = val,
+                DT_RELAENT  => dynamic.rela_ent = val,
+                DT_JMPREL   => dynamic.jmprel = val,
+                DT_PLTRELSZ => dynamic.pltrel_size = val,
+                DT_PLTREL   => dynamic.pltrel = val as i64,
+                DT_RELR     => dynamic.relr = val,
+                DT_RELRSZ   => dynamic.relr_size = val,
+                DT_RELRENT  => dynamic.relr_ent = val,
+                DT_SYMTAB   => dynamic.symtab = val,
+                DT_SYMENT   => dynamic.syment = val,
+                DT_STRTAB   => dynamic.strtab = val,
+                DT_REL      => dynamic.has_rel = true,
+                _ => {}
+            }
+            entry = unsafe { entry.add(1) };
+        }
+        return dynamic;
+    }
+}
+
+struct Relocator {
+    base: usize,
+    dynamic: Dynamic,
+    unsupported: [u32; MAX_REPORTED],
+    unsupported_types: usize,
+    unsupported_count: usize
+}
+
+impl Relocator {
+    /// S of a relocation: no symbol is 0, and absolute symbols do not move with the image.
+    fn symbol(&self, index: u32) -> Result<u64, RelocError> {
+        if index == STN_UNDEF { return Ok(0); }
+        if self.dynamic.symtab == 0 { return Err(RelocError::NoSymbolTable); }
+        let syment = if self.dynamic.syment == 0 { size_of::<SymEntry>() as u64 } else { self.dynamic.syment };
+        let sym = unsafe {
+            &*((self.base as u64 + self.dynamic.symtab + index as u64 * syment) as *const SymEntry)
+        };
+
+        if sym.shndx == SHN_ABS { return Ok(sym.value); }
+        if sym.shndx != SHN_UNDEF { return Ok(self.base as u64 + sym.value); }
+        if sym.info >> 4 == STB_WEAK { return Ok(0); }
+        return Err(RelocError::UndefinedSymbol(self.symbol_name(sym.name)));
+    }
+
+    fn symbol_name(&self, offset: u32) -> &'static str {
+        if self.dynamic.strtab == 0 { return "?"; }
+        let start = (self.base as u64 + self.dynamic.strtab + offset as u64) as *const u8;
+        let len = (0..).take_while(|&i| unsafe { *start.add(i) } != 0).count();
+        let name = unsafe { core::slice::from_raw_parts(start, len) };
+        return core::str::from_utf8(name).unwrap_or("?");
+    }
+
+    fn note_unsupported(&mut self, ty: u32) {
+        self.unsupported_count += 1;
+        let seen = &self.unsupported[..self.unsupported_types];
+        if seen.contains(&ty) || self.unsupported_types == MAX_REPORTED { return; }
+        self.unsupported[self.unsupported_types] = ty;
+        self.unsupported_types += 1;
+    }
+
+    fn apply_rela(&mut self, addr: u64, size: u64, ent: u64) -> Result<(), RelocError> {
+        if addr == 0 || size == 0 { return Ok(()); }
+        let ent = if ent == 0 { size_of::<RelaEntry>() as u64 } else { ent };
+        if ent != size_of::<RelaEntry>() as u64 { return Err(RelocError::EntrySize(ent)); }
+
+        let table = (self.base as u64 + addr) as *const RelaEntry;
+        for i in 0..(size / ent) as usize {
+            let rela = unsafe { &*table.add(i) };
+            let (ty, sym) = (rela.info as u32, (rela.info >> 32) as u32);
+            let target = (self.base as u64 + rela.offset) as *mut u64;
+
+            let value = if ty == R_NONE { continue; }
+            else if ty == R_RELATIVE { (self.base as u64).wrapping_add_signed(rela.addend) }
+            else if ty == R_64 || ty == R_GLOB_DAT || ty == R_JUMP_SLOT {
+                self.symbol(sym)?.wrapping_add_signed(rela.addend)
+            }
+            else { self.note_unsupported(ty); continue; };
+
+            unsafe { target.write_unaligned(value); }
+        }
+        return Ok(());
+    }
+
+    fn apply_relr(&self) -> Result<(), RelocError> {
+        let Dynamic { relr, relr_size, relr_ent, .. } = self.dynamic;
+        if relr == 0 || relr_size == 0 { return Ok(()); }
+        if relr_ent != 0 && relr_ent != 8 { return Err(RelocError::EntrySize(relr_ent)); }
+
+        let table = (self.base as u64 + relr) as *const u64;
+        let base = self.base as u64;
+        let mut next = 0 as *mut u64;
+        for i in 0..(relr_size / 8) as usize {
+            let entry = unsafe { *table.add(i) };
+            if entry & 1 == 0 {
+                // An even entry names one word and starts a new run
+                let target = (base + entry) as *mut u64;
+                unsafe { *target += base; }
+                next = unsafe { target.add(1) };
+            } else {
+                // An odd entry is a bitmap over the 63 words following the run
+                let mut bits = entry >> 1;
+                let mut slot = next;
+                while bits != 0 {
+                    if bits & 1 != 0 { unsafe { *slot += base; } }
+                    bits >>= 1;
+                    slot = unsafe { slot.add(1) };
+                }
+                next = unsafe { next.add(63) };
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Applies every dynamic relocation of an image already copied to `base`.
+pub fn relocate(elf: &ElfFile, base: usize) -> Result<(), RelocError> {
+    let Some(dynamic) = elf.program_iter().find(|ph| ph.get_type() == Ok(Type::Dynamic)) else {
+        return Ok(());
+    };
+
+    let dynamic = Dynamic::read(base, dynamic.virtual_addr());
+    if dynamic.has_rel || (dynamic.jmprel != 0 && dynamic.pltrel != DT_RELA) {
+        return Err(RelocError::RelTable);
+    }
+
+    let mut relocator = Relocator {
+        base, dynamic,
+        unsupported: [0; MAX_REPORTED], unsupported_types: 0, unsupported_count: 0
+    };
+    relocator.apply_relr()?;
+    let Dynamic { rela, rela_size, rela_ent, jmprel, pltrel_size, .. } = relocator.dynamic;
+    relocator.apply_rela(rela, rela_size, rela_ent)?;
+    relocator.apply_rela(jmprel, pltrel_size, rela_ent)?;
+
+    if relocator.unsupported_count > 0 {
+        return Err(RelocError::Unsupported {
+            types: relocator.unsupported,
+            type_count: relocator.unsupported_types,
+            count: relocator.unsupported_count
+        });
+    }
+    return Ok(());
+}
@@ -0,0 +1,113 @@
+use crate::error::LoaderError;
+use ed25519_compact::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+use unix_v11_ember::{integrity, Integrity};
+use xmas_elf::{sections::ShType, ElfFile};
+
+// Notes appended after the ELF image, in the ELF note layout:
+// namesz, descsz, type, then name and descriptor, each padded to 4 bytes
+const NOTE_NAME: &[u8] = b"UNIXv11\0";
+const NT_SHA256: u32  = 1; // Descriptor: SHA-256 of the image
+const NT_ED25519: u32 = 2; // Descriptor: Ed25519 signature over that digest
+
+/// Key images must be signed with, as 64 hex digits in `UNIX_V11_PUBKEY` at build time.
+/// Without one the loader only checks the digest.
+const PUBKEY: Option<[u8; PublicKey::BYTES]> = match option_env!("UNIX_V11_PUBKEY") {
+    Some(hex) => Some(parse_key(hex)),
+    None => None
+};
+
+const fn parse_key(hex: &str) -> [u8; PublicKey::BYTES] {
+    const fn nibble(ch: u8) -> u8 {
+        match ch {
+            b'0'..=b'9' => ch - b'0',
+            b'a'..=b'f' => ch - b'a' + 10,
+            b'A'..=b'F' => ch - b'A' + 10,
+            _ => panic!("UNIX_V11_PUBKEY is not hex")
+        }
+    }
+    let hex = hex.as_bytes();
+    assert!(hex.len() == PublicKey::BYTES * 2, "UNIX_V11_PUBKEY must be 64 hex digits");
+    let mut key = [0; PublicKey::BYTES];
+    let mut i = 0;
+    while i < key.len() {
+        key[i] = nibble(hex[i * 2]) << 4 | nibble(hex[i * 2 + 1]);
+        i += 1;
+    }
+    return key;
+}
+
+fn align4(val: usize) -> usize { (val + 3) & !3 }
+
+/// End of everything the ELF headers account for; notes start right after.
+fn image_len(elf: &ElfFile) -> usize {
+    let pt2 = &elf.header.pt2;
+    let headers = [
+        pt2.ph_offset() + pt2.ph_count() as u64 * pt2.ph_entry_size() as u64,
+        pt2.sh_offset() + pt2.sh_count() as u64 * pt2.sh_entry_size() as u64
+    ];
+    let segments = elf.program_iter().map(|ph| ph.offset() + ph.file_size());
+    let sections = elf.section_iter()
+        .filter(|sh| sh.get_type() != Ok(ShType::NoBits))
+        .map(|sh| sh.offset() + sh.size());
+    let end = headers.into_iter().chain(segments).chain(sections).max().unwrap_or(0);
+    return (end as usize).min(elf.input.len());
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    return Some(u32::from_le_bytes(bytes.try_into().ok()?));
+}
+
+/// Yields `(type, descriptor)` for each of our notes in `trailer`.
+fn notes(trailer: &[u8]) -> impl Iterator<Item = Result<(u32, &[u8]), LoaderError>> {
+    let mut offset = 0;
+    return core::iter::from_fn(move || {
+        if offset >= trailer.len() { return None; }
+        let note = (|| {
+            let name_size = read_u32(trailer, offset)? as usize;
+            let desc_size = read_u32(trailer, offset + 4)? as usize;
+            let ty = read_u32(trailer, offset + 8)?;
+            let name_start = offset + 12;
+            let desc_start = name_start.checked_add(align4(name_size))?;
+            let desc_end = desc_start.checked_add(desc_size)?;
+            if trailer.get(name_start..name_start + name_size)? != NOTE_NAME { return None; }
+            let desc = trailer.get(desc_start..desc_end)?;
+            offset = align4(desc_end);
+            return Some((ty, desc));
+        })();
+        if note.is_none() { offset = trailer.len(); }
+        return Some(note.ok_or(LoaderError::Integrity("malformed note after the image")));
+    });
+}
+
+/// Checks the notes trailing `\unix-v11`. An image without a digest note is
+/// let through as unchecked, unless the loader was built with a key.
+pub fn verify(elf: &ElfFile) -> Result<Integrity, LoaderError> {
+    let len = image_len(elf);
+    let (image, trailer) = elf.input.split_at(len);
+
+    let (mut digest_note, mut signature_note) = (None, None);
+    for note in notes(trailer) {
+        match note? {
+            (NT_SHA256, desc) => digest_note = Some(desc),
+            (NT_ED25519, desc) => signature_note = Some(desc),
+            _ => {}
+        }
+    }
+
+    let sha256: [u8; 32] = Sha256::digest(image).into();
+    let Some(expected) = digest_note else {
+        if PUBKEY.is_some() { return Err(LoaderError::Integrity("image is not signed")); }
+        return Ok(Integrity { status: integrity::UNCHECKED, sha256 });
+    };
+    if expected != sha256 { return Err(LoaderError::Integrity("SHA-256 does not match the image")); }
+
+    let Some(key) = PUBKEY else { return Ok(Integrity { status: integrity::DIGEST, sha256 }); };
+    let signature = signature_note.ok_or(LoaderError::Integrity("image is not signed"))?;
+    let signature = Signature::from_slice(signature)
+        .map_err(|_| LoaderError::Integrity("malformed signature"))?;
+    PublicKey::new(key).verify(sha256, &signature)
+        .map_err(|_| LoaderError::Integrity("signature does not match the loader key"))?;
+    return Ok(Integrity { status: integrity::SIGNED, sha256 });
+}
@@ -0,0 +1,471 @@
+//                            Ember Boot Handoff                            !//
+//
+// Description: Boot handoff format shared by the loader and the kernel
+// Licence: Public Domain
+
+//! An Ember is a single blob: a fixed header followed by a list of typed
+//! tags, each padded to 8 bytes and terminated by an `END` tag.
+//! The major version changes only when an existing tag changes layout;
+//! new tags bump the minor version and are skipped by older kernels.
+
+#![no_std]
+
+use core::fmt;
+
+pub const MAGIC: [u8; 8] = *b"UNIXv11E";
+pub const VERSION_MAJOR: u16 = 2; // 2.0: `Dtb` carries the blob size
+pub const VERSION_MINOR: u16 = 0;
+
+const TAG_ALIGN: usize = 8;
+
+/// OS-defined UEFI memory types for regions the loader hands to the kernel.
+pub mod memtype {
+    pub const DEVICE_TREE: u32 = 0x8044_5442;
+    pub const INITRD     : u32 = 0x8049_5244;
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RAMDescriptor {
+    pub ty: u32,
+    pub reserved: u32,
+    pub phys_start: u64,
+    pub virt_start: u64,
+    pub page_count: u64,
+    pub attr: u64,
+    pub padding: u64
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub magic: [u8; 8],
+    pub major: u16,
+    pub minor: u16,
+    pub header_size: u32,
+    pub total_size: u32,
+    pub tag_count: u32
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TagHeader {
+    pub ty: u32,
+    pub size: u32
+}
+
+pub mod tag {
+    pub const END        : u32 = 0x00;
+    pub const RAM_LAYOUT : u32 = 0x01;
+    pub const ACPI       : u32 = 0x02;
+    pub const DTB        : u32 = 0x03;
+    pub const KERNEL     : u32 = 0x04;
+    pub const STACK      : u32 = 0x05;
+    pub const SMBIOS     : u32 = 0x06;
+    pub const CMDLINE    : u32 = 0x07; // UTF-8 bytes, no terminator
+    pub const INITRD     : u32 = 0x08;
+    pub const SEGMENTS   : u32 = 0x09; // Array of `Segment`
+    pub const FRAMEBUFFER: u32 = 0x0a;
+    pub const INTEGRITY  : u32 = 0x0b;
+    pub const EFI_SYSTEM : u32 = 0x0c;
+}
+
+/// Segment permissions, equal to the ELF `PF_*` bits.
+pub mod segflag {
+    pub const X: u64 = 1 << 0;
+    pub const W: u64 = 1 << 1;
+    pub const R: u64 = 1 << 2;
+}
+
+/// A fixed-layout tag payload.
+///
+/// # Safety
+/// Implementors must be `#[repr(C)]` plain data valid for any bit pattern.
+pub unsafe trait Tag: Copy {
+    const TYPE: u32;
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RAMLayout {
+    pub ptr: u64,
+    pub len: u64,
+    pub desc_size: u64
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Acpi { pub rsdp: u64 }
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Dtb {
+    pub ptr: u64,
+    pub size: u64
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Kernel {
+    pub base: u64,
+    pub size: u64
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Stack { pub base: u64 }
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Smbios { pub ptr: u64 }
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Initrd {
+    pub ptr: u64,
+    pub size: u64
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    pub addr: u64,
+    pub size: u64,
+    pub flags: u64
+}
+
+/// A linear 32-bit framebuffer; each mask selects one channel of a pixel.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+    pub base: u64,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32, // In pixels
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32
+}
+
+/// The EFI system table, whose runtime services outlive `ExitBootServices`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct EfiSystem { pub table: u64 }
+
+/// What the loader could establish about the kernel image it loaded.
+pub mod integrity {
+    pub const UNCHECKED: u64 = 0; // No digest note
+    pub const DIGEST   : u64 = 1; // SHA-256 note matches the image
+    pub const SIGNED   : u64 = 2; // ...and its Ed25519 signature matches the loader key
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Integrity {
+    pub status: u64,
+    pub sha256: [u8; 32]
+}
+
+unsafe impl Tag for RAMLayout   { const TYPE: u32 = tag::RAM_LAYOUT; }
+unsafe impl Tag for Acpi        { const TYPE: u32 = tag::ACPI; }
+unsafe impl Tag for Dtb         { const TYPE: u32 = tag::DTB; }
+unsafe impl Tag for Kernel      { const TYPE: u32 = tag::KERNEL; }
+unsafe impl Tag for Stack       { const TYPE: u32 = tag::STACK; }
+unsafe impl Tag for Smbios      { const TYPE: u32 = tag::SMBIOS; }
+unsafe impl Tag for Initrd      { const TYPE: u32 = tag::INITRD; }
+unsafe impl Tag for Segment     { const TYPE: u32 = tag::SEGMENTS; }
+unsafe impl Tag for Framebuffer { const TYPE: u32 = tag::FRAMEBUFFER; }
+unsafe impl Tag for Integrity   { const TYPE: u32 = tag::INTEGRITY; }
+unsafe impl Tag for EfiSystem   { const TYPE: u32 = tag::EFI_SYSTEM; }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmberError {
+    NullPointer,
+    BadMagic,
+    Version { major: u16, minor: u16 },
+    HeaderSize(u32),
+    Truncated { offset: usize },
+    MissingEnd,
+    TagCount { header: u32, walked: u32 },
+    MissingTag(u32),
+    TagSize { ty: u32, size: u32 },
+    DescSize(u64),
+    Overflow
+}
+
+impl fmt::Display for EmberError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::NullPointer => write!(f, "no handoff was passed"),
+            Self::BadMagic => write!(f, "bad magic, not an Ember"),
+            Self::Version { major, minor } => write!(
+                f, "loader speaks v{}.{}, kernel speaks v{}.{}",
+                major, minor, VERSION_MAJOR, VERSION_MINOR
+            ),
+            Self::HeaderSize(size) => write!(f, "header size {} is invalid", size),
+            Self::Truncated { offset } => write!(f, "tag at offset {:#x} runs past the end", offset),
+            Self::MissingEnd => write!(f, "tag list is not terminated"),
+            Self::TagCount { header, walked } => write!(f, "header counts {} tags, list holds {}", header, walked),
+            Self::MissingTag(ty) => write!(f, "required tag {:#x} is missing", ty),
+            Self::TagSize { ty, size } => write!(f, "tag {:#x} has unexpected size {}", ty, size),
+            Self::DescSize(size) => write!(
+                f, "memory descriptor size {} does not match {}",
+                size, size_of::<RAMDescriptor>()
+            ),
+            Self::Overflow => write!(f, "handoff buffer is too small")
+        }
+    }
+}
+
+const fn align_up(val: usize, align: usize) -> usize {
+    return (val + align - 1) & !(align - 1);
+}
+
+/// Builds an Ember in a caller-provided, 8-byte aligned buffer.
+pub struct EmberWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    count: u32
+}
+
+impl<'a> EmberWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Result<Self, EmberError> {
+        if buf.len() < size_of::<Header>() { return Err(EmberError::Overflow); }
+        let header = Header {
+            magic: MAGIC,
+            major: VERSION_MAJOR,
+            minor: VERSION_MINOR,
+            header_size: size_of::<Header>() as u32,
+            total_size: 0,
+            tag_count: 0
+        };
+        unsafe { core::ptr::write_unaligned(buf.as_mut_ptr() as *mut Header, header); }
+        return Ok(Self { buf, len: size_of::<Header>(), count: 0 });
+    }
+
+    pub fn push<T: Tag>(&mut self, tag: &T) -> Result<(), EmberError> {
+        let data = unsafe {
+            core::slice::from_raw_parts(tag as *const T as *const u8, size_of::<T>())
+        };
+        return self.push_raw(T::TYPE, data);
+    }
+
+    pub fn push_slice<T: Tag>(&mut self, tags: &[T]) -> Result<(), EmberError> {
+        let data = unsafe {
+            core::slice::from_raw_parts(tags.as_ptr() as *const u8, size_of_val(tags))
+        };
+        return self.push_raw(T::TYPE, data);
+    }
+
+    pub fn push_raw(&mut self, ty: u32, data: &[u8]) -> Result<(), EmberError> {
+        let start = self.len;
+        let end = align_up(start + size_of::<TagHeader>() + data.len(), TAG_ALIGN);
+        if end > self.buf.len() { return Err(EmberError::Overflow); }
+
+        let header = TagHeader { ty, size: data.len() as u32 };
+        let payload = start + size_of::<TagHeader>();
+        unsafe { core::ptr::write_unaligned(self.buf[start..].as_mut_ptr() as *mut TagHeader, header); }
+        self.buf[payload..payload + data.len()].copy_from_slice(data);
+        self.buf[payload + data.len()..end].fill(0);
+
+        self.len = end;
+        self.count += 1;
+        return Ok(());
+    }
+
+    pub fn finish(mut self) -> Result<&'a [u8], EmberError> {
+        self.push_raw(tag::END, &[])?;
+        let header = self.buf.as_mut_ptr() as *mut Header;
+        unsafe {
+            (*header).total_size = self.len as u32;
+            (*header).tag_count = self.count;
+        }
+        return Ok(&self.buf[..self.len]);
+    }
+}
+
+/// A validated view over an Ember produced by `EmberWriter`.
+#[derive(Clone, Copy)]
+pub struct EmberView<'a> {
+    header: &'a Header,
+    data: &'a [u8]
+}
+
+impl<'a> EmberView<'a> {
+    /// Validates the header and walks every tag once before handing out a view.
+    ///
+    /// # Safety
+    /// `ptr` must point to at least `total_size` readable bytes
+    /// that stay alive and unmodified for `'a`.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, EmberError> {
+        if ptr.is_null() { return Err(EmberError::NullPointer); }
+        let header = unsafe { &*(ptr as *const Header) };
+
+        if header.magic != MAGIC { return Err(EmberError::BadMagic); }
+        if header.major != VERSION_MAJOR {
+            return Err(EmberError::Version { major: header.major, minor: header.minor });
+        }
+        let header_size = header.header_size as usize;
+        if header_size < size_of::<Header>() || !header_size.is_multiple_of(TAG_ALIGN) {
+            return Err(EmberError::HeaderSize(header.header_size));
+        }
+        if (header.total_size as usize) < header_size {
+            return Err(EmberError::HeaderSize(header.total_size));
+        }
+
+        let data = unsafe { core::slice::from_raw_parts(ptr, header.total_size as usize) };
+        let view = Self { header, data };
+
+        // The count includes the END tag
+        let (mut offset, mut walked) = (header_size, 0u32);
+        loop {
+            let (ty, _, next) = view.tag_at(offset)?;
+            walked += 1;
+            if ty == tag::END { break; }
+            offset = next;
+        }
+        if walked != header.tag_count {
+            return Err(EmberError::TagCount { header: header.tag_count, walked });
+        }
+        return Ok(view);
+    }
+
+    fn tag_at(&self, offset: usize) -> Result<(u32, &'a [u8], usize), EmberError> {
+        if offset + size_of::<TagHeader>() > self.data.len() {
+            return Err(EmberError::MissingEnd);
+        }
+        let tag = unsafe {
+            core::ptr::read_unaligned(self.data[offset..].as_ptr() as *const TagHeader)
+        };
+        let payload = offset + size_of::<TagHeader>();
+        let end = payload + tag.size as usize;
+        if end > self.data.len() { return Err(EmberError::Truncated { offset }); }
+        let next = align_up(end, TAG_ALIGN);
+        return Ok((tag.ty, &self.data[payload..end], next));
+    }
+
+    pub fn header(&self) -> &'a Header { self.header }
+    pub fn as_bytes(&self) -> &'a [u8] { self.data }
+
+    pub fn tags(&self) -> TagIter<'a> {
+        return TagIter { view: *self, offset: self.header.header_size as usize };
+    }
+
+    pub fn find_raw(&self, ty: u32) -> Option<&'a [u8]> {
+        return self.tags().find(|&(tag_ty, _)| tag_ty == ty).map(|(_, data)| data);
+    }
+
+    /// Tags longer than `T` are accepted so newer loaders may append fields.
+    pub fn find<T: Tag>(&self) -> Result<Option<T>, EmberError> {
+        let Some(data) = self.find_raw(T::TYPE) else { return Ok(None); };
+        if data.len() < size_of::<T>() {
+            return Err(EmberError::TagSize { ty: T::TYPE, size: data.len() as u32 });
+        }
+        return Ok(Some(unsafe { core::ptr::read_unaligned(data.as_ptr() as *const T) }));
+    }
+
+    /// Borrows a tag whose payload is an array of `T`.
+    pub fn find_slice<T: Tag>(&self) -> Result<&'a [T], EmberError> {
+        let Some(data) = self.find_raw(T::TYPE) else { return Ok(&[]); };
+        if !data.len().is_multiple_of(size_of::<T>()) || !(data.as_ptr() as usize).is_multiple_of(align_of::<T>()) {
+            return Err(EmberError::TagSize { ty: T::TYPE, size: data.len() as u32 });
+        }
+        return Ok(unsafe {
+            core::slice::from_raw_parts(data.as_ptr() as *const T, data.len() / size_of::<T>())
+        });
+    }
+
+    pub fn require<T: Tag>(&self) -> Result<T, EmberError> {
+        return self.find::<T>()?.ok_or(EmberError::MissingTag(T::TYPE));
+    }
+}
+
+pub struct TagIter<'a> {
+    view: EmberView<'a>,
+    offset: usize
+}
+
+impl<'a> Iterator for TagIter<'a> {
+    type Item = (u32, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (ty, payload, next) = self.view.tag_at(self.offset).ok()?;
+        if ty == tag::END { return None; }
+        self.offset = next;
+        return Some((ty, payload));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C, align(8))]
+    struct Buf([u8; 256]);
+
+    fn write(buf: &mut Buf) -> &[u8] {
+        let mut writer = EmberWriter::new(&mut buf.0).unwrap();
+        writer.push(&Stack { base: 0x8000 }).unwrap();
+        writer.push_slice(&[Segment { addr: 0x2000, size: 0x3000, flags: 5 }; 2]).unwrap();
+        writer.push_raw(0xdead, &[1, 2, 3]).unwrap();
+        return writer.finish().unwrap();
+    }
+
+    fn view(bytes: &[u8]) -> Result<EmberView<'_>, EmberError> {
+        return unsafe { EmberView::from_ptr(bytes.as_ptr()) };
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = Buf([0xff; 256]);
+        let bytes = write(&mut buf);
+        let view = view(bytes).unwrap();
+        assert_eq!((view.header().total_size as usize, view.header().tag_count), (bytes.len(), 4));
+        assert_eq!(view.require::<Stack>().unwrap().base, 0x8000);
+        let segments = view.find_slice::<Segment>().unwrap();
+        assert_eq!((segments.len(), segments[1].addr, segments[1].flags), (2, 0x2000, 5));
+        assert_eq!(view.find_raw(0xdead), Some(&[1, 2, 3][..]));
+        assert_eq!(view.find::<Acpi>().unwrap().map(|acpi| acpi.rsdp), None);
+        assert_eq!(view.require::<Acpi>().map(|acpi| acpi.rsdp), Err(EmberError::MissingTag(tag::ACPI)));
+        assert!(view.tags().map(|(ty, _)| ty).eq([tag::STACK, tag::SEGMENTS, 0xdead]));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut small = [0u8; 8];
+        assert!(matches!(EmberWriter::new(&mut small), Err(EmberError::Overflow)));
+        let mut buf = Buf([0; 256]);
+        let mut writer = EmberWriter::new(&mut buf.0[..size_of::<Header>() + 16]).unwrap();
+        writer.push(&Stack { base: 0 }).unwrap();
+        assert_eq!(writer.push(&Stack { base: 0 }), Err(EmberError::Overflow));
+        assert!(matches!(writer.finish(), Err(EmberError::Overflow)));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut buf = Buf([0; 256]);
+        write(&mut buf);
+        buf.0[0] ^= 1;
+        assert!(matches!(view(&buf.0), Err(EmberError::BadMagic)));
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut buf = Buf([0; 256]);
+        write(&mut buf);
+        let header = buf.0.as_mut_ptr() as *mut Header;
+        unsafe { (*header).major = VERSION_MAJOR + 1; }
+        let err = view(&buf.0).err();
+        assert_eq!(err, Some(EmberError::Version { major: VERSION_MAJOR + 1, minor: VERSION_MINOR }));
+    }
+
+    #[test]
+    fn tag_count_mismatch() {
+        let mut buf = Buf([0; 256]);
+        write(&mut buf);
+        let header = buf.0.as_mut_ptr() as *mut Header;
+        unsafe { (*header).tag_count = 3; }
+        assert_eq!(view(&buf.0).err(), Some(EmberError::TagCount { header: 3, walked: 4 }));
+    }
+}
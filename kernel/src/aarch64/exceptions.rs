@@ -1,3 +1,5 @@
+use crate::ram::{self, EXCEPTION_STACK_SIZE};
+
 #[repr(C, align(2048))]
 pub struct ExceptionVector { pub data: [u32; 512] }
 
@@ -5,35 +7,30 @@ pub struct ExceptionVector { pub data: [u32; 512] }
 #[unsafe(link_section = ".text.exceptions")]
 pub static mut EXCEPTION_VECTOR: ExceptionVector = ExceptionVector { data: [0; 512] };
 
+// Kernel code runs on SP_EL0 and exceptions taken at EL1 switch to SP_EL1, which
+// points at a stack of its own; a fault on the guard page of the kernel stack
+// then still has a stack to report from.
 pub fn init_exceptions() {
     let xvec = unsafe { (&raw mut EXCEPTION_VECTOR).as_mut().unwrap() };
-    const LDR_X16_PC_REL: u32 = 0x58000010; // ldr x16, #offset
-    const BR_X16: u32         = 0xd61f0200; // br x16
+    const LDR_X16_LITERAL: u32 = 0x58000050; // ldr x16, #8
+    const BR_X16: u32          = 0xd61f0200; // br x16
 
+    // Architectural order: current EL on SP_EL0, current EL on SP_ELx,
+    // lower EL in AArch64, lower EL in AArch32; entries are 0x80 bytes apart
     let handlers = [
-        sync_el1h,  irq_el1h,  fiq_el1h,  serr_el1h,
         sync_el1t,  irq_el1t,  fiq_el1t,  serr_el1t,
+        sync_el1h,  irq_el1h,  fiq_el1h,  serr_el1h,
         sync_el0,   irq_el0,   fiq_el0,   serr_el0,
         sync_el0_2, irq_el0_2, fiq_el0_2, serr_el0_2
     ];
-    // let xvec_addr = xvec.data.as_ptr() as usize;
 
     for i in 0..handlers.len() {
-        let slot_index = i * 2;
+        let slot = i * 32;
         let handler_addr = handlers[i] as usize;
-        let slot_addr = &raw const xvec.data[slot_index] as usize;
-        let pc = slot_addr + 8;
-
-        let offset = ((handler_addr as isize - pc as isize) / 4) as i32;
-        assert!(offset >= -(1 << 18) && offset < (1 << 18), "offset out of LDR range");
-
-        let ldr = LDR_X16_PC_REL | (((offset as u32) & 0x7ffff) << 5);
-        xvec.data[slot_index]     = ldr;
-        xvec.data[slot_index + 1] = BR_X16;
-
-        let base = 512 - 16 * 2;
-        xvec.data[base + i * 2]     = (handler_addr & 0xffff_ffff) as u32;
-        xvec.data[base + i * 2 + 1] = (handler_addr >> 32) as u32;
+        xvec.data[slot]     = LDR_X16_LITERAL;
+        xvec.data[slot + 1] = BR_X16;
+        xvec.data[slot + 2] = (handler_addr & 0xffff_ffff) as u32;
+        xvec.data[slot + 3] = (handler_addr >> 32) as u32;
     }
 
     unsafe {
@@ -41,8 +38,50 @@ pub fn init_exceptions() {
         core::arch::asm!("dsb sy");
         core::arch::asm!("isb");
     }
+
+    let spsel: u64;
+    unsafe { core::arch::asm!("mrs {}, spsel", out(reg) spsel); }
+    if spsel & 1 == 0 { return; }
+    let stack = ram::alloc_stack(EXCEPTION_STACK_SIZE);
+    unsafe { core::arch::asm!(
+        "mov {tmp}, sp",
+        "msr sp_el0, {tmp}",
+        "mov sp, {top}",
+        "msr spsel, #0",
+        tmp = out(reg) _,
+        top = in(reg) stack.addr() + stack.size()
+    ); }
+}
+
+const EC_INST_ABORT_EL1: u64 = 0x21;
+const EC_DATA_ABORT_EL1: u64 = 0x25;
+
+/// Synchronous exception at EL1. FAR_EL1 holds the faulting address of aborts.
+fn sync_kernel(name: &str) -> ! {
+    let (esr, far, elr): (u64, u64, u64);
+    unsafe {
+        core::arch::asm!("mrs {}, esr_el1", out(reg) esr);
+        core::arch::asm!("mrs {}, far_el1", out(reg) far);
+        core::arch::asm!("mrs {}, elr_el1", out(reg) elr);
+    }
+    let ec = (esr >> 26) & 0x3f;
+    if ec == EC_DATA_ABORT_EL1 && ram::is_stack_guard(far as usize) {
+        panic!("kernel stack overflow: fault at {:#x}", far);
+    }
+    if ec == EC_DATA_ABORT_EL1 || ec == EC_INST_ABORT_EL1 {
+        panic!("{}: abort at {:#x} (ESR {:#x}) from {:#x}", name, far, esr, elr);
+    }
+    panic!("{}: ESR {:#x} from {:#x}", name, esr, elr);
 }
 
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".text.exceptions")]
+extern "C" fn sync_el1t() { sync_kernel("sync_el1t"); }
+
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".text.exceptions")]
+extern "C" fn sync_el1h() { sync_kernel("sync_el1h"); }
+
 macro_rules! handler {
     ($name:ident, $msg:expr) => {
         #[unsafe(no_mangle)]
@@ -54,11 +93,9 @@ macro_rules! handler {
     };
 }
 
-handler!(sync_el1h,  "[EXC] sync_el1h\n");
 handler!(irq_el1h,   "[EXC] irq_el1h\n");
 handler!(fiq_el1h,   "[EXC] fiq_el1h\n");
 handler!(serr_el1h,  "[EXC] serr_el1h\n");
-handler!(sync_el1t,  "[EXC] sync_el1t\n");
 handler!(irq_el1t,   "[EXC] irq_el1t\n");
 handler!(fiq_el1t,   "[EXC] fiq_el1t\n");
 handler!(serr_el1t,  "[EXC] serr_el1t\n");
@@ -1,6 +1,6 @@
 mod exceptions;
 
-use crate::{ember::ramtype, ram::PAGE_4KIB, ramblock::{self, AllocParams, RBPtr}, EMBER};
+use crate::{ram::PAGE_4KIB, ramblock::RBPtr, vmm, EMBER};
 use aarch64_cpu::{asm::wfi, registers::DAIF};
 pub use exceptions::init_exceptions;
 use tock_registers::interfaces::{Readable, Writeable};
@@ -15,6 +15,23 @@ pub fn halt() {
     wfi();
 }
 
+/// Leaves QEMU through semihosting SYS_EXIT, which needs `-semihosting` and
+/// carries the status out: 33 passed, 35 failed, as on amd64. Should the call
+/// return anyway, PSCI SYSTEM_OFF stops the machine, which the runner fails.
+#[allow(dead_code)] // Only the test harness leaves QEMU
+pub fn exit_qemu(success: bool) -> ! {
+    const SYS_EXIT: u64 = 0x18;
+    const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+    const PSCI_SYSTEM_OFF: u64 = 0x8400_0008;
+
+    let block: [u64; 2] = [ADP_STOPPED_APPLICATION_EXIT, if success { 33 } else { 35 }];
+    unsafe {
+        core::arch::asm!("hlt #0xf000", inout("x0") SYS_EXIT => _, in("x1") block.as_ptr());
+        core::arch::asm!("hvc #0", inout("x0") PSCI_SYSTEM_OFF => _);
+    }
+    loop { halt(); }
+}
+
 const UART0_BASE: usize = 0x0900_0000; // QEMU virt PL011 UART
 
 pub fn init_serial() {
@@ -59,84 +76,73 @@ impl core::fmt::Write for SerialWriter {
     }
 }
 
-const VALID: u64           = 1 << 0;
+/// MMIO the kernel touches without the firmware listing it.
+pub const FIXED_MMIO: &[(usize, usize)] = &[(UART0_BASE, PAGE_4KIB)];
+
+pub const PTE_VALID: u64   = 1 << 0;
+pub const PTE_ADDR: u64    = 0x0000_ffff_ffff_f000;
+const TABLE_DESC: u64      = 1 << 1;
 const PAGE_DESC: u64       = 1 << 1;
 const ATTR_IDX_NORMAL: u64 = 0 << 2;
 const ATTR_IDX_DEVICE: u64 = 1 << 2;
 const AP_RW_EL1: u64       = 0b00 << 6;
+const AP_RW_EL0: u64       = 0b01 << 6;
+const AP_RO_EL1: u64       = 0b10 << 6;
+const AP_RO_EL0: u64       = 0b11 << 6;
 const SH_NONE: u64         = 0b00 << 8;
 const SH_INNER: u64        = 0b11 << 8;
 const AF: u64              = 1 << 10;
 const UXN: u64 = 1 << 54;
 const PXN: u64 = 1 << 53;
 
-const PAGE_DEFAULT: u64 = AF | ATTR_IDX_NORMAL | SH_INNER | AP_RW_EL1;
-const PAGE_NOEXEC: u64  = PAGE_DEFAULT | UXN | PXN;
-const PAGE_DEVICE: u64 =  AF | ATTR_IDX_DEVICE | SH_NONE  | AP_RW_EL1 | UXN | PXN;
-
-fn get_page_idx(level: usize, virt: u64) -> usize {
-    match level {
-        0 => ((virt >> 39) & 0x1ff) as usize,
-        1 => ((virt >> 30) & 0x1ff) as usize,
-        2 => ((virt >> 21) & 0x1ff) as usize,
-        3 => ((virt >> 12) & 0x1ff) as usize,
-        _ => unreachable!(),
-    }
+pub fn table_entry(phys: usize) -> u64 {
+    return (phys as u64 & PTE_ADDR) | PTE_VALID | TABLE_DESC;
 }
 
-pub unsafe fn map_page(l0: *mut u64, virt: u64, phys: u64, flags: u64) {
-    let virt = virt & 0x0000_ffff_ffff_f000;
-    let phys = phys & 0x0000_ffff_ffff_f000;
-
-    let mut table = l0;
-    for level in 0..4 {
-        let index = get_page_idx(level, virt);
-        let entry = unsafe { table.add(index) };
-        if level == 3 { unsafe { *entry = phys | VALID | PAGE_DESC | flags; } }
-        else {
-            table = unsafe { if *entry & VALID == 0 {
-                let next_phys = ramblock::alloc(AllocParams::new(PAGE_4KIB).as_type(ramtype::PAGE_TABLE))
-                    .expect("[ERROR] alloc for page table failed!");
-                core::ptr::write_bytes(next_phys.ptr::<*mut u8>(), 0, PAGE_4KIB);
-                *entry = next_phys.addr() as u64 | VALID;
-                next_phys.ptr()
-            }
-            else { (*entry & 0x0000_ffff_ffff_f000) as *mut u64 } };
-        }
-    }
+pub fn leaf_entry(phys: usize, prot: u8) -> u64 {
+    let (write, user, exec) = (prot & vmm::prot::WRITE != 0, prot & vmm::prot::USER != 0, prot & vmm::prot::EXEC != 0);
+    let attr = if prot & vmm::prot::DEVICE != 0 { ATTR_IDX_DEVICE | SH_NONE } else { ATTR_IDX_NORMAL | SH_INNER };
+    let ap = match (write, user) {
+        (true, false)  => AP_RW_EL1,
+        (true, true)   => AP_RW_EL0,
+        (false, false) => AP_RO_EL1,
+        (false, true)  => AP_RO_EL0
+    };
+    // The kernel never runs user pages
+    let xn = match (exec, user) {
+        (false, _)    => UXN | PXN,
+        (true, false) => UXN,
+        (true, true)  => PXN
+    };
+    return (phys as u64 & PTE_ADDR) | PTE_VALID | PAGE_DESC | AF | attr | ap | xn;
 }
 
-fn flags_for(ty: u32) -> u64 {
-    match ty {
-        ramtype::CONVENTIONAL => PAGE_DEFAULT,
-        ramtype::BOOT_SERVICES_CODE => PAGE_DEFAULT,
-        ramtype::RUNTIME_SERVICES_CODE => PAGE_DEFAULT,
-        ramtype::KERNEL       => PAGE_DEFAULT,
-        ramtype::KERNEL_DATA  => PAGE_NOEXEC,
-        ramtype::PAGE_TABLE   => PAGE_NOEXEC,
-        ramtype::MMIO         => PAGE_DEVICE,
-        _                     => PAGE_NOEXEC
-    }
+/// A 2 MiB block, one level above pages: a leaf without the page bit.
+pub fn block_entry(phys: usize, prot: u8) -> u64 {
+    return leaf_entry(phys, prot) & !PAGE_DESC;
 }
 
-const ENTRIES_PER_TABLE: usize = 0x200;
+pub fn is_block(entry: u64) -> bool { return entry & TABLE_DESC == 0; }
 
-// Not working yet, I rly hate AArch64 MMU
-pub unsafe fn identity_map() {
-    let ember = EMBER.lock();
-    let l0 = ramblock::alloc(AllocParams::new(PAGE_4KIB).as_type(ramtype::PAGE_TABLE)).unwrap();
-    unsafe { core::ptr::write_bytes(l0.ptr::<*mut u8>(), 0, PAGE_4KIB); }
-
-    for desc in ember.efi_ram_layout() {
-        let block_ty = desc.ty;
-        let block_start = desc.phys_start;
-        let block_end = block_start + desc.page_count * PAGE_4KIB as u64;
-
-        for phys in (block_start..block_end).step_by(PAGE_4KIB) {
-            unsafe { map_page(l0.ptr(), phys, phys, flags_for(block_ty)); }
-        }
-    }
+/// Page `index` of a 2 MiB block, with the block's attributes.
+pub fn block_page(block: u64, index: usize) -> u64 {
+    return (block & !PTE_ADDR) | PAGE_DESC | ((block & PTE_ADDR) + (index * PAGE_4KIB) as u64);
+}
+
+/// Makes new table entries visible to the walker before they are used.
+pub fn sync_tables() {
+    unsafe { core::arch::asm!("dsb ishst", "isb"); }
+}
+
+// TLBI takes VA[55:12], which keeps the higher half apart from the lower
+pub fn flush_page(virt: usize) {
+    unsafe { core::arch::asm!("dsb ishst", "tlbi vaae1is, {}", "dsb ish", "isb", in(reg) (virt as u64 >> 12) & ((1 << 44) - 1)); }
+}
 
+/// Switches to the tables at `root`. Both halves share the root: TTBR0 walks
+/// the lower entries, TTBR1 the higher ones.
+// Not working yet, I rly hate AArch64 MMU
+pub unsafe fn activate(root: usize) {
     let mut mmfr0: u64;
     unsafe { core::arch::asm!("mrs {}, ID_AA64MMFR0_EL1", out(reg) mmfr0); }
     let parange = mmfr0 & 0xf;
@@ -153,6 +159,10 @@ pub unsafe fn identity_map() {
         | (0b01 << 10)  // IRGN0 = WB/WA
         | (0b11 << 12)  // SH0 = Inner Shareable
         | (0b00 << 14)  // TG0 = 4 KiB granule
+        | (16 << 16)    // T1SZ: 48-bit VA
+        | (0b01 << 24)  // IRGN1 = WB/WA
+        | (0b01 << 26)  // ORGN1 = WB/WA
+        | (0b11 << 28)  // SH1 = Inner Shareable
         | (0b10 << 30)  // TG1 = 4 KiB granule
         | (parange << 32) // IPS = PARange
     ;
@@ -161,7 +171,7 @@ pub unsafe fn identity_map() {
         // Set up registers for MMU
         mov x1, {0} // MAIR_EL1
         mov x2, {1} // TCR_EL1
-        mov x3, {2} // TTBR0_EL1
+        mov x3, {2} // TTBR0_EL1, TTBR1_EL1
 
         // Disable MMU
         mrs x0, sctlr_el1
@@ -178,6 +188,7 @@ pub unsafe fn identity_map() {
         msr mair_el1, x1
         msr tcr_el1, x2
         msr ttbr0_el1, x3
+        msr ttbr1_el1, x3
         isb
 
         // Enable MMU
@@ -190,7 +201,8 @@ pub unsafe fn identity_map() {
     ",
         in(reg) mair_el1,
         in(reg) tcr_el1,
-        in(reg) l0.addr()
+        in(reg) root,
+        out("x0") _, out("x1") _, out("x2") _, out("x3") _
     ); }
 }
 
@@ -1,12 +1,56 @@
-use spin::Mutex;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use crate::ram::{self, EXCEPTION_STACK_SIZE};
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::{segmentation::{Segment, CS, DS, ES, SS}, tables::load_tss},
+    registers::control::Cr2,
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+        tss::TaskStateSegment
+    },
+    VirtAddr
+};
 
 static IDT: Mutex<InterruptDescriptorTable> = Mutex::new(InterruptDescriptorTable::new());
 
+// The firmware's GDT has no TSS, so the kernel brings its own to give the
+// double fault handler a stack of its own: an overflow faults on the guard
+// page, the CPU cannot push the frame there, and the double fault follows.
+const DOUBLE_FAULT_IST: u16 = 0;
+
+static TSS: Once<TaskStateSegment> = Once::new();
+static GDT: Once<(GlobalDescriptorTable, SegmentSelector, SegmentSelector, SegmentSelector)> = Once::new();
+
+fn init_gdt() {
+    let tss = TSS.call_once(|| {
+        let stack = ram::alloc_stack(EXCEPTION_STACK_SIZE);
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST as usize] = VirtAddr::new((stack.addr() + stack.size()) as u64);
+        tss
+    });
+    let (gdt, code, data, tss) = GDT.call_once(|| {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code = gdt.append(Descriptor::kernel_code_segment());
+        let data = gdt.append(Descriptor::kernel_data_segment());
+        let tss = gdt.append(Descriptor::tss_segment(tss));
+        (gdt, code, data, tss)
+    });
+    gdt.load();
+    unsafe {
+        CS::set_reg(*code);
+        SS::set_reg(*data);
+        DS::set_reg(*data);
+        ES::set_reg(*data);
+        load_tss(*tss);
+    }
+}
+
 pub fn init_exceptions() {
+    init_gdt();
     let mut idt = IDT.lock();
     idt.breakpoint.set_handler_fn(breakpoint);
-    idt.double_fault.set_handler_fn(double_fault);
+    idt.page_fault.set_handler_fn(page_fault);
+    unsafe { idt.double_fault.set_handler_fn(double_fault).set_stack_index(DOUBLE_FAULT_IST); }
     unsafe { idt.load_unsafe(); }
 }
 
@@ -14,10 +58,21 @@ extern "x86-interrupt" fn breakpoint(_stack: InterruptStackFrame) {
     super::serial_puts("[INTERRUPT] Breakpoint\n");
 }
 
+extern "x86-interrupt" fn page_fault(
+    stack: InterruptStackFrame,
+    error_code: PageFaultErrorCode
+) {
+    let addr = Cr2::read_raw() as usize;
+    if ram::is_stack_guard(addr) { panic!("kernel stack overflow: fault at {:#x}", addr); }
+    panic!("page fault at {:#x} ({:?}) from {:#x}", addr, error_code, stack.instruction_pointer.as_u64());
+}
+
 extern "x86-interrupt" fn double_fault(
-    _stack: InterruptStackFrame,
+    stack: InterruptStackFrame,
     _error_code: u64
 ) -> ! {
-    super::serial_puts("[INTERRUPT] Double Fault\n");
-    loop { super::halt(); }
-}
\ No newline at end of file
+    // CR2 still holds the page fault that could not be delivered
+    let addr = Cr2::read_raw() as usize;
+    if ram::is_stack_guard(addr) { panic!("kernel stack overflow: fault at {:#x}", addr); }
+    panic!("double fault from {:#x}", stack.instruction_pointer.as_u64());
+}
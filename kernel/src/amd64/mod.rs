@@ -1,12 +1,14 @@
 mod exceptions;
+#[cfg(not(all(test, not(target_os = "none"))))]
+mod multiboot;
 
-use crate::{ember::ramtype, ram::PAGE_4KIB, ramblock::{self, AllocParams, RBPtr}, EMBER};
+use crate::{ram::PAGE_4KIB, ramblock::RBPtr, vmm, EMBER};
 pub use exceptions::init_exceptions;
 use x86_64::{
     instructions::{hlt, interrupts, port::Port, tlb},
     registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags, Cr4, Cr4Flags, Efer, EferFlags},
     structures::paging::PhysFrame,
-    PhysAddr
+    PhysAddr, VirtAddr
 };
 
 pub fn halt() {
@@ -14,6 +16,15 @@ pub fn halt() {
     hlt();
 }
 
+/// Leaves QEMU through its isa-debug-exit device at port 0xf4, which turns the
+/// value written into the exit status `(value << 1) | 1`: 33 passed, 35 failed.
+#[allow(dead_code)] // Only the test harness leaves QEMU
+pub fn exit_qemu(success: bool) -> ! {
+    let code: u32 = if success { 0x10 } else { 0x11 };
+    unsafe { Port::new(0xf4).write(code); }
+    loop { halt(); }
+}
+
 const COM1: u16 = 0x3f8;
 
 pub fn init_serial() {
@@ -59,86 +70,68 @@ impl core::fmt::Write for SerialWriter {
     }
 }
 
-// const UNAVAILABLE_FLAG: u64 = 0x01; // PRESENT
-const KERNEL_FLAG: u64 = 0x03;      // PRESENT | WRITABLE
-const NORMAL_FLAG: u64 = 0x07;      // PRESENT | WRITABLE | USER
-const PROTECT_FLAG: u64 = 0x1b;     // PRESENT | WRITABLE |      | PWT | PCD
+/// MMIO the kernel touches without the firmware listing it.
+pub const FIXED_MMIO: &[(usize, usize)] = &[];
 
-pub unsafe fn map_page(pml4: *mut u64, virt: u64, phys: u64, flags: u64) {
-    let virt = virt & 0x000fffff_fffff000;
-    let phys = phys & 0x000fffff_fffff000;
+pub const PTE_VALID: u64 = 0x01;
+pub const PTE_ADDR: u64  = 0x000fffff_fffff000;
+const WRITABLE: u64      = 0x02;
+const USER: u64          = 0x04;
+const UNCACHED: u64      = 0x18; // PWT | PCD
+const HUGE_PAGE: u64     = 0x80; // A 2 MiB leaf in a directory entry
+const NO_EXECUTE: u64    = 1 << 63;
 
-    fn get_index(level: usize, virt: u64) -> usize {
-        match level {
-            0 => ((virt >> 39) & 0x1ff) as usize, // PML4
-            1 => ((virt >> 30) & 0x1ff) as usize, // PDPT
-            2 => ((virt >> 21) & 0x1ff) as usize, // PD
-            3 => ((virt >> 12) & 0x1ff) as usize, // PT
-            _ => unreachable!(),
-        }
-    }
-
-    let mut table = pml4;
-    for level in 0..4 {
-        let index = get_index(level, virt);
-        let entry = unsafe { table.add(index) };
-        if level == 3 { unsafe { *entry = phys | flags; } }
-        else {
-            table = unsafe { if *entry & 0x1 == 0 {
-                let next_phys = ramblock::alloc(AllocParams::new(PAGE_4KIB).as_type(ramtype::PAGE_TABLE))
-                    .expect("[ERROR] alloc for page table failed!");
-                core::ptr::write_bytes(next_phys.ptr::<*mut u8>(), 0, PAGE_4KIB);
-                *entry = next_phys.addr() as u64 | KERNEL_FLAG;
-                next_phys.ptr()
-            }
-            else { (*entry & 0x000fffff_fffff000) as *mut u64 } };
-        }
-    }
+/// Tables allow everything; their leaves decide.
+pub fn table_entry(phys: usize) -> u64 {
+    return (phys as u64 & PTE_ADDR) | PTE_VALID | WRITABLE | USER;
 }
 
-fn flags_for(ty: u32) -> u64 {
-    match ty {
-        ramtype::CONVENTIONAL => NORMAL_FLAG,
-        ramtype::KERNEL =>       KERNEL_FLAG,
-        ramtype::KERNEL_DATA =>  KERNEL_FLAG,
-        ramtype::PAGE_TABLE =>   KERNEL_FLAG,
-        ramtype::MMIO =>         PROTECT_FLAG,
-        _ =>                     PROTECT_FLAG
-    }
+pub fn leaf_entry(phys: usize, prot: u8) -> u64 {
+    let mut entry = (phys as u64 & PTE_ADDR) | PTE_VALID;
+    if prot & vmm::prot::WRITE != 0  { entry |= WRITABLE; }
+    if prot & vmm::prot::USER != 0   { entry |= USER; }
+    if prot & vmm::prot::DEVICE != 0 { entry |= UNCACHED; }
+    if prot & vmm::prot::EXEC == 0   { entry |= NO_EXECUTE; }
+    return entry;
 }
 
-pub unsafe fn identity_map() {
-    let ember = EMBER.lock();
+/// Table writes are ordinary stores, which the walker sees in order; only the
+/// compiler must not sink them past the first use.
+pub fn sync_tables() {
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
 
-    // Enable PAE, PSE, and Long mode
-    unsafe {
-        Cr4::write(Cr4::read() | Cr4Flags::PHYSICAL_ADDRESS_EXTENSION | Cr4Flags::PAGE_SIZE_EXTENSION);
-        Efer::write(Efer::read() | EferFlags::LONG_MODE_ENABLE | EferFlags::NO_EXECUTE_ENABLE);
-    }
+/// A 2 MiB leaf, one level above pages.
+pub fn block_entry(phys: usize, prot: u8) -> u64 {
+    return leaf_entry(phys, prot) | HUGE_PAGE;
+}
 
-    let pml4_addr = ramblock::alloc(AllocParams::new(PAGE_4KIB).as_type(ramtype::PAGE_TABLE)).unwrap();
-    unsafe { core::ptr::write_bytes(pml4_addr.ptr::<*mut u8>(), 0, PAGE_4KIB); }
+pub fn is_block(entry: u64) -> bool { return entry & HUGE_PAGE != 0; }
 
-    // Map Page Tables
-    for desc in ember.efi_ram_layout() {
-        let block_ty = desc.ty;
-        let block_start = desc.phys_start;
-        let block_end = block_start + desc.page_count * PAGE_4KIB as u64;
+/// Page `index` of a 2 MiB block, with the block's attributes.
+pub fn block_page(block: u64, index: usize) -> u64 {
+    return (block & !PTE_ADDR & !HUGE_PAGE) | ((block & PTE_ADDR) + (index * PAGE_4KIB) as u64);
+}
 
-        for phys in (block_start..block_end).step_by(PAGE_4KIB) {
-            unsafe { map_page(pml4_addr.ptr(), phys, phys, flags_for(block_ty)); }
-        }
-    }
+pub fn flush_page(virt: usize) {
+    tlb::flush(VirtAddr::new_truncate(virt as u64));
+}
 
+/// Switches to the tables at `root`.
+pub unsafe fn activate(root: usize) {
     unsafe {
+        // Enable PAE, PSE, and Long mode
+        Cr4::write(Cr4::read() | Cr4Flags::PHYSICAL_ADDRESS_EXTENSION | Cr4Flags::PAGE_SIZE_EXTENSION);
+        Efer::write(Efer::read() | EferFlags::LONG_MODE_ENABLE | EferFlags::NO_EXECUTE_ENABLE);
+
         // Register PML4 in CR3
         Cr3::write(
-            PhysFrame::containing_address(PhysAddr::new(pml4_addr.addr() as u64)),
+            PhysFrame::containing_address(PhysAddr::new(root as u64)),
             Cr3Flags::empty()
         );
 
-        // Warrant that paging is enabled
-        Cr0::write(Cr0::read() | Cr0Flags::PAGING);
+        // Warrant that paging is enabled, and that read-only pages bind the kernel too
+        Cr0::write(Cr0::read() | Cr0Flags::PAGING | Cr0Flags::WRITE_PROTECT);
     }
 
     // Flush TLB
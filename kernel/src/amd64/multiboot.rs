@@ -0,0 +1,619 @@
+// Multiboot entry, for booting without the EFI loader: through GRUB's `multiboot2` command,
+// or QEMU's `-kernel`, which only speaks Multiboot 1. The loader places the image in the low
+// 4 GiB and enters it in 32-bit protected mode; the stub below identity maps that range,
+// switches to long mode, applies the image's own relocations, and translates the boot
+// information of either protocol into an Ember for `flame`.
+
+use crate::{ember::{ramtype, segflag, EmberError, Framebuffer, RAMDescriptor, Segment}, ram::PAGE_4KIB, sort::HeaplessSort};
+use core::fmt;
+use unix_v11_ember::{tag, Acpi, EmberWriter, Initrd, Kernel, RAMLayout, Stack};
+
+const BOOT_MAGIC: u32 = 0x36d76289;    // In EAX when a Multiboot2 loader jumps to us
+const BOOT_MAGIC_V1: u32 = 0x2badb002; // Likewise for Multiboot 1
+
+// Multiboot 1 cannot load an ELF64 image, so its header asks for the file from the header
+// on to be copied as is to a fixed address: page-aligned modules, a memory map, and the
+// address fields below
+const V1_FLAGS: u32 = (1 << 0) | (1 << 1) | (1 << 16);
+const V1_LOAD: u32  = 0x200000;
+
+// Boot information tags
+const TAG_END: u32          = 0;
+const TAG_CMDLINE: u32      = 1;
+const TAG_MODULE: u32       = 3;
+const TAG_MMAP: u32         = 6;
+const TAG_FRAMEBUFFER: u32  = 8;
+const TAG_ELF_SECTIONS: u32 = 9;
+const TAG_ACPI_OLD: u32     = 14;
+const TAG_ACPI_NEW: u32     = 15;
+
+const MMAP_AVAILABLE: u32        = 1;
+const MMAP_ACPI_RECLAIMABLE: u32 = 3;
+const MMAP_ACPI_NVS: u32         = 4;
+const MMAP_BAD: u32              = 5;
+
+// Multiboot 1 boot information, by the flag that says a field is valid
+const V1_CMDLINE: u32     = 1 << 2;
+const V1_MODULES: u32     = 1 << 3;
+const V1_MMAP: u32        = 1 << 6;
+const V1_FRAMEBUFFER: u32 = 1 << 12;
+const V1_INFO_SIZE: usize = 116;
+
+const FB_TYPE_RGB: u8 = 1;
+
+const SHF_WRITE: u64     = 0x1;
+const SHF_ALLOC: u64     = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+
+const STACK_SIZE: usize = 0x10000;
+const MAX_DESCRIPTORS: usize = 128;
+const MAX_SEGMENTS: usize = 16;
+const MAX_CLAIMS: usize = 8;
+const RSDP_MAX: usize = 36;
+
+#[repr(C, align(4096))]
+struct Pages<T>(T);
+
+// Everything the handoff needs lives in the image, so nothing has to be allocated yet.
+// Each buffer is page aligned so it gets a memory map entry of its own.
+static mut STACK: Pages<[u8; STACK_SIZE]> = Pages([0; STACK_SIZE]);
+static mut TABLES: Pages<[[u64; 512]; 6]> = Pages([[0; 512]; 6]); // PML4, PDPT, 4 page directories
+static mut LAYOUT: Pages<[RAMDescriptor; MAX_DESCRIPTORS]> = Pages([EMPTY_DESCRIPTOR; MAX_DESCRIPTORS]);
+static mut HANDOFF: Pages<[u8; PAGE_4KIB]> = Pages([0; PAGE_4KIB]);
+static mut RSDP: [u8; RSDP_MAX] = [0; RSDP_MAX]; // The loader only hands us a copy inside its own tags
+
+const EMPTY_DESCRIPTOR: RAMDescriptor = RAMDescriptor {
+    ty: 0, reserved: 0, phys_start: 0, virt_start: 0, page_count: 0, attr: 0, padding: 0
+};
+
+core::arch::global_asm!(
+    ".section .multiboot2, \"ax\"",
+    ".balign 4",
+    ".global multiboot1_header",
+    "multiboot1_header:",
+    ".long 0x1badb002",
+    ".long {v1_flags}",
+    ".long 0x100000000 - (0x1badb002 + {v1_flags})",
+    ".long {v1_load} + MULTIBOOT1_HEADER",              // Where the header lands, which fixes the file offset
+    ".long {v1_load}",
+    ".long {v1_load} + MULTIBOOT_LOAD_END",             // End of what the file holds
+    ".long {v1_load} + MULTIBOOT_BSS_END",              // End of the image, zeroed past the file
+    ".long {v1_load} + MULTIBOOT_ENTRY",
+
+    ".balign 8",
+    "multiboot_header:",
+    ".long 0xe85250d6",
+    ".long 0",                                          // i386 protected mode
+    ".long multiboot_header_end - multiboot_header",
+    ".long 0x100000000 - (0xe85250d6 + (multiboot_header_end - multiboot_header))",
+
+    // Information request: command line, modules, memory map, framebuffer, sections, ACPI
+    ".balign 8",
+    ".short 1, 0",
+    ".long 8 + 4 * 7",
+    ".long 1, 3, 6, 8, 9, 14, 15",
+
+    // Relocatable: anywhere page aligned between 2 MiB and 4 GiB, as low as possible
+    ".balign 8",
+    ".short 10, 0",
+    ".long 24",
+    ".long 0x200000, 0xffffffff, 0x1000, 1",
+
+    // Entry: the link address of the stub, which the loader shifts along with the image.
+    // The image is linked at zero, so this is also the stub's offset into it
+    ".balign 8",
+    ".short 3, 0",
+    ".long 12",
+    "multiboot_entry_addr:",
+    ".long MULTIBOOT_ENTRY",
+
+    // Framebuffer: any mode, 32 bits per pixel, if the loader can set one
+    ".balign 8",
+    ".short 5, 1",
+    ".long 20",
+    ".long 0, 0, 32",
+
+    ".balign 8",
+    ".short 0, 0",
+    ".long 8",
+    "multiboot_header_end:",
+
+    // Accessed bits are preset, so the CPU never writes to this table once .text is read-only
+    ".balign 8",
+    ".Lgdt:",
+    ".quad 0",
+    ".quad 0x00209b0000000000",                         // 0x08: 64-bit code
+    ".quad 0x0000930000000000",                         // 0x10: data
+
+    ".code32",
+    ".global multiboot_start",
+    "multiboot_start:",
+    "cli",
+    "cld",
+    "call .Lhere",
+    ".Lhere:",
+    "pop ebp",                                          // Everything below is addressed from here
+    ".set .Lstack_top, {stack} + {stack_size} - .Lhere",
+    ".set .Ltables, {tables} - .Lhere",
+    ".set .Lstart, multiboot_start - .Lhere",
+    ".set .Lentry_addr, multiboot_entry_addr - .Lhere",
+    ".set .Lgdt_addr, .Lgdt - .Lhere",
+    ".set .Llong_mode_addr, .Llong_mode - .Lhere",
+    "cmp eax, {magic}",
+    "je .Lknown",
+    "cmp eax, {magic_v1}",
+    "jne .Lhang",
+    ".Lknown:",
+    "lea esp, [ebp + .Lstack_top]",
+    "push eax",                                         // Which protocol
+    "push ebx",
+
+    // Image base: where the stub runs, less where it was linked
+    "lea esi, [ebp + .Lstart]",
+    "sub esi, [ebp + .Lentry_addr]",
+
+    // Identity map the low 4 GiB with 2 MiB pages
+    "lea ebx, [ebp + .Ltables]",
+    "mov edi, ebx",
+    "xor eax, eax",
+    "mov ecx, 6 * 1024",
+    "rep stosd",
+    "lea eax, [ebx + 0x1003]",                          // PML4[0] -> PDPT
+    "mov [ebx], eax",
+    "lea eax, [ebx + 0x2003]",                          // PDPT[0..4] -> page directories
+    "mov [ebx + 0x1000], eax",
+    "add eax, 0x1000",
+    "mov [ebx + 0x1008], eax",
+    "add eax, 0x1000",
+    "mov [ebx + 0x1010], eax",
+    "add eax, 0x1000",
+    "mov [ebx + 0x1018], eax",
+    "lea edi, [ebx + 0x2000]",
+    "mov eax, 0x83",                                    // PRESENT | WRITABLE | HUGE
+    "mov ecx, 4 * 512",
+    ".Lfill:",
+    "mov [edi], eax",
+    "add eax, 0x200000",
+    "add edi, 8",
+    "dec ecx",
+    "jnz .Lfill",
+    "pop edi",                                          // Boot information
+    "pop edx",                                          // Magic
+
+    // PAE, then long mode and NX in EFER, then paging
+    "mov cr3, ebx",
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, (1 << 31) | 1",
+    "mov cr0, eax",
+
+    "lea eax, [ebp + .Lgdt_addr]",
+    "sub esp, 8",
+    "mov word ptr [esp], 23",
+    "mov [esp + 2], eax",
+    "lgdt [esp]",
+    "add esp, 8",
+    "lea eax, [ebp + .Llong_mode_addr]",
+    "push 0x08",
+    "push eax",
+    "retf",
+
+    ".Lhang:",
+    "hlt",
+    "jmp .Lhang",
+
+    ".code64",
+    ".Llong_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov edi, edi",                                     // Upper halves are undefined after the switch
+    "mov esi, esi",
+    "mov edx, edx",
+    "mov esp, esp",
+    "and rsp, -16",
+
+    // Nothing holding an absolute address is usable before this
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "sub rsp, 8",
+    "mov rdi, rsi",
+    "lea rsi, [rip + _DYNAMIC]",
+    "call {relocate}",
+    "add rsp, 8",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "call {main}",
+    "ud2",
+    ".previous",
+
+    magic = const BOOT_MAGIC,
+    magic_v1 = const BOOT_MAGIC_V1,
+    v1_flags = const V1_FLAGS,
+    v1_load = const V1_LOAD,
+    stack = sym STACK,
+    stack_size = const STACK_SIZE,
+    tables = sym TABLES,
+    relocate = sym relocate,
+    main = sym multiboot_main
+);
+
+#[repr(C)]
+struct DynEntry {
+    tag: i64,
+    val: u64
+}
+
+const DT_NULL: i64    = 0;
+const DT_RELA: i64    = 7;
+const DT_RELASZ: i64  = 8;
+const DT_RELRSZ: i64  = 35;
+const DT_RELR: i64    = 36;
+const R_RELATIVE: u64 = 8;
+
+/// Applies the image's relative relocations, which is all a static PIE carries.
+/// Runs before them, so it must not touch anything holding an absolute address:
+/// no statics, no formatting, no panics.
+unsafe extern "C" fn relocate(base: usize, dynamic: *const DynEntry) {
+    let (mut rela, mut rela_size, mut relr, mut relr_size) = (0, 0, 0, 0);
+    let mut entry = dynamic;
+    loop {
+        let DynEntry { tag, val } = unsafe { entry.read() };
+        match tag {
+            DT_NULL   => break,
+            DT_RELA   => rela = val as usize,
+            DT_RELASZ => rela_size = val as usize,
+            DT_RELR   => relr = val as usize,
+            DT_RELRSZ => relr_size = val as usize,
+            _ => {}
+        }
+        entry = entry.wrapping_add(1);
+    }
+
+    let mut offset = 0;
+    while offset < rela_size {
+        let rela = base.wrapping_add(rela + offset) as *const u64;
+        let (target, info, addend) = unsafe { (rela.read(), rela.add(1).read(), rela.add(2).read()) };
+        if info & 0xffffffff == R_RELATIVE {
+            unsafe { *(base.wrapping_add(target as usize) as *mut u64) = (base as u64).wrapping_add(addend); }
+        }
+        offset += 24;
+    }
+
+    // RELR: an address entry relocates one word, then bitmap entries cover the next 63
+    let mut next = core::ptr::null_mut::<u64>();
+    let mut offset = 0;
+    while offset < relr_size {
+        let word = unsafe { *(base.wrapping_add(relr + offset) as *const u64) };
+        if word & 1 == 0 {
+            next = base.wrapping_add(word as usize) as *mut u64;
+            unsafe { *next = (*next).wrapping_add(base as u64); }
+            next = next.wrapping_add(1);
+        } else {
+            let mut bits = word >> 1;
+            let mut slot = next;
+            while bits != 0 {
+                if bits & 1 != 0 { unsafe { *slot = (*slot).wrapping_add(base as u64); } }
+                bits >>= 1;
+                slot = slot.wrapping_add(1);
+            }
+            next = next.wrapping_add(63);
+        }
+        offset += 8;
+    }
+}
+
+unsafe extern "C" {
+    static multiboot1_header: [u32; 8];
+}
+
+#[derive(Debug)]
+enum HandoffError {
+    NoMemoryMap,
+    NoSections,
+    Ember(EmberError)
+}
+
+impl fmt::Display for HandoffError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoMemoryMap => write!(f, "the loader passed no memory map"),
+            Self::NoSections => write!(f, "the loader passed no ELF sections"),
+            Self::Ember(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl From<EmberError> for HandoffError {
+    fn from(err: EmberError) -> Self { Self::Ember(err) }
+}
+
+fn align_down(val: u64) -> u64 { val & !(PAGE_4KIB as u64 - 1) }
+fn align_up(val: u64) -> u64 { align_down(val + PAGE_4KIB as u64 - 1) }
+
+fn field<const N: usize>(bytes: &[u8], offset: usize) -> [u8; N] {
+    return bytes.get(offset..offset + N).and_then(|bytes| bytes.try_into().ok()).unwrap_or([0; N]);
+}
+fn u32_at(bytes: &[u8], offset: usize) -> u32 { u32::from_le_bytes(field(bytes, offset)) }
+fn u64_at(bytes: &[u8], offset: usize) -> u64 { u64::from_le_bytes(field(bytes, offset)) }
+
+/// Yields each tag of the boot information as `(type, whole tag)`.
+fn tags<'a>(info: *const u8) -> impl Iterator<Item = (u32, &'a [u8])> {
+    let total = unsafe { (info as *const u32).read() } as usize;
+    let info = unsafe { core::slice::from_raw_parts(info, total) };
+    let mut offset = 8;
+    return core::iter::from_fn(move || {
+        let (ty, size) = (u32_at(info, offset), u32_at(info, offset + 4) as usize);
+        if ty == TAG_END || size < 8 || offset + size > info.len() { return None; }
+        let tag = &info[offset..offset + size];
+        offset = (offset + size + 7) & !7;
+        return Some((ty, tag));
+    });
+}
+
+/// Both protocols describe the framebuffer with the same fields; only where they
+/// start and where the colour layout follows differ.
+fn framebuffer(info: &[u8], offset: usize, colours: usize) -> Option<Framebuffer> {
+    let (pitch, width, height) = (u32_at(info, offset + 8), u32_at(info, offset + 12), u32_at(info, offset + 16));
+    let [bpp, ty] = field(info, offset + 20);
+    if bpp != 32 || ty != FB_TYPE_RGB { return None; }
+    let [red_pos, red_size, green_pos, green_size, blue_pos, blue_size] = field(info, colours);
+    let mask = |position: u8, size: u8| (((1u64 << size) - 1) << position) as u32;
+    return Some(Framebuffer {
+        base: u64_at(info, offset),
+        size: pitch as u64 * height as u64,
+        width, height,
+        stride: pitch / 4,
+        red_mask: mask(red_pos, red_size),
+        green_mask: mask(green_pos, green_size),
+        blue_mask: mask(blue_pos, blue_size)
+    });
+}
+
+/// Loaded sections of the image, which stand in for the segments the EFI loader reports.
+fn segments(tag: &[u8], base: u64, out: &mut [Segment; MAX_SEGMENTS]) -> (usize, u64, u64) {
+    let (num, entsize) = (u32_at(tag, 8) as usize, u32_at(tag, 12) as usize);
+    let (mut count, mut start, mut end) = (0, u64::MAX, 0);
+    for index in 0..num {
+        let Some(sh) = tag.get(20 + index * entsize..20 + (index + 1) * entsize) else { break; };
+        let (flags, addr, size) = (u64_at(sh, 8), u64_at(sh, 16), u64_at(sh, 32));
+        if flags & SHF_ALLOC == 0 || size == 0 { continue; }
+        (start, end) = (start.min(addr), end.max(addr + size));
+        if count == MAX_SEGMENTS { continue; }
+
+        let mut seg_flags = segflag::R;
+        if flags & SHF_WRITE != 0     { seg_flags |= segflag::W; }
+        if flags & SHF_EXECINSTR != 0 { seg_flags |= segflag::X; }
+        out[count] = Segment { addr: base + addr, size, flags: seg_flags };
+        count += 1;
+    }
+    return (count, base + align_down(start), base + align_up(end));
+}
+
+#[derive(Clone, Copy)]
+struct Claim {
+    start: u64,
+    end: u64,
+    ty: u32
+}
+
+fn claim_of<T>(ptr: *const T, ty: u32) -> Claim {
+    return Claim { start: ptr as u64, end: ptr as u64 + size_of::<T>() as u64, ty };
+}
+
+#[derive(Clone, Copy)]
+enum Mmap<'a> {
+    V1(&'a [u8]), // Entries, each led by its own size
+    V2(&'a [u8])  // The whole tag, whose header gives the size of every entry
+}
+
+impl<'a> Mmap<'a> {
+    /// Yields each entry as `(base, length, type)`.
+    fn entries(self) -> impl Iterator<Item = (u64, u64, u32)> + 'a {
+        let (bytes, mut offset) = match self { Self::V1(bytes) => (bytes, 0), Self::V2(tag) => (tag, 16) };
+        return core::iter::from_fn(move || {
+            let (entry, next) = match self {
+                Self::V1(_) => (offset + 4, offset + 4 + u32_at(bytes, offset) as usize),
+                Self::V2(_) => (offset, offset + (u32_at(bytes, 8) as usize).max(24))
+            };
+            if entry + 20 > bytes.len() { return None; }
+            offset = next;
+            return Some((u64_at(bytes, entry), u64_at(bytes, entry + 8), u32_at(bytes, entry + 16)));
+        });
+    }
+}
+
+/// Turns the Multiboot memory map into UEFI-style descriptors. Available memory
+/// is split around what we occupy, so `Ember::init` can type each piece by itself.
+fn translate_mmap(mmap: Mmap, claims: &[Claim]) -> &'static [RAMDescriptor] {
+    let layout = unsafe { &mut (*&raw mut LAYOUT).0 };
+    let mut count = 0;
+    let mut push = |start: u64, end: u64, ty: u32| {
+        if start >= end || count == MAX_DESCRIPTORS { return; }
+        layout[count] = RAMDescriptor {
+            ty, reserved: 0, phys_start: start, virt_start: 0,
+            page_count: (end - start) / PAGE_4KIB as u64, attr: 0, padding: 0
+        };
+        count += 1;
+    };
+
+    for (base, len, ty) in mmap.entries() {
+        let ty = match ty {
+            MMAP_AVAILABLE        => ramtype::CONVENTIONAL,
+            MMAP_ACPI_RECLAIMABLE => ramtype::ACPI_RECLAIM,
+            MMAP_ACPI_NVS         => ramtype::ACPI_NON_VOLATILE,
+            MMAP_BAD              => ramtype::UNUSABLE,
+            _                     => ramtype::RESERVED
+        };
+        if ty != ramtype::CONVENTIONAL {
+            push(align_down(base), align_up(base + len), ty);
+            continue;
+        }
+
+        let (start, end) = (align_up(base), align_down(base + len));
+        let mut cursor = start;
+        for claim in claims {
+            if claim.start >= end || claim.end <= cursor { continue; }
+            let claim_start = claim.start.max(cursor);
+            let claim_end = claim.end.min(end);
+            push(cursor, claim_start, ramtype::CONVENTIONAL);
+            push(claim_start, claim_end, claim.ty);
+            cursor = claim_end;
+        }
+        push(cursor, end, ramtype::CONVENTIONAL);
+    }
+    return &layout[..count];
+}
+
+/// What the loader told us, whichever protocol it spoke.
+#[derive(Default)]
+struct BootInfo<'a> {
+    mmap: Option<Mmap<'a>>,
+    sections: Option<&'a [u8]>,
+    cmdline: &'a [u8],
+    initrd: Option<(u64, u64)>,
+    rsdp: Option<&'a [u8]>,
+    fb: Option<Framebuffer>
+}
+
+fn boot_info<'a>(info: *const u8) -> BootInfo<'a> {
+    let mut boot = BootInfo::default();
+    for (ty, tag) in tags(info) {
+        match ty {
+            TAG_CMDLINE      => boot.cmdline = tag[8..].split(|&b| b == 0).next().unwrap_or(&[]),
+            TAG_MODULE       => if boot.initrd.is_none() { boot.initrd = Some((u32_at(tag, 8) as u64, u32_at(tag, 12) as u64)); },
+            TAG_MMAP         => boot.mmap = Some(Mmap::V2(tag)),
+            TAG_FRAMEBUFFER  => boot.fb = framebuffer(tag, 8, 32),
+            TAG_ELF_SECTIONS => boot.sections = Some(tag),
+            TAG_ACPI_OLD     => if boot.rsdp.is_none() { boot.rsdp = Some(&tag[8..]); },
+            TAG_ACPI_NEW     => boot.rsdp = Some(&tag[8..]),
+            _ => {}
+        }
+    }
+    return boot;
+}
+
+/// Multiboot 1 keeps its information in one fixed structure, flagged field by field,
+/// and passes neither the RSDP nor usable sections.
+fn boot_info_v1<'a>(info: *const u8) -> BootInfo<'a> {
+    let info = unsafe { core::slice::from_raw_parts(info, V1_INFO_SIZE) };
+    let flags = u32_at(info, 0);
+    let mut boot = BootInfo { rsdp: find_rsdp(), ..Default::default() };
+    if flags & V1_CMDLINE != 0 {
+        let cmdline = u32_at(info, 16) as *const core::ffi::c_char;
+        boot.cmdline = unsafe { core::ffi::CStr::from_ptr(cmdline) }.to_bytes();
+    }
+    if flags & V1_MODULES != 0 && u32_at(info, 20) != 0 {
+        let module = unsafe { core::slice::from_raw_parts(u32_at(info, 24) as *const u8, 16) };
+        boot.initrd = Some((u32_at(module, 0) as u64, u32_at(module, 4) as u64));
+    }
+    if flags & V1_MMAP != 0 {
+        let (len, addr) = (u32_at(info, 44) as usize, u32_at(info, 48) as *const u8);
+        boot.mmap = Some(Mmap::V1(unsafe { core::slice::from_raw_parts(addr, len) }));
+    }
+    if flags & V1_FRAMEBUFFER != 0 { boot.fb = framebuffer(info, 88, 110); }
+    return boot;
+}
+
+/// On a BIOS the RSDP sits on a 16-byte boundary in the first KiB of the EBDA
+/// or in 0xe0000..0x100000, and is found by its signature and checksum.
+fn find_rsdp() -> Option<&'static [u8]> {
+    let ebda = (unsafe { (0x40e as *const u16).read_unaligned() } as usize) << 4;
+    for (start, end) in [(ebda, ebda + 0x400), (0xe0000, 0x100000)] {
+        if start == 0 { continue; }
+        for addr in (start..end).step_by(16) {
+            let rsdp = unsafe { core::slice::from_raw_parts(addr as *const u8, 20) };
+            if &rsdp[..8] != b"RSD PTR " || rsdp.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 { continue; }
+            let len = if rsdp[15] >= 2 { RSDP_MAX } else { 20 };
+            return Some(unsafe { core::slice::from_raw_parts(addr as *const u8, len) });
+        }
+    }
+    return None;
+}
+
+fn handoff(info: *const u8, base: u64, magic: u32) -> Result<*const u8, HandoffError> {
+    let BootInfo { mmap, sections, cmdline, initrd, rsdp, fb } = match magic {
+        BOOT_MAGIC_V1 => boot_info_v1(info),
+        _ => boot_info(info)
+    };
+    let mmap = mmap.ok_or(HandoffError::NoMemoryMap)?;
+    let mut segment_buf = [Segment { addr: 0, size: 0, flags: 0 }; MAX_SEGMENTS];
+    let (segment_count, kernel_start, kernel_end) = match sections {
+        Some(sections) => segments(sections, base, &mut segment_buf),
+        None if magic == BOOT_MAGIC_V1 => {
+            // Loaded by the header's address fields; without sections every page stays executable
+            let header = unsafe { multiboot1_header };
+            (0, base, base + align_up((header[6] - header[4]) as u64))
+        }
+        None => return Err(HandoffError::NoSections)
+    };
+
+    // The kernel's own buffers each get a piece of their own; the rest of the image is code
+    let mut inner = [
+        claim_of(&raw const TABLES, ramtype::LOADER_DATA),
+        claim_of(&raw const LAYOUT, ramtype::LOADER_DATA),
+        claim_of(&raw const HANDOFF, ramtype::LOADER_DATA)
+    ];
+    let mut inner_slice = &mut inner[..];
+    inner_slice.sort_noheap_by_key(|claim| claim.start);
+    let mut claims = [Claim { start: 0, end: 0, ty: 0 }; MAX_CLAIMS];
+    let mut claim_count = 0;
+    let mut cursor = kernel_start;
+    for claim in inner {
+        claims[claim_count] = Claim { start: cursor, end: claim.start, ty: ramtype::LOADER_CODE };
+        claims[claim_count + 1] = claim;
+        (claim_count, cursor) = (claim_count + 2, claim.end);
+    }
+    claims[claim_count] = Claim { start: cursor, end: kernel_end, ty: ramtype::LOADER_CODE };
+    claim_count += 1;
+    if let Some((start, end)) = initrd {
+        claims[claim_count] = Claim { start: align_down(start), end: align_up(end), ty: ramtype::INITRD };
+        claim_count += 1;
+    }
+    let mut claim_slice = &mut claims[..claim_count];
+    claim_slice.sort_noheap_by_key(|claim| claim.start);
+    let layout = translate_mmap(mmap, &claims[..claim_count]);
+
+    let buf = unsafe { &mut (*&raw mut HANDOFF).0 };
+    let mut ember = EmberWriter::new(buf)?;
+    if let Some(rsdp) = rsdp {
+        let copy = unsafe { &mut *&raw mut RSDP };
+        let len = rsdp.len().min(RSDP_MAX);
+        copy[..len].copy_from_slice(&rsdp[..len]);
+        ember.push(&Acpi { rsdp: copy.as_ptr() as u64 })?;
+    }
+    if !cmdline.is_empty() { ember.push_raw(tag::CMDLINE, cmdline)?; }
+    if let Some((start, end)) = initrd { ember.push(&Initrd { ptr: start, size: end - start })?; }
+    ember.push(&Kernel { base: kernel_start, size: kernel_end - kernel_start })?;
+    ember.push_slice(&segment_buf[..segment_count])?;
+    if let Some(fb) = fb { ember.push(&fb)?; }
+    ember.push(&Stack { base: unsafe { (&raw const STACK).add(1) } as u64 })?;
+    ember.push(&RAMLayout {
+        ptr: layout.as_ptr() as u64,
+        len: layout.len() as u64,
+        desc_size: size_of::<RAMDescriptor>() as u64
+    })?;
+    return Ok(ember.finish()?.as_ptr());
+}
+
+extern "C" fn multiboot_main(info: *const u8, base: u64, magic: u32) -> ! {
+    match handoff(info, base, magic) {
+        Ok(ember) => crate::flame(ember),
+        Err(err) => {
+            super::init_serial();
+            printlnk!("Multiboot handoff failed: {}", err);
+            loop { super::halt(); }
+        }
+    }
+}
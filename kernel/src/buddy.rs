@@ -0,0 +1,511 @@
+// Buddy page-frame allocator. Every CONVENTIONAL range of the RAM layout becomes
+// a zone, tiled by naturally aligned blocks of 2^order pages. Free blocks sit on
+// one list per order, threaded through a frame array carved out of RAM at init;
+// free memory itself is never written, so it need not be mapped yet. Zones are cut
+// at the DMA limits, and each class of zones below a limit has lists of its own.
+use crate::ram::PAGE_4KIB;
+use core::fmt;
+
+pub const MAX_ORDER: usize = 18; // 1 GiB blocks
+const NIL: u32 = u32::MAX;
+
+/// Ends of the address classes below NORMAL memory: ISA DMA, then 32-bit DMA.
+pub const DMA_LIMITS: [usize; 2] = [16 << 20, 1 << 32];
+const CLASSES: usize = DMA_LIMITS.len() + 1;
+
+const TAIL: u8 = 0; // Inside some block, or not yet handed to the allocator
+const FREE: u8 = 1; // Head of a block on a free list
+const USED: u8 = 2; // Head of an allocated block
+
+// An allocation may span several blocks, so its first frame records how many pages
+// it has. A free must stay inside one allocation; what is left on either side of it
+// becomes an allocation of its own.
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    next: u32,
+    prev: u32,
+    ty: u32,
+    order: u8,
+    state: u8,
+    owner: u16,
+    pages: u32 // Length of the allocation starting here; 0 on every other frame
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Zone {
+    base: usize,  // First page frame number
+    pages: usize,
+    first: usize, // Index of the first frame in the frame array
+    class: usize  // Lists this zone's free blocks go on
+}
+
+impl Zone {
+    fn end(&self) -> usize { self.base + self.pages }
+    fn contains(&self, start: usize, end: usize) -> bool { start >= self.base && end <= self.end() }
+}
+
+/// Run of adjacent blocks with the same state, type and owner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Run {
+    pub addr: usize,
+    pub size: usize,
+    pub ty: u32,    // 0 when free
+    pub owner: u16,
+    pub used: bool
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreeError {
+    Unmanaged(usize),
+    NotAllocated(usize),
+    Overrun(usize)
+}
+
+impl fmt::Display for FreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unmanaged(addr) => write!(f, "{:#x} is not in allocatable RAM", addr),
+            Self::NotAllocated(addr) => write!(f, "page {:#x} is not allocated (double free?)", addr),
+            Self::Overrun(addr) => write!(f, "free runs past the allocation ending at {:#x}", addr)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Buddy {
+    zones: *mut Zone,
+    zone_count: usize,
+    zone_max: usize,
+    frames: *mut Frame,
+    frame_count: usize,
+    frame_max: usize,
+    free_lists: [[u32; MAX_ORDER + 1]; CLASSES],
+    free_pages: [usize; CLASSES],
+    total_pages: usize
+}
+
+unsafe impl Send for Buddy {}
+unsafe impl Sync for Buddy {}
+
+/// Largest order of a block starting at `pfn` that ends at or before `end`.
+fn piece_order(pfn: usize, end: usize) -> usize {
+    let mut order = (pfn.trailing_zeros() as usize).min(MAX_ORDER);
+    while pfn + (1 << order) > end { order -= 1; }
+    return order;
+}
+
+/// Class of the page frame `pfn`.
+fn class_of(pfn: usize) -> usize {
+    return DMA_LIMITS.iter().filter(|&&limit| pfn * PAGE_4KIB >= limit).count();
+}
+
+fn class_base(class: usize) -> usize {
+    return class.checked_sub(1).map_or(0, |below| DMA_LIMITS[below]);
+}
+
+fn class_limit(class: usize) -> usize {
+    return DMA_LIMITS.get(class).copied().unwrap_or(usize::MAX);
+}
+
+/// Order of the smallest block holding `pages` pages.
+pub fn order_for(pages: usize) -> usize {
+    return pages.max(1).next_power_of_two().trailing_zeros() as usize;
+}
+
+impl Buddy {
+    pub const fn empty() -> Self {
+        Buddy {
+            zones: core::ptr::null_mut(),
+            zone_count: 0,
+            zone_max: 0,
+            frames: core::ptr::null_mut(),
+            frame_count: 0,
+            frame_max: 0,
+            free_lists: [[NIL; MAX_ORDER + 1]; CLASSES],
+            free_pages: [0; CLASSES],
+            total_pages: 0
+        }
+    }
+
+    /// Bytes of metadata needed for `zones` ranges spanning `pages` pages in total.
+    /// Cutting at the DMA limits may add a zone per limit.
+    pub fn meta_size(zones: usize, pages: usize) -> usize {
+        return Self::zone_slots(zones) * size_of::<Zone>() + pages * size_of::<Frame>();
+    }
+
+    fn zone_slots(zones: usize) -> usize { zones + DMA_LIMITS.len() }
+
+    /// Takes `meta` (at least `meta_size(zones, pages)` bytes, 8-byte aligned) for the
+    /// zone and frame arrays. Zones are added afterwards with `add_zone`.
+    pub unsafe fn init(&mut self, meta: *mut u8, zones: usize, pages: usize) {
+        unsafe { core::ptr::write_bytes(meta, 0, Self::meta_size(zones, pages)); }
+        self.zones = meta as *mut Zone;
+        self.zone_max = Self::zone_slots(zones);
+        self.frames = unsafe { meta.add(self.zone_max * size_of::<Zone>()) } as *mut Frame;
+        self.frame_max = pages;
+    }
+
+    /// Hands the whole pages of `start..end` to the allocator, as one zone per DMA
+    /// class it touches. Zones must come in ascending address order.
+    pub fn add_zone(&mut self, start: usize, end: usize) {
+        let mut start = start;
+        for limit in DMA_LIMITS {
+            if start < limit && limit < end {
+                self.add_class_zone(start, limit);
+                start = limit;
+            }
+        }
+        self.add_class_zone(start, end);
+    }
+
+    fn add_class_zone(&mut self, start: usize, end: usize) {
+        let base = start.div_ceil(PAGE_4KIB);
+        let pages = (end / PAGE_4KIB).saturating_sub(base).min(self.frame_max - self.frame_count);
+        if pages == 0 || self.zone_count == self.zone_max { return; }
+
+        let zone = Zone { base, pages, first: self.frame_count, class: class_of(base) };
+        unsafe { self.zones.add(self.zone_count).write(zone); }
+        self.zone_count += 1;
+        self.frame_count += pages;
+        self.total_pages += pages;
+        self.release_range(zone, base, zone.end());
+    }
+
+    pub fn free_pages(&self) -> usize { self.free_pages.iter().sum() }
+
+    /// Free pages that end at or below `limit`, counting whole classes only.
+    pub fn free_pages_below(&self, limit: usize) -> usize {
+        return (0..CLASSES).filter(|&class| class_limit(class) <= limit).map(|class| self.free_pages[class]).sum();
+    }
+    pub fn total_pages(&self) -> usize { self.total_pages }
+
+    /// Length of the free list of one order.
+    #[cfg(all(test, not(target_os = "none")))]
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count = 0;
+        for lists in &self.free_lists {
+            let mut idx = lists[order];
+            while idx != NIL {
+                idx = unsafe { (*self.frames.add(idx as usize)).next };
+                count += 1;
+            }
+        }
+        return count;
+    }
+
+    /// Runs of the whole allocator in address order.
+    pub fn runs(&self) -> impl Iterator<Item = Run> + '_ {
+        let (mut zone_idx, mut pfn) = (0, self.zones().first().map_or(0, |zone| zone.base));
+        return core::iter::from_fn(move || {
+            let zone = *self.zones().get(zone_idx)?;
+            let head = self.frame(zone, pfn);
+            let start = pfn;
+            while pfn < zone.end() {
+                let frame = self.frame(zone, pfn);
+                if frame.state != head.state || frame.ty != head.ty || frame.owner != head.owner { break; }
+                pfn += 1 << frame.order;
+            }
+            let end = pfn;
+            if pfn >= zone.end() {
+                zone_idx += 1;
+                if let Some(next) = self.zones().get(zone_idx) { pfn = next.base; }
+            }
+            return Some(Run {
+                addr: start * PAGE_4KIB, size: (end - start) * PAGE_4KIB,
+                ty: head.ty, owner: head.owner, used: head.state == USED
+            });
+        });
+    }
+
+    /// Owner of the allocated block holding `addr`.
+    pub fn owner_of(&self, addr: usize) -> Option<u16> {
+        let pfn = addr / PAGE_4KIB;
+        let zone = self.zone_of(pfn)?;
+        let (head, _) = self.block_of(zone, pfn)?;
+        let frame = self.frame(zone, head);
+        return (frame.state == USED).then_some(frame.owner);
+    }
+
+    fn zones(&self) -> &[Zone] {
+        if self.zones.is_null() { return &[]; }
+        return unsafe { core::slice::from_raw_parts(self.zones, self.zone_count) };
+    }
+
+    fn zone_of(&self, pfn: usize) -> Option<Zone> {
+        let zones = self.zones();
+        let idx = zones.partition_point(|zone| zone.end() <= pfn);
+        return zones.get(idx).filter(|zone| zone.base <= pfn).copied();
+    }
+
+    fn zone_of_index(&self, idx: usize) -> Zone {
+        let zones = self.zones();
+        return zones[zones.partition_point(|zone| zone.first + zone.pages <= idx)];
+    }
+
+    fn frame(&self, zone: Zone, pfn: usize) -> Frame {
+        return unsafe { self.frames.add(zone.first + pfn - zone.base).read() };
+    }
+
+    fn frame_mut(&mut self, zone: Zone, pfn: usize) -> &mut Frame {
+        return unsafe { &mut *self.frames.add(zone.first + pfn - zone.base) };
+    }
+
+    fn frame_at(&mut self, idx: u32) -> &mut Frame {
+        return unsafe { &mut *self.frames.add(idx as usize) };
+    }
+
+    fn list_push(&mut self, zone: Zone, pfn: usize, order: usize) {
+        let idx = (zone.first + pfn - zone.base) as u32;
+        let head = self.free_lists[zone.class][order];
+        *self.frame_mut(zone, pfn) = Frame { next: head, prev: NIL, ty: 0, order: order as u8, state: FREE, owner: 0, pages: 0 };
+        if head != NIL { self.frame_at(head).prev = idx; }
+        self.free_lists[zone.class][order] = idx;
+        self.free_pages[zone.class] += 1 << order;
+    }
+
+    fn list_remove(&mut self, zone: Zone, pfn: usize, order: usize) {
+        let Frame { next, prev, .. } = self.frame(zone, pfn);
+        if prev != NIL { self.frame_at(prev).next = next; } else { self.free_lists[zone.class][order] = next; }
+        if next != NIL { self.frame_at(next).prev = prev; }
+        self.frame_mut(zone, pfn).state = TAIL;
+        self.free_pages[zone.class] -= 1 << order;
+    }
+
+    /// Frees one block, merging it with its buddy for as long as that is free too.
+    fn insert_free(&mut self, zone: Zone, mut pfn: usize, mut order: usize) {
+        self.frame_mut(zone, pfn).state = TAIL;
+        while order < MAX_ORDER {
+            let buddy = pfn ^ (1 << order);
+            if !zone.contains(buddy, buddy + (1 << order)) { break; }
+            let frame = self.frame(zone, buddy);
+            if frame.state != FREE || frame.order as usize != order { break; }
+            self.list_remove(zone, buddy, order);
+            pfn = pfn.min(buddy);
+            order += 1;
+        }
+        self.list_push(zone, pfn, order);
+    }
+
+    /// Frees `start..end`, cut into the largest aligned blocks that fit.
+    fn release_range(&mut self, zone: Zone, mut start: usize, end: usize) {
+        while start < end {
+            let order = piece_order(start, end);
+            self.insert_free(zone, start, order);
+            start += 1 << order;
+        }
+    }
+
+    /// Marks `start..end`, already off the free lists, as allocated; cut the same way
+    /// as `release_range`, so freeing the same range finds the same blocks.
+    fn mark_range(&mut self, zone: Zone, mut start: usize, end: usize, ty: u32, owner: u16) {
+        while start < end {
+            let order = piece_order(start, end);
+            *self.frame_mut(zone, start) = Frame { next: NIL, prev: NIL, ty, order: order as u8, state: USED, owner, pages: 0 };
+            start += 1 << order;
+        }
+    }
+
+    /// Marks `start..end` as one allocation.
+    fn mark_allocation(&mut self, zone: Zone, start: usize, end: usize, ty: u32, owner: u16) {
+        self.mark_range(zone, start, end, ty, owner);
+        self.frame_mut(zone, start).pages = (end - start) as u32;
+    }
+
+    /// First page and end of the allocation holding `pfn`, found by walking back
+    /// over the blocks before it to the one that starts the allocation.
+    fn allocation_of(&self, zone: Zone, pfn: usize) -> Option<(usize, usize)> {
+        let mut at = pfn;
+        loop {
+            let (head, _) = self.block_of(zone, at)?;
+            let frame = self.frame(zone, head);
+            if frame.state != USED { return None; }
+            if frame.pages != 0 {
+                let end = head + frame.pages as usize;
+                return (pfn < end).then_some((head, end));
+            }
+            at = head.checked_sub(1).filter(|&prev| prev >= zone.base)?;
+        }
+    }
+
+    /// Head and order of the block holding `pfn`.
+    fn block_of(&self, zone: Zone, pfn: usize) -> Option<(usize, usize)> {
+        for order in 0..=MAX_ORDER {
+            let head = pfn & !((1 << order) - 1);
+            if head < zone.base { return None; }
+            let frame = self.frame(zone, head);
+            if frame.state != TAIL && frame.order as usize == order { return Some((head, order)); }
+        }
+        return None;
+    }
+
+    /// Takes `start..end` off the free lists if every page of it is free.
+    fn take_range(&mut self, zone: Zone, start: usize, end: usize, ty: u32, owner: u16) -> bool {
+        let mut pfn = start;
+        while pfn < end {
+            let Some((head, order)) = self.block_of(zone, pfn) else { return false; };
+            if self.frame(zone, head).state != FREE { return false; }
+            pfn = head + (1 << order);
+        }
+
+        let mut pfn = start;
+        while pfn < end {
+            let (head, order) = self.block_of(zone, pfn).unwrap();
+            let block_end = head + (1 << order);
+            self.list_remove(zone, head, order);
+            self.release_range(zone, head, start.max(head));
+            self.release_range(zone, end.min(block_end), block_end);
+            pfn = block_end;
+        }
+        self.mark_allocation(zone, start, end, ty, owner);
+        return true;
+    }
+
+    /// Allocates `pages` physically contiguous pages aligned to `align` pages (a power
+    /// of two), ending at or below the address `limit`, and returns the physical
+    /// address. Pages past `pages` in the block go straight back. Higher classes are
+    /// tried first, which keeps DMA memory for the devices that need it.
+    pub fn alloc(&mut self, pages: usize, align: usize, ty: u32, owner: u16, limit: usize) -> Option<usize> {
+        let pages = pages.max(1);
+        let want = order_for(pages.max(align));
+        let end = limit / PAGE_4KIB;
+        for class in (0..CLASSES).rev().filter(|&class| class_base(class) < limit) {
+            for order in want..=MAX_ORDER {
+                // Every block of a class below the limit fits; only the class it cuts needs a search
+                let mut idx = self.free_lists[class][order];
+                while idx != NIL {
+                    let zone = self.zone_of_index(idx as usize);
+                    let pfn = zone.base + idx as usize - zone.first;
+                    if pfn + pages <= end {
+                        self.list_remove(zone, pfn, order);
+                        self.mark_allocation(zone, pfn, pfn + pages, ty, owner);
+                        self.release_range(zone, pfn + pages, pfn + (1 << order));
+                        return Some(pfn * PAGE_4KIB);
+                    }
+                    idx = self.frame(zone, pfn).next;
+                }
+            }
+        }
+        return self.alloc_run(pages, align, ty, owner, end);
+    }
+
+    /// Too large for any single block: first fit over runs of adjacent free blocks,
+    /// ending at or below the page frame `end`.
+    fn alloc_run(&mut self, pages: usize, align: usize, ty: u32, owner: u16, end: usize) -> Option<usize> {
+        for idx in 0..self.zone_count {
+            let zone = self.zones()[idx];
+            let (mut pfn, mut run) = (zone.base, zone.base);
+            while pfn < zone.end() {
+                let frame = self.frame(zone, pfn);
+                let next = pfn + (1 << frame.order);
+                if frame.state != FREE { (pfn, run) = (next, next); continue; }
+
+                let start = run.next_multiple_of(align);
+                if start + pages > end { return None; }
+                if start + pages <= next && self.take_range(zone, start, start + pages, ty, owner) {
+                    return Some(start * PAGE_4KIB);
+                }
+                pfn = next;
+            }
+        }
+        return None;
+    }
+
+    /// Allocates the pages covering `addr..addr + size`, if all of them are free.
+    pub fn alloc_at(&mut self, addr: usize, size: usize, ty: u32, owner: u16) -> bool {
+        let (start, end) = (addr / PAGE_4KIB, (addr + size).div_ceil(PAGE_4KIB));
+        let Some(zone) = self.zone_of(start) else { return false; };
+        if !zone.contains(start, end) { return false; }
+        return self.take_range(zone, start, end, ty, owner);
+    }
+
+    /// Frees the pages covering `addr..addr + size`, which must lie inside one
+    /// allocation; its pages before and after the range stay allocated, as two
+    /// allocations of their own. Nothing is freed if the check fails.
+    pub fn free(&mut self, addr: usize, size: usize) -> Result<(), FreeError> {
+        let (start, end) = (addr / PAGE_4KIB, (addr + size).div_ceil(PAGE_4KIB));
+        let zone = self.zone_of(start)
+            .filter(|zone| zone.contains(start, end))
+            .ok_or(FreeError::Unmanaged(addr))?;
+        let (alloc_start, alloc_end) = self.allocation_of(zone, start).ok_or(FreeError::NotAllocated(start * PAGE_4KIB))?;
+        if end > alloc_end { return Err(FreeError::Overrun(alloc_end * PAGE_4KIB)); }
+
+        let mut pfn = start;
+        while pfn < end {
+            let (head, order) = self.block_of(zone, pfn).unwrap();
+            let block_end = head + (1 << order);
+            let Frame { ty, owner, .. } = self.frame(zone, head);
+            self.mark_range(zone, head, start.max(head), ty, owner);
+            self.mark_range(zone, end.min(block_end), block_end, ty, owner);
+            self.release_range(zone, start.max(head), end.min(block_end));
+            pfn = block_end;
+        }
+        // Both ends are block heads by now
+        if alloc_start < start { self.frame_mut(zone, alloc_start).pages = (start - alloc_start) as u32; }
+        if end < alloc_end { self.frame_mut(zone, end).pages = (alloc_end - end) as u32; }
+        return Ok(());
+    }
+}
+
+// Frames live apart from the memory they describe, so zones can sit at made-up
+// addresses around the DMA limits.
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    const MIB: usize = 1 << 20;
+    const GIB: usize = 1 << 30;
+
+    fn buddy(meta: &mut Vec<u64>, ranges: &[(usize, usize)]) -> Buddy {
+        let pages = ranges.iter().map(|&(start, end)| (end - start) / PAGE_4KIB).sum();
+        *meta = vec![0; Buddy::meta_size(ranges.len(), pages).div_ceil(8)];
+        let mut buddy = Buddy::empty();
+        unsafe { buddy.init(meta.as_mut_ptr().cast(), ranges.len(), pages); }
+        for &(start, end) in ranges { buddy.add_zone(start, end); }
+        return buddy;
+    }
+
+    #[test]
+    fn zones_are_cut_at_dma_limits() {
+        let mut meta = Vec::new();
+        let buddy = buddy(&mut meta, &[(12 * MIB, 20 * MIB), (4 * GIB - 8 * MIB, 4 * GIB + 8 * MIB)]);
+        let zones: Vec<_> = buddy.zones().iter()
+            .map(|zone| (zone.base * PAGE_4KIB, zone.end() * PAGE_4KIB, zone.class))
+            .collect();
+        assert_eq!(zones, vec![
+            (12 * MIB, 16 * MIB, 0), (16 * MIB, 20 * MIB, 1),
+            (4 * GIB - 8 * MIB, 4 * GIB, 1), (4 * GIB, 4 * GIB + 8 * MIB, 2)
+        ]);
+        assert_eq!(buddy.free_pages_below(16 * MIB), 4 * MIB / PAGE_4KIB);
+        assert_eq!(buddy.free_pages_below(4 * GIB), 16 * MIB / PAGE_4KIB);
+        assert_eq!(buddy.free_pages_below(usize::MAX), buddy.total_pages());
+    }
+
+    #[test]
+    fn alloc_honours_limits_and_spares_low_memory() {
+        let mut meta = Vec::new();
+        let mut buddy = buddy(&mut meta, &[(12 * MIB, 20 * MIB), (4 * GIB - 8 * MIB, 4 * GIB + 8 * MIB)]);
+
+        let normal = buddy.alloc(4, 1, 0, 0, usize::MAX).unwrap();
+        assert!(normal >= 4 * GIB);
+        let dma32 = buddy.alloc(4, 1, 0, 0, 4 * GIB).unwrap();
+        assert!(dma32 >= 16 * MIB && dma32 + 4 * PAGE_4KIB <= 4 * GIB);
+        let dma16 = buddy.alloc(4, 1, 0, 0, 16 * MIB).unwrap();
+        assert!(dma16 + 4 * PAGE_4KIB <= 16 * MIB);
+
+        // A limit inside a class takes only the blocks below it
+        let low = buddy.alloc(16, 16, 0, 0, 13 * MIB).unwrap();
+        assert!(low + 16 * PAGE_4KIB <= 13 * MIB);
+        assert!(buddy.alloc(512, 1, 0, 0, 13 * MIB).is_none());
+
+        // Once the ISA range is gone, only larger limits succeed
+        let mut taken = 0;
+        while buddy.alloc(1, 1, 0, 0, 16 * MIB).is_some() { taken += 1; }
+        assert_eq!(taken, 4 * MIB / PAGE_4KIB - 4 - 16);
+        assert_eq!(buddy.free_pages_below(16 * MIB), 0);
+        assert!(buddy.alloc(1, 1, 0, 0, 4 * GIB).is_some());
+    }
+}
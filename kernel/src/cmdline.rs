@@ -0,0 +1,105 @@
+use crate::{ramblock::owner, EMBER};
+use spin::Once;
+
+pub const CONSOLE_SERIAL: u8 = 1 << 0;
+pub const CONSOLE_FB: u8     = 1 << 1;
+
+pub const LOG_INFO: u8  = 6;
+pub const LOG_DEBUG: u8 = 7;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Params {
+    pub raw: &'static str,
+    pub console: u8,
+    pub loglevel: u8,
+    pub heap: Option<usize>,
+    pub quotas: [Option<usize>; owner::MAX as usize],
+    pub root: Option<&'static str>,
+    pub memmap: bool,
+    pub paging: bool
+}
+
+impl Params {
+    const fn default() -> Self {
+        Params {
+            raw: "",
+            console: CONSOLE_SERIAL | CONSOLE_FB,
+            loglevel: LOG_DEBUG,
+            heap: None,
+            quotas: [None; owner::MAX as usize],
+            root: None,
+            memmap: false,
+            paging: true
+        }
+    }
+
+    fn parse(raw: &'static str) -> Self {
+        let mut params = Self::default();
+        params.raw = raw;
+
+        for word in raw.split_ascii_whitespace() {
+            let (key, val) = word.split_once('=').unwrap_or((word, ""));
+            match key {
+                "console"  => params.console = parse_console(val).unwrap_or(params.console),
+                "loglevel" => params.loglevel = val.parse::<u8>().map_or(params.loglevel, |l| l.min(LOG_DEBUG)),
+                "heap"     => params.heap = parse_size(val).or(params.heap),
+                "quota"    => parse_quotas(val, &mut params.quotas),
+                "root"     => params.root = Some(val).filter(|val| !val.is_empty()),
+                "memmap"   => params.memmap = true,
+                "nopaging" => params.paging = false,
+                _          => {}
+            }
+        }
+        return params;
+    }
+}
+
+fn parse_console(val: &str) -> Option<u8> {
+    let mut console = 0;
+    for name in val.split(',') {
+        console |= match name {
+            "serial" => CONSOLE_SERIAL,
+            "fb"     => CONSOLE_FB,
+            "none"   => 0,
+            _        => return None
+        };
+    }
+    return Some(console);
+}
+
+/// Parses `nvme:4M,page_tables:1M`; entries naming no owner or size are skipped.
+fn parse_quotas(val: &str, quotas: &mut [Option<usize>]) {
+    for entry in val.split(',') {
+        let Some((name, size)) = entry.split_once(':') else { continue; };
+        let (Some(owner), Some(size)) = (owner::by_name(name), parse_size(size)) else { continue; };
+        quotas[owner as usize] = Some(size);
+    }
+}
+
+/// Parses `1048576`, `0x100000`, `1024K`, `1M` or `1G`.
+fn parse_size(val: &str) -> Option<usize> {
+    let (num, shift) = match val.as_bytes().last()? {
+        b'k' | b'K' => (&val[..val.len() - 1], 10),
+        b'm' | b'M' => (&val[..val.len() - 1], 20),
+        b'g' | b'G' => (&val[..val.len() - 1], 30),
+        _ => (val, 0)
+    };
+    let num = match num.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+        None => num.parse::<usize>().ok()?
+    };
+    return num.checked_mul(1 << shift);
+}
+
+static PARAMS: Once<Params> = Once::new();
+static DEFAULT: Params = Params::default();
+
+pub fn init() {
+    PARAMS.call_once(|| Params::parse(EMBER.lock().cmdline()));
+}
+
+pub fn params() -> &'static Params {
+    return PARAMS.get().unwrap_or(&DEFAULT);
+}
+
+pub fn loglevel(level: u8) -> bool { params().loglevel >= level }
@@ -1,6 +1,6 @@
 mod block; mod nvme;
 
-use crate::{printk, printlnk, EMBER};
+use crate::{cmdline::{self, LOG_DEBUG, LOG_INFO}, printk, printlnk, vmm, EMBER};
 use acpi::{mcfg::Mcfg, AcpiHandler, AcpiTables, PhysicalMapping};
 use alloc::{string::String, vec::Vec};
 use fdt::Fdt;
@@ -137,6 +137,10 @@ impl PciDevice {
 
 fn scan_pcie_devices(base: u64, start_bus: u8, end_bus: u8) -> Vec<PciDevice> {
     let mut devices = Vec::new();
+    // ECAM gives each bus 1 MiB from `base`, counted from bus 0
+    let first = (start_bus as u64) << 20;
+    let size = (end_bus as usize - start_bus as usize + 1) << 20;
+    let base = (vmm::map_mmio((base + first) as usize, size) as u64).wrapping_sub(first);
 
     for bus in start_bus..=end_bus { for device in 0..32 { for function in 0..8 {
         if let Some(mut dev) = PciDevice::read(base, bus, device, function) {
@@ -209,26 +213,47 @@ pub fn init_device_tree() {
     }
 }
 
+pub fn init_smbios() {
+    let smbios_ptr = EMBER.lock().smbios_ptr;
+    if smbios_ptr == 0 { return; }
+
+    let entry = unsafe { core::slice::from_raw_parts(smbios_ptr as *const u8, 9) };
+    let (major, minor) = match entry {
+        [b'_', b'S', b'M', b'3', b'_', _, _, major, minor] => (*major, *minor),
+        [b'_', b'S', b'M', b'_', _, _, major, minor, _] => (*major, *minor),
+        _ => { printlnk!("SMBIOS entry point at {:#x} is invalid", smbios_ptr); return; }
+    };
+    printlnk!("SMBIOS {}.{} at {:#x}", major, minor, smbios_ptr);
+}
+
 pub fn init_device() {
     init_acpi();
     init_device_tree();
+    init_smbios();
     scan_pci();
 
-    for dev in PCI_DEVICES.lock().iter() {
-        printk!(
-            "/bus{}/dev{}/fn{} | {:04x}:{:04x} Class {:02x}.{:02x} IF {:02x}",
-            dev.bus(), dev.device(), dev.function(),
-            dev.vendor_id(), dev.device_id(),
-            dev.class(), dev.subclass(), dev.prog_if()
-        );
-
-        if dev.is_nvme()    { printk!(" --> NVMe Controller"); }
-        if dev.is_usb()     { printk!(" --> USB Controller"); }
-        if dev.is_display() { printk!(" --> Display Controller"); }
-        if dev.is_bridge()  { printk!(" (PCI Bridge)"); }
-        printlnk!();
+    if cmdline::loglevel(LOG_INFO) {
+        for dev in PCI_DEVICES.lock().iter() {
+            printk!(
+                "/bus{}/dev{}/fn{} | {:04x}:{:04x} Class {:02x}.{:02x} IF {:02x}",
+                dev.bus(), dev.device(), dev.function(),
+                dev.vendor_id(), dev.device_id(),
+                dev.class(), dev.subclass(), dev.prog_if()
+            );
+
+            if dev.is_nvme()    { printk!(" --> NVMe Controller"); }
+            if dev.is_usb()     { printk!(" --> USB Controller"); }
+            if dev.is_display() { printk!(" --> Display Controller"); }
+            if dev.is_bridge()  { printk!(" (PCI Bridge)"); }
+            printlnk!();
+        }
     }
 
     nvme::init_nvme();
-    nvme::test_nvme();
+    if cmdline::loglevel(LOG_DEBUG) { nvme::test_nvme(); }
+
+    if let Some(root) = cmdline::params().root {
+        if nvme::has_namespace(root) { printlnk!("Root device: {}", root); }
+        else { printlnk!("Root device {} not found", root); }
+    }
 }
\ No newline at end of file
@@ -1,23 +1,32 @@
-use crate::{printlnk, ram::PageAligned, ramblock::{self, AllocParams}};
+use crate::{printlnk, ram::PageAligned, ramblock::{self, owner, AllocParams, DmaZone}, vmm};
 use super::PCI_DEVICES;
 use alloc::vec::Vec;
 use nvme::{Allocator, Device};
 use spin::Mutex;
 
-pub struct NVMeAlloc;
+/// Queues and buffers come from `zone`, as the controller must reach them by DMA.
+pub struct NVMeAlloc {
+    zone: DmaZone
+}
 
 impl Allocator for NVMeAlloc {
     unsafe fn allocate(&self, size: usize) -> usize {
-        return ramblock::alloc(AllocParams::new(size)).unwrap().addr();
+        let args = AllocParams::new(size).owner(owner::NVME).zone(self.zone);
+        return ramblock::alloc(args).unwrap().addr();
     }
 
     unsafe fn deallocate(&self, addr: usize, size: usize) {
-        unsafe { ramblock::free_raw(addr as *mut u8, size); }
+        if let Err(err) = unsafe { ramblock::free_raw(addr as *mut u8, size) } {
+            panic!("NVMe freed memory it does not own: {}", err);
+        }
     }
 
     fn translate(&self, addr: usize) -> usize { addr }
 }
 
+// Registers and doorbells; BAR0 is at least this large
+const NVME_MMIO_SIZE: usize = 0x4000;
+
 static NVME_DEV: Mutex<Vec<Device<NVMeAlloc>>> = Mutex::new(Vec::new());
 
 pub fn init_nvme() {
@@ -28,11 +37,22 @@ pub fn init_nvme() {
             ((pci_dev.bar(1).unwrap() as usize) << 32) | (base & !0b111)
         } else { base & !0b11 };
 
-        let nvme_device = Device::init(mmio_addr, NVMeAlloc).unwrap();
+        let mmio_addr = vmm::map_mmio(mmio_addr, NVME_MMIO_SIZE);
+        // NVMe controllers address all 64 bits
+        let nvme_device = Device::init(mmio_addr, NVMeAlloc { zone: DmaZone::Normal }).unwrap();
         nvme_dev.push(nvme_device);
     }
 }
 
+/// Looks up a namespace by its `nvme<controller>n<nsid>` name.
+pub fn has_namespace(name: &str) -> bool {
+    let Some((ctrl, nsid)) = name.strip_prefix("nvme").and_then(|rest| rest.split_once('n')) else {
+        return false;
+    };
+    let (Ok(ctrl), Ok(nsid)) = (ctrl.parse::<usize>(), nsid.parse::<u32>()) else { return false; };
+    return NVME_DEV.lock().get(ctrl).is_some_and(|dev| dev.list_namespaces().contains(&nsid));
+}
+
 pub fn test_nvme() {
     let nvme_dev_ls = NVME_DEV.lock();
 
@@ -52,4 +72,18 @@ pub fn test_nvme() {
             Err(e) => printlnk!("Read failed from namespace {}: {}", nsi, e),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, target_os = "none"))]
+#[test_case]
+fn read_every_namespace() {
+    let nvme_dev_ls = NVME_DEV.lock();
+    let mut buffer = PageAligned::new(4096);
+    for nvme_dev in nvme_dev_ls.iter() {
+        for nsi in nvme_dev.list_namespaces() {
+            if let Err(e) = nvme_dev.get_ns(nsi).unwrap().read(0, &mut buffer) {
+                panic!("read failed from namespace {}: {}", nsi, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,195 @@
+// UEFI runtime services, called through the identity map the firmware left us.
+// SetVirtualAddressMap is never called, so physical addresses stay valid.
+
+use crate::EMBER;
+use core::{ffi::c_void, fmt};
+use spin::Mutex;
+
+const SYSTEM_TABLE_SIGNATURE: u64     = 0x5453_5953_2049_4249; // "IBI SYST"
+const RUNTIME_SERVICES_SIGNATURE: u64 = 0x5652_4553_544e_5552; // "RUNTSERV"
+const NAME_MAX: usize = 128;
+
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32
+}
+
+#[repr(C)]
+struct SystemTable {
+    hdr: TableHeader,
+    firmware_vendor: *const u16,
+    firmware_revision: u32,
+    console_in_handle: *const c_void,
+    con_in: *const c_void,
+    console_out_handle: *const c_void,
+    con_out: *const c_void,
+    standard_error_handle: *const c_void,
+    std_err: *const c_void,
+    runtime_services: *const RuntimeServices
+    // Boot services and the configuration table follow; we have no use for them
+}
+
+#[repr(C)]
+struct RuntimeServices {
+    hdr: TableHeader,
+    get_time: unsafe extern "efiapi" fn(*mut Time, *mut c_void) -> usize,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: usize,
+    convert_pointer: usize,
+    get_variable: unsafe extern "efiapi" fn(*const u16, *const Guid, *mut u32, *mut usize, *mut u8) -> usize,
+    get_next_variable_name: usize,
+    set_variable: unsafe extern "efiapi" fn(*const u16, *const Guid, u32, usize, *const u8) -> usize,
+    get_next_high_monotonic_count: usize,
+    reset_system: unsafe extern "efiapi" fn(u32, usize, usize, *const u8) -> !
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pad1: u8,
+    pub nanosecond: u32,
+    pub time_zone: i16, // Minutes from UTC, 0x7ff if unspecified
+    pub daylight: u8,
+    pad2: u8
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Guid(pub u32, pub u16, pub u16, pub [u8; 8]);
+
+/// Vendor GUID of the variables the specification defines, like `BootOrder`.
+#[allow(dead_code)] // For callers of the variable wrappers
+pub const GLOBAL_VARIABLE: Guid = Guid(0x8be4df61, 0x93ca, 0x11d2, [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c]);
+
+#[allow(dead_code)] // For callers of the variable wrappers
+pub mod attr {
+    pub const NON_VOLATILE: u32       = 0x01;
+    pub const BOOTSERVICE_ACCESS: u32 = 0x02;
+    pub const RUNTIME_ACCESS: u32     = 0x04;
+}
+
+#[allow(dead_code)] // For callers of reset_system
+#[repr(u32)]
+#[derive(Clone, Copy, Debug)]
+pub enum ResetType {
+    Cold     = 0,
+    Warm     = 1,
+    Shutdown = 2
+}
+
+const ERROR_BIT: usize = 1 << (usize::BITS - 1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EfiError {
+    Unavailable,
+    NameTooLong,
+    BufferTooSmall(usize),
+    Status(usize)
+}
+
+impl fmt::Display for EfiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unavailable => write!(f, "runtime services are unavailable"),
+            Self::NameTooLong => write!(f, "variable name is too long"),
+            Self::BufferTooSmall(size) => write!(f, "variable needs a {} byte buffer", size),
+            Self::Status(status) => write!(f, "firmware returned status {:#x}", status & !ERROR_BIT)
+        }
+    }
+}
+
+const BUFFER_TOO_SMALL: usize = ERROR_BIT | 5;
+
+fn check(status: usize) -> Result<(), EfiError> {
+    if status & ERROR_BIT == 0 { return Ok(()); }
+    return Err(EfiError::Status(status));
+}
+
+struct Runtime(*const RuntimeServices);
+unsafe impl Send for Runtime {}
+
+// Runtime services are not reentrant, so every call goes through this lock
+static RUNTIME: Mutex<Option<Runtime>> = Mutex::new(None);
+
+pub fn init() {
+    let table = EMBER.lock().efi_system_table as *const SystemTable;
+    if table.is_null() { return; }
+    let table = unsafe { &*table };
+    if table.hdr.signature != SYSTEM_TABLE_SIGNATURE || table.runtime_services.is_null() { return; }
+    if unsafe { (*table.runtime_services).hdr.signature } != RUNTIME_SERVICES_SIGNATURE { return; }
+    *RUNTIME.lock() = Some(Runtime(table.runtime_services));
+}
+
+fn with_runtime<T>(f: impl FnOnce(&RuntimeServices) -> Result<T, EfiError>) -> Result<T, EfiError> {
+    let runtime = RUNTIME.lock();
+    let runtime = runtime.as_ref().ok_or(EfiError::Unavailable)?;
+    return f(unsafe { &*runtime.0 });
+}
+
+/// Variable names are UCS-2 on the firmware side; ASCII is all we need.
+fn ucs2_name(name: &str, buf: &mut [u16; NAME_MAX]) -> Result<*const u16, EfiError> {
+    if name.len() >= NAME_MAX || !name.is_ascii() { return Err(EfiError::NameTooLong); }
+    for (dst, byte) in buf.iter_mut().zip(name.bytes()) { *dst = byte as u16; }
+    buf[name.len()] = 0;
+    return Ok(buf.as_ptr());
+}
+
+pub fn get_time() -> Result<Time, EfiError> {
+    return with_runtime(|rt| {
+        let mut time = Time::default();
+        check(unsafe { (rt.get_time)(&mut time, core::ptr::null_mut()) })?;
+        return Ok(time);
+    });
+}
+
+/// Returns the variable's size and attributes; `data` receives its contents.
+#[allow(dead_code)] // No callers until userland asks for it
+pub fn get_variable(name: &str, vendor: &Guid, data: &mut [u8]) -> Result<(usize, u32), EfiError> {
+    let mut name_buf = [0u16; NAME_MAX];
+    let name = ucs2_name(name, &mut name_buf)?;
+    return with_runtime(|rt| {
+        let (mut attrs, mut size) = (0, data.len());
+        let status = unsafe { (rt.get_variable)(name, vendor, &mut attrs, &mut size, data.as_mut_ptr()) };
+        if status == BUFFER_TOO_SMALL { return Err(EfiError::BufferTooSmall(size)); }
+        check(status)?;
+        return Ok((size, attrs));
+    });
+}
+
+/// Writes a variable; empty `data` deletes it.
+#[allow(dead_code)] // No callers until userland asks for it
+pub fn set_variable(name: &str, vendor: &Guid, attrs: u32, data: &[u8]) -> Result<(), EfiError> {
+    let mut name_buf = [0u16; NAME_MAX];
+    let name = ucs2_name(name, &mut name_buf)?;
+    return with_runtime(|rt| check(unsafe { (rt.set_variable)(name, vendor, attrs, data.len(), data.as_ptr()) }));
+}
+
+/// Resets or powers off the machine; falls back to halting if firmware cannot.
+#[allow(dead_code)] // No callers until userland asks for it
+pub fn reset_system(kind: ResetType) -> ! {
+    if let Some(runtime) = RUNTIME.lock().as_ref() {
+        unsafe { ((*runtime.0).reset_system)(kind as u32, 0, 0, core::ptr::null()); }
+    }
+    loop { crate::arch::halt(); }
+}
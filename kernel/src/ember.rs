@@ -1,21 +1,27 @@
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-pub struct RAMDescriptor {
-    pub ty: u32,
-    pub reserved: u32,
-    pub phys_start: u64,
-    pub virt_start: u64,
-    pub page_count: u64,
-    pub attr: u64,
-    pub padding: u64
-}
+pub use unix_v11_ember::{segflag, Framebuffer, Integrity, RAMDescriptor, Segment};
+#[cfg(not(all(test, not(target_os = "none"))))]
+pub use unix_v11_ember::{integrity, EmberError, EmberView};
+use unix_v11_ember::memtype;
+#[cfg(not(all(test, not(target_os = "none"))))]
+use unix_v11_ember::{tag, Acpi, Dtb, EfiSystem, Initrd, Kernel, RAMLayout, Smbios, Stack};
 
-#[repr(C)]
 pub struct Ember {
+    handoff_ptr: *const u8,
+    handoff_size: usize,
     layout_ptr: *const RAMDescriptor,
     layout_len: usize,
+    cmdline_ptr: *const u8,
+    cmdline_len: usize,
+    segments_ptr: *const Segment,
+    segments_len: usize,
     pub acpi_ptr: usize,
     pub dtb_ptr: usize,
+    pub smbios_ptr: usize,
+    pub efi_system_table: usize,
+    pub framebuffer: Option<Framebuffer>,
+    pub integrity: Option<Integrity>,
+    pub initrd_ptr: usize,
+    pub initrd_size: usize,
     pub stack_base: usize,
     pub kernel_base: usize,
     pub kernel_size: usize
@@ -45,10 +51,42 @@ pub mod ramtype {
 
     // ...
 
+    pub const DEVICE_TREE          : u32 = super::memtype::DEVICE_TREE;
+    pub const INITRD               : u32 = super::memtype::INITRD;
     pub const KERNEL_DATA          : u32 = 0x44415441;
+    pub const EMBER                : u32 = 0x454d4252;
     pub const EFI_RAM_LAYOUT       : u32 = 0x524c594f;
     pub const PAGE_TABLE           : u32 = 0x766d6170;
     pub const KERNEL               : u32 = 0xffffffff;
+
+    pub fn name(ty: u32) -> &'static str {
+        match ty {
+            RESERVED              => "Reserved",
+            LOADER_CODE           => "Loader code",
+            LOADER_DATA           => "Loader data",
+            BOOT_SERVICES_CODE    => "Boot services code",
+            BOOT_SERVICES_DATA    => "Boot services data",
+            RUNTIME_SERVICES_CODE => "Runtime services code",
+            RUNTIME_SERVICES_DATA => "Runtime services data",
+            CONVENTIONAL          => "Conventional",
+            UNUSABLE              => "Unusable",
+            ACPI_RECLAIM          => "ACPI reclaimable",
+            ACPI_NON_VOLATILE     => "ACPI NVS",
+            MMIO                  => "MMIO",
+            MMIO_PORT_SPACE       => "MMIO port space",
+            PAL_CODE              => "PAL code",
+            PERSISTENT_MEMORY     => "Persistent memory",
+            UNACCEPTED            => "Unaccepted",
+            DEVICE_TREE           => "Device tree",
+            INITRD                => "Initrd",
+            KERNEL_DATA           => "Kernel data",
+            EMBER                 => "Ember handoff",
+            EFI_RAM_LAYOUT        => "EFI RAM layout",
+            PAGE_TABLE            => "Page table",
+            KERNEL                => "Kernel image",
+            _                     => "Unknown"
+        }
+    }
 }
 
 const RECLAMABLE: &[u32] = &[
@@ -63,19 +101,63 @@ unsafe impl Send for Ember {}
 impl Ember {
     pub const fn empty() -> Self {
         Ember {
+            handoff_ptr: core::ptr::null(),
+            handoff_size: 0,
             layout_ptr: core::ptr::null(),
             layout_len: 0,
+            cmdline_ptr: core::ptr::null(),
+            cmdline_len: 0,
+            segments_ptr: core::ptr::null(),
+            segments_len: 0,
             acpi_ptr: 0,
             dtb_ptr: 0,
+            smbios_ptr: 0,
+            efi_system_table: 0,
+            framebuffer: None,
+            integrity: None,
+            initrd_ptr: 0,
+            initrd_size: 0,
             stack_base: 0,
             kernel_base: 0,
             kernel_size: 0
         }
     }
 
-    pub fn init(&mut self, param: Self) {
-        *self = param;
+    #[cfg(not(all(test, not(target_os = "none"))))] // Host tests have no handoff
+    pub fn init(&mut self, handoff: *const u8) -> Result<(), EmberError> {
+        let view = unsafe { EmberView::from_ptr(handoff)? };
+        let layout = view.require::<RAMLayout>()?;
+        if layout.desc_size != size_of::<RAMDescriptor>() as u64 {
+            return Err(EmberError::DescSize(layout.desc_size));
+        }
+        let kernel = view.require::<Kernel>()?;
+        let stack = view.require::<Stack>()?;
+
+        self.handoff_ptr = handoff;
+        self.handoff_size = view.as_bytes().len();
+        self.layout_ptr = layout.ptr as *const RAMDescriptor;
+        self.layout_len = layout.len as usize;
+        let cmdline = view.find_raw(tag::CMDLINE).unwrap_or(&[]);
+        (self.cmdline_ptr, self.cmdline_len) = (cmdline.as_ptr(), cmdline.len());
+        let segments = view.find_slice::<Segment>()?;
+        (self.segments_ptr, self.segments_len) = (segments.as_ptr(), segments.len());
+        self.acpi_ptr = view.find::<Acpi>()?.map_or(0, |acpi| acpi.rsdp as usize);
+        self.dtb_ptr = view.find::<Dtb>()?.map_or(0, |dtb| dtb.ptr as usize);
+        self.smbios_ptr = view.find::<Smbios>()?.map_or(0, |smbios| smbios.ptr as usize);
+        self.efi_system_table = view.find::<EfiSystem>()?.map_or(0, |efi| efi.table as usize);
+        self.framebuffer = view.find::<Framebuffer>()?;
+        self.integrity = view.find::<Integrity>()?;
+        if let Some(initrd) = view.find::<Initrd>()? {
+            (self.initrd_ptr, self.initrd_size) = (initrd.ptr as usize, initrd.size as usize);
+        }
+        self.stack_base = stack.base as usize;
+        self.kernel_base = kernel.base as usize;
+        self.kernel_size = kernel.size as usize;
 
+        let handoff_start = self.handoff_ptr as u64;
+        let handoff_end = handoff_start + self.handoff_size as u64;
+        let initrd_start = self.initrd_ptr as u64;
+        let initrd_end = initrd_start + self.initrd_size as u64;
         let kernel_start = self.kernel_base as u64;
         let kernel_end = (self.kernel_base + self.kernel_size) as u64;
         let layout_start = self.layout_ptr as u64;
@@ -89,9 +171,12 @@ impl Ember {
             if kernel_start < desc_end && kernel_end > desc_start { desc.ty = ramtype::KERNEL; }
             if id_map_ptr >= desc_start && id_map_ptr < desc_end  { desc.ty = ramtype::PAGE_TABLE; }
             if layout_start < desc_end && layout_end > desc_start { desc.ty = ramtype::EFI_RAM_LAYOUT; }
+            if handoff_start < desc_end && handoff_end > desc_start { desc.ty = ramtype::EMBER; }
+            if initrd_start < desc_end && initrd_end > desc_start   { desc.ty = ramtype::INITRD; }
             #[cfg(target_arch = "x86_64")] if desc.phys_start < 0x100000 { desc.ty = ramtype::RESERVED; }
             if RECLAMABLE.contains(&desc.ty) { desc.ty = ramtype::CONVENTIONAL; }
         });
+        return Ok(());
     }
 
     pub fn efi_ram_layout<'a>(&self) -> &'a [RAMDescriptor] {
@@ -102,6 +187,31 @@ impl Ember {
         return unsafe { core::slice::from_raw_parts_mut(self.layout_ptr as *mut RAMDescriptor, self.layout_len) };
     }
 
+    /// Loaded kernel segments; empty if the loader did not report them.
+    pub fn kernel_segments<'a>(&self) -> &'a [Segment] {
+        if self.segments_ptr.is_null() { return &[]; }
+        return unsafe { core::slice::from_raw_parts(self.segments_ptr, self.segments_len) };
+    }
+
+    /// ELF permissions of a kernel page, merged over every segment touching it.
+    /// Pages of the image outside any segment are treated as data; without any
+    /// segments at all, nothing is known and every page stays executable.
+    pub fn kernel_page_flags(&self, page: u64) -> u64 {
+        if self.segments_len == 0 { return segflag::R | segflag::W | segflag::X; }
+        let page_end = page + PAGE_4KIB as u64;
+        let flags = self.kernel_segments().iter()
+            .filter(|seg| seg.addr < page_end && seg.addr + seg.size > page)
+            .fold(0, |flags, seg| flags | seg.flags);
+        if flags == 0 { return segflag::R | segflag::W; }
+        return flags;
+    }
+
+    pub fn cmdline<'a>(&self) -> &'a str {
+        if self.cmdline_ptr.is_null() { return ""; }
+        let bytes = unsafe { core::slice::from_raw_parts(self.cmdline_ptr, self.cmdline_len) };
+        return core::str::from_utf8(bytes).unwrap_or("");
+    }
+
     pub fn set_new_stack_base(&mut self, stack_base: usize) {
         self.stack_base = stack_base;
     }
@@ -0,0 +1,176 @@
+mod font;
+
+use crate::EMBER;
+use spin::Mutex;
+
+// VGA palette in ANSI order; colour n + 8 is the bright variant of n
+const PALETTE: [u32; 16] = [
+    0x000000, 0xaa0000, 0x00aa00, 0xaa5500, 0x0000aa, 0xaa00aa, 0x00aaaa, 0xaaaaaa,
+    0x555555, 0xff5555, 0x55ff55, 0xffff55, 0x5555ff, 0xff55ff, 0x55ffff, 0xffffff
+];
+const DEFAULT_FG: u8 = 7;
+const DEFAULT_BG: u8 = 0;
+const TAB_WIDTH: usize = 8;
+const MAX_PARAMS: usize = 4;
+
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    Esc,
+    Csi { params: [u16; MAX_PARAMS], count: usize }
+}
+
+pub struct FbConsole {
+    base: *mut u32,
+    stride: usize,
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    fg: u8,
+    bg: u8,
+    colours: [u32; 16],
+    escape: Escape
+}
+
+unsafe impl Send for FbConsole {}
+
+/// Scales an 8-bit channel into the bits selected by `mask`.
+fn channel(val: u32, mask: u32) -> u32 {
+    if mask == 0 { return 0; }
+    let bits = mask.count_ones().min(8);
+    return ((val >> (8 - bits)) << mask.trailing_zeros()) & mask;
+}
+
+impl FbConsole {
+    fn new(fb: &crate::ember::Framebuffer) -> Option<Self> {
+        let (width, height, stride) = (fb.width as usize, fb.height as usize, fb.stride as usize);
+        if stride < width || stride * height * 4 > fb.size as usize { return None; }
+        let (cols, rows) = (width / font::WIDTH, height / font::HEIGHT);
+        if cols == 0 || rows == 0 { return None; }
+
+        let colours = PALETTE.map(|rgb| {
+            channel(rgb >> 16 & 0xff, fb.red_mask)
+                | channel(rgb >> 8 & 0xff, fb.green_mask)
+                | channel(rgb & 0xff, fb.blue_mask)
+        });
+        return Some(Self {
+            base: fb.base as *mut u32, stride, cols, rows,
+            col: 0, row: 0,
+            fg: DEFAULT_FG, bg: DEFAULT_BG,
+            colours, escape: Escape::None
+        });
+    }
+
+    fn fill_rows(&mut self, first: usize, count: usize, colour: u32) {
+        let start = first * font::HEIGHT * self.stride;
+        let len = count * font::HEIGHT * self.stride;
+        for i in start..start + len { unsafe { self.base.add(i).write_volatile(colour); } }
+    }
+
+    fn draw_glyph(&mut self, byte: u8) {
+        let index = if (font::FIRST..=font::LAST).contains(&byte) { byte } else { b'?' };
+        let glyph = &font::GLYPHS[(index - font::FIRST) as usize];
+        let (fg, bg) = (self.colours[self.fg as usize], self.colours[self.bg as usize]);
+
+        let origin = self.row * font::HEIGHT * self.stride + self.col * font::WIDTH;
+        for (y, &bits) in glyph.iter().enumerate() {
+            let line = unsafe { self.base.add(origin + y * self.stride) };
+            for x in 0..font::WIDTH {
+                let colour = if bits & (0x80 >> x) != 0 { fg } else { bg };
+                unsafe { line.add(x).write_volatile(colour); }
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows { self.row += 1; return; }
+
+        // Scroll everything up by one text row and blank the last one
+        let row_len = font::HEIGHT * self.stride;
+        unsafe { core::ptr::copy(self.base.add(row_len), self.base, (self.rows - 1) * row_len); }
+        self.fill_rows(self.rows - 1, 1, self.colours[self.bg as usize]);
+    }
+
+    fn put(&mut self, byte: u8) {
+        if self.col == self.cols { self.newline(); }
+        self.draw_glyph(byte);
+        self.col += 1;
+    }
+
+    /// Applies an SGR sequence (`ESC [ ... m`); only colour attributes are honoured.
+    fn select_graphic(&mut self, params: &[u16]) {
+        if params.is_empty() { (self.fg, self.bg) = (DEFAULT_FG, DEFAULT_BG); }
+        for &param in params {
+            match param {
+                0         => (self.fg, self.bg) = (DEFAULT_FG, DEFAULT_BG),
+                1         => self.fg |= 8,
+                22        => self.fg &= 7,
+                30..=37   => self.fg = (self.fg & 8) | (param - 30) as u8,
+                39        => self.fg = (self.fg & 8) | DEFAULT_FG,
+                40..=47   => self.bg = (param - 40) as u8,
+                49        => self.bg = DEFAULT_BG,
+                90..=97   => self.fg = (param - 90) as u8 + 8,
+                100..=107 => self.bg = (param - 100) as u8 + 8,
+                _         => {}
+            }
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        self.escape = match (self.escape, byte) {
+            (Escape::None, 0x1b) => Escape::Esc,
+            (Escape::None, _) => {
+                match byte {
+                    b'\n' => self.newline(),
+                    b'\r' => self.col = 0,
+                    b'\t' => self.col = ((self.col / TAB_WIDTH + 1) * TAB_WIDTH).min(self.cols),
+                    0x08  => self.col = self.col.saturating_sub(1),
+                    _     => self.put(byte)
+                }
+                Escape::None
+            },
+            (Escape::Esc, b'[') => Escape::Csi { params: [0; MAX_PARAMS], count: 0 },
+            (Escape::Esc, _) => Escape::None,
+            (Escape::Csi { mut params, count }, b'0'..=b'9') => {
+                let slot = count.max(1) - 1;
+                params[slot] = params[slot].saturating_mul(10).saturating_add((byte - b'0') as u16);
+                Escape::Csi { params, count: count.max(1) }
+            },
+            (Escape::Csi { params, count }, b';') => {
+                Escape::Csi { params, count: (count.max(1) + 1).min(MAX_PARAMS) }
+            },
+            (Escape::Csi { params, count }, b'm') => {
+                self.select_graphic(&params[..count]);
+                Escape::None
+            },
+            // Any other final byte ends a sequence we do not implement
+            (Escape::Csi { .. }, 0x40..=0x7e) => Escape::None,
+            (escape, _) => escape
+        };
+    }
+}
+
+static FBCON: Mutex<Option<FbConsole>> = Mutex::new(None);
+
+pub fn init() {
+    let Some(fb) = EMBER.lock().framebuffer else { return; };
+    let Some(mut console) = FbConsole::new(&fb) else { return; };
+    console.fill_rows(0, console.rows, console.colours[DEFAULT_BG as usize]);
+    *FBCON.lock() = Some(console);
+}
+
+pub struct FbWriter;
+
+impl core::fmt::Write for FbWriter {
+    // A panic or fault in the middle of a print finds the console held; the
+    // message still reaches the serial console, if that is on
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let Some(mut console) = FBCON.try_lock() else { return Ok(()); };
+        if let Some(console) = console.as_mut() {
+            for byte in s.bytes() { console.write_byte(byte); }
+        }
+        Ok(())
+    }
+}
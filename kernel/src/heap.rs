@@ -0,0 +1,193 @@
+// Kernel heap. Small requests go to the kmalloc slab caches; the rest to first-fit
+// arenas over memory from ramblock. The first arena is set up at boot; when no
+// arena has room, the heap grows the last one in place if the pages above it are
+// free, or else takes a new arena. Growing in place is an allocation of its own, so
+// each arena keeps its extents. Arenas that empty out go back to ramblock extent by
+// extent, all but the first.
+use crate::{
+    ember::ramtype, printlnk,
+    ram::{align_up, PAGE_4KIB},
+    ramblock::{self, owner, AllocParams},
+    slab
+};
+use core::{alloc::{GlobalAlloc, Layout}, ptr::{null_mut, NonNull}};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+
+const MAX_ARENAS: usize = 32;
+const MAX_EXTENTS: usize = 8;
+const GROW_MIN: usize = 0x100000;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub arenas: usize,
+    pub grown: usize,    // Times the heap took more memory
+    pub returned: usize, // Arenas given back to ramblock
+    pub failed: usize    // Requests that could not be met
+}
+
+struct Arena {
+    heap: Heap,
+    extents: [(usize, usize); MAX_EXTENTS], // Each allocation from ramblock, as address and size
+    extent_count: usize
+}
+
+impl Arena {
+    const fn empty() -> Self {
+        return Arena { heap: Heap::empty(), extents: [(0, 0); MAX_EXTENTS], extent_count: 0 };
+    }
+
+    fn push_extent(&mut self, addr: usize, size: usize) {
+        self.extents[self.extent_count] = (addr, size);
+        self.extent_count += 1;
+    }
+}
+
+struct Arenas {
+    arenas: [Arena; MAX_ARENAS],
+    count: usize,
+    grown: usize,
+    returned: usize,
+    failed: usize
+}
+
+pub struct KernelHeap(Mutex<Arenas>);
+
+#[cfg_attr(not(all(test, not(target_os = "none"))), global_allocator)]
+static HEAP: KernelHeap = KernelHeap(Mutex::new(Arenas {
+    arenas: [const { Arena::empty() }; MAX_ARENAS],
+    count: 0,
+    grown: 0,
+    returned: 0,
+    failed: 0
+}));
+
+impl Arenas {
+    fn arenas(&mut self) -> &mut [Arena] {
+        return &mut self.arenas[..self.count];
+    }
+
+    fn try_alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        return self.arenas().iter_mut().find_map(|arena| arena.heap.allocate_first_fit(layout).ok());
+    }
+
+    /// Takes at least enough memory from ramblock for `layout`.
+    fn grow(&mut self, layout: Layout) -> bool {
+        let size = align_up((layout.size() + layout.align()).max(GROW_MIN), PAGE_4KIB);
+        let args = AllocParams::new(size).as_type(ramtype::KERNEL_DATA).owner(owner::HEAP);
+
+        let last = self.arenas().last_mut()
+            .filter(|last| last.heap.top() as usize % PAGE_4KIB == 0 && last.extent_count < MAX_EXTENTS);
+        if let Some(last) = last {
+            if let Some(region) = ramblock::alloc(args.at(last.heap.top())) {
+                unsafe { last.heap.extend(size); }
+                last.push_extent(region.addr(), size);
+                self.grown += 1;
+                return true;
+            }
+        }
+        if self.count == MAX_ARENAS { return false; }
+        let Some(region) = ramblock::alloc(args) else { return false; };
+        let arena = &mut self.arenas[self.count];
+        unsafe { arena.heap.init(region.ptr(), size); }
+        arena.push_extent(region.addr(), size);
+        self.count += 1;
+        self.grown += 1;
+        return true;
+    }
+
+    fn stats(&self) -> HeapStats {
+        let arenas = &self.arenas[..self.count];
+        return HeapStats {
+            size: arenas.iter().map(|arena| arena.heap.size()).sum(),
+            used: arenas.iter().map(|arena| arena.heap.used()).sum(),
+            arenas: self.count,
+            grown: self.grown,
+            returned: self.returned,
+            failed: self.failed
+        };
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(cache) = slab::kmalloc_cache(layout) {
+            if let Some(ptr) = cache.alloc() { return ptr.as_ptr(); }
+            report_oom(layout, stats());
+            return null_mut();
+        }
+
+        let mut arenas = self.0.lock();
+        if let Some(ptr) = arenas.try_alloc(layout) { return ptr.as_ptr(); }
+        if arenas.grow(layout) {
+            if let Some(ptr) = arenas.try_alloc(layout) { return ptr.as_ptr(); }
+        }
+        arenas.failed += 1;
+        let stats = arenas.stats();
+        drop(arenas);
+        report_oom(layout, stats);
+        return null_mut();
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(cache) = slab::kmalloc_cache(layout) {
+            unsafe { cache.free(NonNull::new_unchecked(ptr)); }
+            return;
+        }
+
+        let mut arenas = self.0.lock();
+        let Some(idx) = arenas.arenas().iter().position(|arena| arena.heap.bottom() <= ptr && ptr < arena.heap.top()) else {
+            panic!("heap freed {:p}, which no arena holds", ptr);
+        };
+        let arena = &mut arenas.arenas[idx];
+        unsafe { arena.heap.deallocate(NonNull::new_unchecked(ptr), layout); }
+
+        if idx == 0 || arena.heap.used() != 0 { return; }
+        for &(addr, size) in &arena.extents[..arena.extent_count] {
+            if let Err(err) = unsafe { ramblock::free_raw(addr as *const u8, size) } {
+                panic!("heap arena extent at {:#x} was not allocated: {}", addr, err);
+            }
+        }
+        let last = arenas.count - 1;
+        arenas.arenas.swap(idx, last);
+        arenas.arenas[last] = Arena::empty();
+        arenas.count = last;
+        arenas.returned += 1;
+    }
+}
+
+/// Prints what the heap and ramblock had left when a request failed. The
+/// allocation error that follows names the request once more and panics.
+fn report_oom(layout: Layout, heap: HeapStats) {
+    let ram = ramblock::stats();
+    printlnk!("Out of memory: {} bytes aligned to {} from the kernel heap", layout.size(), layout.align());
+    printlnk!(
+        "Heap: {} KiB in {} arenas, {} KiB used, grown {} times, {} arenas returned, {} requests failed",
+        heap.size / 1024, heap.arenas, heap.used / 1024, heap.grown, heap.returned, heap.failed
+    );
+    printlnk!(
+        "RAM: {} KiB total, {} KiB available, {} KiB peak used; {} failed allocations",
+        ram.total / 1024, ram.available / 1024, ram.peak_used / 1024, ram.failed
+    );
+}
+
+/// Makes `size` bytes at `ptr`, from ramblock, the first arena.
+pub unsafe fn init(ptr: *mut u8, size: usize) {
+    let mut arenas = HEAP.0.lock();
+    let arena = &mut arenas.arenas[0];
+    unsafe { arena.heap.init(ptr, size); }
+    arena.push_extent(ptr as usize, size);
+    arenas.count = 1;
+}
+
+pub fn stats() -> HeapStats { HEAP.0.lock().stats() }
+
+/// Bottom and top of arena `idx`.
+#[cfg(all(test, target_os = "none"))]
+pub fn arena(idx: usize) -> Option<(usize, usize)> {
+    let mut arenas = HEAP.0.lock();
+    let arena = arenas.arenas().get(idx)?;
+    return Some((arena.heap.bottom() as usize, arena.heap.top() as usize));
+}
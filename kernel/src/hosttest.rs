@@ -0,0 +1,9 @@
+// Helpers shared by the host tests
+
+/// Xorshift64: reproducible pseudo-random input from a fixed seed.
+pub fn rng(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    return *state;
+}
@@ -0,0 +1,73 @@
+use crate::EMBER;
+
+// SVR4 "newc" cpio, as produced by `find . | cpio -o -H newc`
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+pub struct CpioEntry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8]
+}
+
+impl CpioEntry<'_> {
+    pub fn is_file(&self) -> bool { self.mode & 0o170000 == 0o100000 }
+}
+
+pub struct CpioIter<'a> {
+    archive: &'a [u8],
+    offset: usize
+}
+
+fn align4(val: usize) -> usize { (val + 3) & !3 }
+
+fn hex_field(header: &[u8], index: usize) -> Option<usize> {
+    let start = CPIO_MAGIC.len() + index * 8;
+    let field = core::str::from_utf8(header.get(start..start + 8)?).ok()?;
+    return usize::from_str_radix(field, 16).ok();
+}
+
+impl<'a> Iterator for CpioIter<'a> {
+    type Item = CpioEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.archive.get(self.offset..self.offset + CPIO_HEADER_SIZE)?;
+        if !header.starts_with(CPIO_MAGIC) { return None; }
+
+        let mode = hex_field(header, 1)? as u32;
+        let file_size = hex_field(header, 6)?;
+        let name_size = hex_field(header, 11)?;
+
+        let name_start = self.offset + CPIO_HEADER_SIZE;
+        let name = self.archive.get(name_start..name_start + name_size.checked_sub(1)?)?;
+        let name = core::str::from_utf8(name).ok()?;
+        if name == CPIO_TRAILER { return None; }
+
+        let data_start = align4(name_start + name_size);
+        let data = self.archive.get(data_start..data_start + file_size)?;
+        self.offset = align4(data_start + file_size);
+        return Some(CpioEntry { name, mode, data });
+    }
+}
+
+pub fn entries<'a>(archive: &'a [u8]) -> CpioIter<'a> {
+    return CpioIter { archive, offset: 0 };
+}
+
+pub fn archive() -> Option<&'static [u8]> {
+    let ember = EMBER.lock();
+    if ember.initrd_ptr == 0 { return None; }
+    return Some(unsafe {
+        core::slice::from_raw_parts(ember.initrd_ptr as *const u8, ember.initrd_size)
+    });
+}
+
+/// Looks up a regular file; `/etc/init`, `etc/init` and `./etc/init` are equivalent.
+pub fn open(path: &str) -> Option<&'static [u8]> {
+    let normalise = |path: &'static str| path.trim_start_matches("./").trim_start_matches('/');
+    let path = path.trim_start_matches('/');
+    return entries(archive()?)
+        .find(|entry| entry.is_file() && normalise(entry.name) == path)
+        .map(|entry| entry.data);
+}
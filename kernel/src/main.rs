@@ -4,21 +4,45 @@
 //! Description: Kernel of Research UNIX Version 11
 //! Licence: Public Domain
 
-#![no_std]
-#![no_main]
-#![feature(abi_x86_interrupt)]
+#![cfg_attr(not(all(test, not(target_os = "none"))), no_std)]
+#![cfg_attr(not(all(test, not(target_os = "none"))), no_main)]
+#![cfg_attr(not(all(test, not(target_os = "none"))), feature(abi_x86_interrupt))]
 #![feature(abi_riscv_interrupt)]
+#![cfg_attr(all(test, target_os = "none"), feature(custom_test_frameworks))]
+#![cfg_attr(all(test, target_os = "none"), test_runner(crate::testing::run))]
+#![cfg_attr(all(test, target_os = "none"), reexport_test_harness_main = "test_main")]
+// Host tests take a slice of the kernel, so most of it has no callers there
+#![cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
 
 extern crate alloc;
 
-mod device; mod ember;
-mod ram; mod ramblock;
+// Host tests build only the allocator and what it sits on; the rest needs the
+// machine or the handoff, and stays behind `metal!`.
+macro_rules! metal {
+    ($($item:item)*) => { $(#[cfg(not(all(test, not(target_os = "none"))))] $item)* };
+}
+
+metal! {
+    mod cmdline;
+    mod device; mod efi;
+    mod fbcon;
+    mod initrd;
+    mod heap; mod slab; mod vmm;
+}
+mod ember;
+mod buddy; mod ram; mod ramblock;
 mod sort;
+#[cfg(all(test, target_os = "none"))]
+mod testing;
+#[cfg(all(test, not(target_os = "none")))]
+mod hosttest;
 
-use core::panic::PanicInfo;
-use ember::Ember;
-use spin::Mutex;
+metal! {
+    use ember::Ember;
+    use spin::Mutex;
+}
 
+#[cfg(not(all(test, not(target_os = "none"))))]
 macro_rules! use_arch {
     ($arch:literal, $modname:ident) => {
         #[cfg(target_arch = $arch)] mod $modname;
@@ -26,47 +50,103 @@ macro_rules! use_arch {
     };
 }
 
+#[cfg(not(all(test, not(target_os = "none"))))]
 #[macro_export]
 macro_rules! printk {
     ($($arg:tt)*) => {{
         use core::fmt::Write;
-        let _ = core::write!($crate::arch::SerialWriter, $($arg)*);
+        let console = $crate::cmdline::params().console;
+        if console & $crate::cmdline::CONSOLE_SERIAL != 0 {
+            let _ = core::write!($crate::arch::SerialWriter, $($arg)*);
+        }
+        if console & $crate::cmdline::CONSOLE_FB != 0 {
+            let _ = core::write!($crate::fbcon::FbWriter, $($arg)*);
+        }
     }};
 }
 
+#[cfg(all(test, not(target_os = "none")))]
+#[macro_export]
+macro_rules! printk {
+    ($($arg:tt)*) => { std::print!($($arg)*) };
+}
+
 #[macro_export]
 macro_rules! printlnk {
     () => { $crate::printk!("\r\n"); };
     ($($arg:tt)*) => { $crate::printk!("{}\r\n", format_args!($($arg)*)) };
 }
 
-use_arch!("x86_64", amd64);
-use_arch!("aarch64", aarch64);
-use_arch!("riscv64", riscv64);
+metal! {
+    use_arch!("x86_64", amd64);
+    use_arch!("aarch64", aarch64);
+    use_arch!("riscv64", riscv64);
 
-fn init_metal() {
-    arch::init_exceptions();
-    arch::init_serial();
-    ram::init_ram();
-    printlnk!("Uniplexed Information and Computing Service Version 11");
-    device::init_device();
-}
-fn exec_aleph() {}
-fn schedule() -> ! { loop { arch::halt(); } }
+    fn init_metal() {
+        cmdline::init();
+        arch::init_exceptions();
+        let console = cmdline::params().console;
+        if console & cmdline::CONSOLE_SERIAL != 0 { arch::init_serial(); }
+        if console & cmdline::CONSOLE_FB != 0     { fbcon::init(); }
+        ram::init_ram();
+        if cmdline::params().paging { vmm::init(); }
+        printlnk!("Uniplexed Information and Computing Service Version 11");
+        let cmdline = cmdline::params().raw;
+        if !cmdline.is_empty() { printlnk!("Command line: {}", cmdline); }
+        report_integrity();
+        efi::init();
+        if let Ok(time) = efi::get_time() { printlnk!("RTC: {}", time); }
+        device::init_device();
+        if cmdline::params().memmap {
+            ramblock::dump();
+            slab::dump();
+            ramblock::leak_report();
+        }
+    }
+    fn report_integrity() {
+        let Some(integrity) = EMBER.lock().integrity else {
+            printlnk!("Kernel image: not checked by the loader");
+            return;
+        };
+        let state = match integrity.status {
+            ember::integrity::SIGNED => "signature verified",
+            ember::integrity::DIGEST => "checksum verified",
+            _                        => "unverified"
+        };
+        printk!("Kernel image: {}, SHA-256 ", state);
+        for byte in integrity.sha256 { printk!("{:02x}", byte); }
+        printlnk!();
+    }
+    fn exec_aleph() {
+        match initrd::open("/etc/init") {
+            Some(init) => printlnk!("/etc/init: {} bytes from initrd", init.len()),
+            None => printlnk!("/etc/init not found in initrd")
+        }
+    }
+    fn schedule() -> ! { loop { arch::halt(); } }
 
-pub static EMBER: Mutex<Ember> = Mutex::new(Ember::empty());
+    pub static EMBER: Mutex<Ember> = Mutex::new(Ember::empty());
 
-#[unsafe(no_mangle)]
-pub extern "efiapi" fn flame(ember: Ember) -> ! {
-    EMBER.lock().init(ember);
-    ramblock::init();
-    init_metal();
-    exec_aleph();
-    schedule();
+    #[unsafe(no_mangle)]
+    pub extern "efiapi" fn flame(handoff: *const u8) -> ! {
+        let result = EMBER.lock().init(handoff);
+        if let Err(err) = result {
+            arch::init_serial();
+            printlnk!("Ember handoff rejected: {}", err);
+            loop { arch::halt(); }
+        }
+        ramblock::init();
+        init_metal();
+        #[cfg(all(test, target_os = "none"))]
+        test_main();
+        exec_aleph();
+        schedule();
+    }
 }
 
+#[cfg(not(test))]
 #[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
     printlnk!("{}", info);
     loop { arch::halt(); }
 }
\ No newline at end of file
@@ -1,13 +1,20 @@
-use crate::{arch, ember::ramtype, ramblock::{self, AllocParams}};
-use core::{alloc::Layout, ops::{Deref, DerefMut}};
-use alloc::alloc::{alloc, dealloc};
-use linked_list_allocator::LockedHeap;
+#[cfg(not(all(test, not(target_os = "none"))))]
+use crate::{arch, cmdline, ember::ramtype, heap, ramblock::{self, owner, AllocParams, RBPtr}, vmm};
+use core::{alloc::Layout, ops::{Deref, DerefMut}, sync::atomic::{AtomicUsize, Ordering}};
+use alloc::alloc::{alloc, dealloc, handle_alloc_error};
 
 pub const STACK_SIZE: usize = 0x100000;
+pub const EXCEPTION_STACK_SIZE: usize = 0x10000;
 pub const HEAP_SIZE: usize = 0x100000;
 
 pub const PAGE_4KIB: usize = 0x1000;
 
+// Guard pages sit unmapped below every stack, so an overflow faults instead of
+// running into whatever lies beneath. Exception handlers look them up here.
+const MAX_GUARDS: usize = 64;
+static GUARDS: [AtomicUsize; MAX_GUARDS] = [const { AtomicUsize::new(0) }; MAX_GUARDS];
+static GUARD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 pub struct PageAligned {
     ptr: *mut u8,
     layout: Layout
@@ -17,7 +24,7 @@ impl PageAligned {
     pub fn new(size: usize) -> Self {
         let layout = Layout::from_size_align(size, PAGE_4KIB).unwrap();
         let ptr = unsafe { alloc(layout) };
-        if ptr.is_null() { panic!("Failed to allocate aligned memory"); }
+        if ptr.is_null() { handle_alloc_error(layout); }
         return Self { ptr, layout };
     }
 }
@@ -41,24 +48,52 @@ impl DerefMut for PageAligned {
     }
 }
 
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
-
 pub fn align_up(val: usize, align: usize) -> usize {
     if align == 0 { return val; }
     return val + (align - val % align) % align;
 }
 
+/// Allocates a stack of `size` bytes with a guard page below it. The returned
+/// block starts with the guard; the stack grows down from its end. Without a
+/// kernel space, as on aarch64 so far, the guard stays mapped and catches nothing.
+#[cfg(not(all(test, not(target_os = "none"))))]
+pub fn alloc_stack(size: usize) -> RBPtr {
+    let stack = ramblock::alloc(
+        AllocParams::new(PAGE_4KIB + size).as_type(ramtype::KERNEL_DATA).owner(owner::STACK)
+    ).expect("no memory for a kernel stack");
+    let idx = GUARD_COUNT.fetch_add(1, Ordering::AcqRel);
+    assert!(idx < MAX_GUARDS, "too many kernel stacks");
+    GUARDS[idx].store(stack.addr(), Ordering::Release);
+    vmm::unmap_guard(stack.addr());
+    return stack;
+}
+
+pub fn guard_pages() -> impl Iterator<Item = usize> {
+    let count = GUARD_COUNT.load(Ordering::Acquire).min(MAX_GUARDS);
+    return GUARDS[..count].iter().map(|guard| guard.load(Ordering::Acquire)).filter(|&guard| guard != 0);
+}
+
+/// Whether `addr` is in the guard page of some stack.
+pub fn is_stack_guard(addr: usize) -> bool {
+    return guard_pages().any(|guard| (guard..guard + PAGE_4KIB).contains(&addr));
+}
+
+#[cfg(not(all(test, not(target_os = "none"))))]
 pub fn init_ram() {
-    let stack_ptr = ramblock::alloc(
-        AllocParams::new(STACK_SIZE).as_type(ramtype::KERNEL_DATA)
-    ).unwrap();
-    unsafe { arch::move_stack(&stack_ptr, STACK_SIZE); }
+    let stack = alloc_stack(STACK_SIZE);
+    unsafe { arch::move_stack(&stack, stack.size()); }
 
     let available = ramblock::available();
-    let heap_size = ((available as f64 * 0.02) as usize).max(HEAP_SIZE);
+    let heap_size = cmdline::params().heap
+        .unwrap_or((available as f64 * 0.02) as usize)
+        .max(HEAP_SIZE);
     let heap_ptr = ramblock::alloc(
-        AllocParams::new(heap_size).as_type(ramtype::KERNEL_DATA)
+        AllocParams::new(heap_size).as_type(ramtype::KERNEL_DATA).owner(owner::HEAP)
     ).unwrap();
-    unsafe { ALLOCATOR.lock().init(heap_ptr.ptr(), heap_size); }
+    unsafe { heap::init(heap_ptr.ptr(), heap_ptr.size()); }
+
+    // Quotas cap what comes after the boot stack and the initial heap
+    for (owner, &quota) in cmdline::params().quotas.iter().enumerate() {
+        if quota.is_some() { ramblock::set_quota(owner as u16, quota); }
+    }
 }
\ No newline at end of file
@@ -1,7 +1,45 @@
 #![allow(dead_code)]
-use crate::{ember::ramtype, ram::{align_up, PAGE_4KIB}, sort::HeaplessSort, EMBER};
+use crate::{buddy::{Buddy, FreeError, DMA_LIMITS}, ember::{ramtype, RAMDescriptor}, printlnk, ram::{align_up, PAGE_4KIB}, sort::HeaplessSort};
+#[cfg(not(all(test, not(target_os = "none"))))]
+use crate::EMBER;
 use spin::Mutex;
 
+/// Who asked for memory, so usage can be told apart and capped per subsystem.
+pub mod owner {
+    pub const NONE      : u16 = 0;
+    pub const FIRMWARE  : u16 = 1;
+    pub const RAMBLOCK  : u16 = 2;
+    pub const STACK     : u16 = 3;
+    pub const HEAP      : u16 = 4;
+    pub const PAGE_TABLE: u16 = 5;
+    pub const NVME      : u16 = 6;
+    pub const TEST      : u16 = 7;
+    pub const SLAB      : u16 = 8;
+    pub const MAX       : u16 = 9;
+
+    pub fn name(owner: u16) -> &'static str {
+        match owner {
+            NONE       => "untagged",
+            FIRMWARE   => "firmware",
+            RAMBLOCK   => "ramblock",
+            STACK      => "stack",
+            HEAP       => "heap",
+            PAGE_TABLE => "page tables",
+            NVME       => "nvme",
+            TEST       => "test",
+            SLAB       => "slab",
+            _          => "unknown"
+        }
+    }
+
+    /// Owner whose name is `name`, with `_` for spaces, as the command line spells it.
+    pub fn by_name(name: &str) -> Option<u16> {
+        return (0..MAX).find(|&owner|
+            self::name(owner).bytes().map(|b| if b == b' ' { b'_' } else { b }).eq(name.bytes())
+        );
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct RAMBlock {
@@ -9,21 +47,23 @@ pub struct RAMBlock {
     size: usize,
     ty: u32,
     valid: bool,
-    used: bool
+    used: bool,
+    owner: u16
 }
 
 impl RAMBlock {
-    pub fn new(addr: *const u8, size: usize, ty: u32, used: bool) -> Self {
-        return Self { addr, size, ty, valid: true, used };
+    pub fn new(addr: *const u8, size: usize, ty: u32, used: bool, owner: u16) -> Self {
+        return Self { addr, size, ty, valid: true, used, owner };
     }
     pub const fn new_invalid() -> Self {
-        return Self { addr: 0 as *const u8, size: 0, ty: 0, valid: false, used: false };
+        return Self { addr: 0 as *const u8, size: 0, ty: 0, valid: false, used: false, owner: owner::NONE };
     }
 
     pub fn addr(&self) -> usize    {  self.addr as usize }
     pub fn ptr(&self) -> *mut u8   {  self.addr as *mut u8 }
     pub fn size(&self) -> usize    {  self.size }
     pub fn ty(&self) -> u32        {  self.ty }
+    pub fn owner(&self) -> u16     {  self.owner }
     pub fn valid(&self) -> bool    {  self.valid }
     pub fn invalid(&self) -> bool  { !self.valid }
     pub fn used(&self) -> bool     {  self.used }
@@ -39,7 +79,8 @@ impl RAMBlock {
         let info_eq = {
             self.valid && other.valid &&
             self.ty == other.ty &&
-            self.used == other.used
+            self.used == other.used &&
+            self.owner == other.owner
         };
         if !info_eq { return 0; }
         return
@@ -64,31 +105,48 @@ impl RBPtr {
     pub fn size(&self) -> usize { self.size }
 }
 
+/// Address ranges for devices that cannot reach all of RAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmaZone {
+    Dma16,  // Below 16 MiB, for ISA-style DMA
+    Dma32,  // Below 4 GiB, for 32-bit DMA
+    Normal
+}
+
+impl DmaZone {
+    pub fn limit(self) -> usize {
+        match self {
+            Self::Dma16  => DMA_LIMITS[0],
+            Self::Dma32  => DMA_LIMITS[1],
+            Self::Normal => usize::MAX
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct AllocParams {
     addr: Option<*const u8>,
     size: usize,
     align: usize,
-    from_type: u32,
     as_type: u32,
-    used: bool
+    owner: u16,
+    limit: usize  // The block must end at or below this address
 }
 
 impl AllocParams {
     pub fn new(size: usize) -> Self {
         Self {
-            addr: None, size, align: PAGE_4KIB,
-            from_type: ramtype::CONVENTIONAL,
-            as_type: ramtype::CONVENTIONAL,
-            used: true
+            addr: None, size, align: PAGE_4KIB, as_type: ramtype::CONVENTIONAL,
+            owner: owner::NONE, limit: usize::MAX
         }
     }
 
     pub fn at<T>(mut self, addr: *mut T) -> Self { self.addr = Some(addr as *const u8); self }
-    pub fn align(mut self, align: usize) -> Self { self.align = align.max(1); self }
-    pub fn from_type(mut self, ty: u32) -> Self { self.from_type = ty; self }
+    pub fn align(mut self, align: usize) -> Self { self.align = align.max(1).next_power_of_two(); self }
     pub fn as_type(mut self, ty: u32) -> Self { self.as_type = ty; self }
-    pub fn reserve(mut self) -> Self { self.used = false; self }
+    pub fn owner(mut self, owner: u16) -> Self { self.owner = if owner < owner::MAX { owner } else { owner::NONE }; self }
+    pub fn below(mut self, limit: usize) -> Self { self.limit = limit; self }
+    pub fn zone(mut self, zone: DmaZone) -> Self { self.limit = zone.limit(); self }
 
     fn aligned(mut self) -> Self {
         self.size = align_up(self.size, self.align);
@@ -99,17 +157,57 @@ impl AllocParams {
     }
 }
 
+/// Counters kept since init, for the boot-time dump and /proc/meminfo alike.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RAMStats {
+    pub total: usize,
+    pub available: usize,
+    pub peak_used: usize, // High-water mark of allocated bytes
+    pub allocs: usize,
+    pub frees: usize,
+    pub failed: usize     // Allocations that found no room or hit a quota
+}
+
+/// Memory one owner holds from the allocator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OwnerUsage {
+    pub used: usize,
+    pub peak: usize,
+    pub quota: Option<usize>
+}
+
+impl OwnerUsage {
+    const fn new() -> Self {
+        return Self { used: 0, peak: 0, quota: None };
+    }
+}
+
+/// Bytes of the whole pages covering `addr..addr + size`.
+fn page_span(addr: usize, size: usize) -> usize {
+    return ((addr + size).div_ceil(PAGE_4KIB) - addr / PAGE_4KIB) * PAGE_4KIB;
+}
+
+// Free CONVENTIONAL memory belongs to the buddy allocator, and so do the blocks
+// it hands out; the block list keeps the rest of the map (firmware, kernel, the
+// buddy's own frame array) in address order.
 #[repr(C)]
 #[derive(Debug)]
 pub struct RAMBlockManager {
     blocks: *const RAMBlock,
     is_init: bool,
-    max: usize
+    max: usize,
+    owned: bool, // Blocks came from expand, not the embedded array
+    buddy: Buddy,
+    stats: RAMStats,
+    owners: [OwnerUsage; owner::MAX as usize]
 }
 
 const BASE_RAMBLOCK_SIZE: usize = 128;
-static RAMBLOCKS_EMBEDDED: [RAMBlock; BASE_RAMBLOCK_SIZE] = [RAMBlock::new_invalid(); BASE_RAMBLOCK_SIZE];
-static RAMBLOCK_MANAGER: Mutex<RAMBlockManager> = Mutex::new(RAMBlockManager::empty(&RAMBLOCKS_EMBEDDED));
+// Mutable, or the embedded list would land in read-only data
+static mut RAMBLOCKS_EMBEDDED: [RAMBlock; BASE_RAMBLOCK_SIZE] = [RAMBlock::new_invalid(); BASE_RAMBLOCK_SIZE];
+static RAMBLOCK_MANAGER: Mutex<RAMBlockManager> = Mutex::new(
+    RAMBlockManager::empty((&raw mut RAMBLOCKS_EMBEDDED).cast(), BASE_RAMBLOCK_SIZE)
+);
 
 unsafe impl Send for RAMBlock {}
 unsafe impl Sync for RAMBlock {}
@@ -117,27 +215,43 @@ unsafe impl Send for RAMBlockManager {}
 unsafe impl Sync for RAMBlockManager {}
 
 impl RAMBlockManager {
-    const fn empty(rb: &[RAMBlock]) -> Self {
-        RAMBlockManager { blocks: rb.as_ptr(), is_init: false, max: rb.len() }
+    const fn empty(blocks: *mut RAMBlock, max: usize) -> Self {
+        RAMBlockManager {
+            blocks, is_init: false, max, owned: false, buddy: Buddy::empty(),
+            stats: RAMStats { total: 0, available: 0, peak_used: 0, allocs: 0, frees: 0, failed: 0 },
+            owners: [OwnerUsage::new(); owner::MAX as usize]
+        }
     }
 
+    #[cfg(not(all(test, not(target_os = "none"))))]
     fn init(&mut self) {
+        let efi_ram_layout = EMBER.lock().efi_ram_layout_mut();
+        self.init_with(efi_ram_layout);
+    }
+
+    /// Builds the allocator from a RAM layout, which gets sorted by address.
+    fn init_with(&mut self, mut efi_ram_layout: &mut [RAMDescriptor]) {
         if self.is_init { return; }
-        let mut efi_ram_layout = EMBER.lock().efi_ram_layout_mut();
-        efi_ram_layout.sort_noheap_by_key(|desc| desc.page_count);
-        for desc in efi_ram_layout.iter().rev() {
-            if desc.ty == ramtype::CONVENTIONAL {
-                let size = desc.page_count as usize * PAGE_4KIB;
-                let addr = desc.phys_start as *const u8;
-                self.add(addr, size, desc.ty, false);
-            }
-        }
         efi_ram_layout.sort_noheap_by_key(|desc| desc.phys_start);
-        for desc in efi_ram_layout {
+
+        // The frame array comes out of the lowest range large enough for it
+        let (zones, pages) = conventional_ranges(efi_ram_layout)
+            .fold((0, 0), |(zones, pages), (start, end)| (zones + 1, pages + (end - start) / PAGE_4KIB));
+        let meta_size = align_up(Buddy::meta_size(zones, pages), PAGE_4KIB);
+        let (meta, _) = conventional_ranges(efi_ram_layout)
+            .find(|&(start, end)| end - start >= meta_size)
+            .expect("no room for the page frame array");
+        unsafe { self.buddy.init(meta as *mut u8, zones, pages); }
+        for (start, end) in conventional_ranges(efi_ram_layout) {
+            self.buddy.add_zone(if start == meta { meta + meta_size } else { start }, end);
+        }
+
+        self.add(meta as *const u8, meta_size, ramtype::KERNEL_DATA, true, owner::RAMBLOCK);
+        for desc in efi_ram_layout.iter() {
             if desc.ty != ramtype::CONVENTIONAL {
                 let size = desc.page_count as usize * PAGE_4KIB;
                 let addr = desc.phys_start as *const u8;
-                self.add(addr, size, desc.ty, true);
+                self.add(addr, size, desc.ty, true, owner::FIRMWARE);
             }
         }
         self.is_init = true;
@@ -166,12 +280,31 @@ impl RAMBlockManager {
             .map(|block| block.size()).sum();
     }
 
-    fn available(&self) -> usize {
-        return self.count_filter(|block| block.not_used() && block.ty() == ramtype::CONVENTIONAL);
+    fn available(&self) -> usize { return self.buddy.free_pages() * PAGE_4KIB; }
+
+    fn available_in(&self, zone: DmaZone) -> usize { return self.buddy.free_pages_below(zone.limit()) * PAGE_4KIB; }
+
+    fn total(&self) -> usize { return self.buddy.total_pages() * PAGE_4KIB; }
+
+    fn stats(&self) -> RAMStats {
+        return RAMStats { total: self.total(), available: self.available(), ..self.stats };
     }
 
-    fn total(&self) -> usize {
-        return self.count_filter(|block| block.ty() == ramtype::CONVENTIONAL);
+    /// Every block of the map in address order, and whether it is reserved: the fixed
+    /// part from the block list, or else allocated or free memory from the buddy
+    /// allocator. Free memory is CONVENTIONAL.
+    fn for_each_block(&self, mut f: impl FnMut(RAMBlock, bool)) {
+        let mut runs = self.buddy.runs()
+            .map(|run| {
+                let ty = if run.used { run.ty } else { ramtype::CONVENTIONAL };
+                RAMBlock::new(run.addr as *const u8, run.size, ty, run.used, run.owner)
+            })
+            .peekable();
+        for &block in self.blocks_iter() {
+            while let Some(run) = runs.next_if(|run| run.addr() < block.addr()) { f(run, false); }
+            f(block, true);
+        }
+        runs.for_each(|run| f(run, false));
     }
 
     fn sort(&mut self) {
@@ -189,59 +322,49 @@ impl RAMBlockManager {
         return self.blocks_iter_mut().find(|block| f(block));
     }
 
-    fn find_free_ram(&mut self, args: AllocParams) -> Option<RBPtr> {
-        let args = args.aligned();
-        return self.find(|block|
-            block.not_used() && block.size() >= args.size && block.ty() == args.from_type
-        ).map(|block| RBPtr::new(block.ptr(), args.size));
-    }
-
     fn alloc(&mut self, args: AllocParams) -> Option<RBPtr> {
         let args = args.aligned();
+        let usage = self.owners[args.owner as usize];
+        let span = page_span(args.addr.map_or(0, |addr| addr as usize), args.size);
+        if usage.quota.is_some_and(|quota| usage.used + span > quota) {
+            self.stats.failed += 1;
+            return None;
+        }
+
         let ptr = match args.addr {
-            Some(addr) => addr,
-            None => self.find_free_ram(args)?.ptr(),
+            Some(addr) if addr as usize + args.size > args.limit => None,
+            Some(addr) => self.buddy.alloc_at(addr as usize, args.size, args.as_type, args.owner).then_some(addr),
+            None => {
+                let pages = args.size.div_ceil(PAGE_4KIB);
+                let align = args.align.div_ceil(PAGE_4KIB);
+                self.buddy.alloc(pages, align, args.as_type, args.owner, args.limit).map(|addr| addr as *const u8)
+            }
         };
-
-        let filter = |block: &RAMBlock| {
-            block.not_used() && args.from_type == block.ty() &&
-            ptr >= block.ptr() && ptr as usize + args.size <= block.addr() + block.size()
+        let Some(ptr) = ptr else {
+            self.stats.failed += 1;
+            return None;
         };
-
-        let mut split_info = None;
-        for block in self.blocks_iter_mut() {
-            if filter(block) {
-                if block.ty() == args.as_type && !args.used { break; }
-                split_info = Some(*block);
-                *block = RAMBlock::new(ptr, args.size, args.as_type, args.used);
-                break;
-            }
-        }
-
-        if let Some(block) = split_info {
-            let before = ptr as usize - block.addr();
-            let after_ptr = (ptr as usize + args.size) as *const u8;
-            let after = block.addr() + block.size() - after_ptr as usize;
-            if before > 0 { self.add(block.ptr(), before, block.ty(), false); }
-            if after > 0 { self.add(after_ptr, after, block.ty(), false); }
-            return Some(RBPtr::new(ptr, args.size));
-        }
-
-        return None;
+        self.stats.allocs += 1;
+        self.stats.peak_used = self.stats.peak_used.max(self.total() - self.available());
+        let usage = &mut self.owners[args.owner as usize];
+        usage.used += span;
+        usage.peak = usage.peak.max(usage.used);
+        return Some(RBPtr::new(ptr, args.size));
     }
 
-    fn free(&mut self, ptr: RBPtr) {
-        let found = self.find(|block|
-            block.ptr() <= ptr.ptr() && block.addr() + block.size() > ptr.addr()
-        );
-        if let Some(block) = found {
-            block.set_used(false);
-            block.set_ty(ramtype::CONVENTIONAL);
+    fn free(&mut self, ptr: RBPtr) -> Result<(), FreeError> {
+        let owner = self.buddy.owner_of(ptr.addr());
+        self.buddy.free(ptr.addr(), ptr.size())?;
+        self.stats.frees += 1;
+        if let Some(owner) = owner {
+            let usage = &mut self.owners[owner as usize];
+            usage.used = usage.used.saturating_sub(page_span(ptr.addr(), ptr.size()));
         }
+        return Ok(());
     }
 
-    fn add(&mut self, addr: *const u8, size: usize, ty: u32, used: bool) {
-        let new_block = RAMBlock::new(addr, size, ty, used);
+    fn add(&mut self, addr: *const u8, size: usize, ty: u32, used: bool, owner: u16) {
+        let new_block = RAMBlock::new(addr, size, ty, used, owner);
 
         let (mut before, mut after) = (None, None);
 
@@ -257,6 +380,7 @@ impl RAMBlockManager {
             (Some(before_block), Some(after_block)) => {
                 before_block.set_size(before_block.size() + new_block.size() + after_block.size());
                 after_block.set_valid(false);
+                self.sort(); // Close the hole, or a later insertion lands out of order
             },
             (Some(before_block), None) => {
                 before_block.set_size(before_block.size() + new_block.size());
@@ -271,7 +395,7 @@ impl RAMBlockManager {
                 let mut idx = 0;
                 for block in &mut *blocks {
                     if block.valid() { idx += 1; continue; }
-                    *block = RAMBlock::new(addr, size, ty, used);
+                    *block = new_block;
                     break;
                 }
 
@@ -287,31 +411,470 @@ impl RAMBlockManager {
     fn expand(&mut self, new_max: usize) {
         if new_max <= self.max { return; }
 
-        let alloc_param = AllocParams::new(new_max * size_of::<RAMBlock>());
-        let old_blocks_ptr = self.blocks;
-        let new_blocks_ptr = self.find_free_ram(alloc_param).unwrap().ptr();
+        let alloc_param = AllocParams::new(new_max * size_of::<RAMBlock>())
+            .as_type(ramtype::KERNEL_DATA).owner(owner::RAMBLOCK);
+        let (old_blocks_ptr, old_max) = (self.blocks, self.max);
+        let new_blocks_ptr = self.alloc(alloc_param).expect("no room to grow the RAM block list").ptr::<RAMBlock>();
         unsafe {
             core::ptr::write_bytes(new_blocks_ptr, 0, new_max);
-            core::ptr::copy(old_blocks_ptr, new_blocks_ptr, self.max);
+            core::ptr::copy(old_blocks_ptr, new_blocks_ptr, old_max);
         }
-        (self.blocks, self.max) = (new_blocks_ptr, new_max);
-        if old_blocks_ptr != RAMBLOCKS_EMBEDDED.as_ptr() {
-            self.free(RBPtr::new(old_blocks_ptr, self.max));
+        if self.owned {
+            self.free(RBPtr::new(old_blocks_ptr, old_max)).expect("RAM block list was not allocated");
         }
-        self.alloc(alloc_param.at(new_blocks_ptr));
+        (self.blocks, self.max, self.owned) = (new_blocks_ptr, new_max, true);
     }
 }
 
+/// CONVENTIONAL ranges of an address-sorted layout, adjacent ones merged.
+fn conventional_ranges(layout: &[RAMDescriptor]) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut descs = layout.iter()
+        .filter(|desc| desc.ty == ramtype::CONVENTIONAL && desc.page_count > 0)
+        .map(|desc| (desc.phys_start as usize, (desc.phys_start + desc.page_count * PAGE_4KIB as u64) as usize))
+        .peekable();
+    return core::iter::from_fn(move || {
+        let (start, mut end) = descs.next()?;
+        while let Some(&(next_start, next_end)) = descs.peek() {
+            if next_start != end { break; }
+            end = next_end;
+            descs.next();
+        }
+        return Some((start, end));
+    });
+}
+
 // Atomic API to RAMBlock Manager
+#[cfg(not(all(test, not(target_os = "none"))))]
 pub fn init() { RAMBLOCK_MANAGER.lock().init() }
 pub fn available() -> usize { RAMBLOCK_MANAGER.lock().available() }
+pub fn available_in(zone: DmaZone) -> usize { RAMBLOCK_MANAGER.lock().available_in(zone) }
 pub fn total() -> usize { RAMBLOCK_MANAGER.lock().total() }
 pub fn sort() { RAMBLOCK_MANAGER.lock().sort(); }
-pub fn find_free_ram(args: AllocParams) -> Option<RBPtr> { RAMBLOCK_MANAGER.lock().find_free_ram(args) }
 pub fn alloc(args: AllocParams) -> Option<RBPtr> { RAMBLOCK_MANAGER.lock().alloc(args) }
-pub fn free(ptr: RBPtr) { RAMBLOCK_MANAGER.lock().free(ptr) }
-pub unsafe fn free_raw(ptr: *const u8, size: usize) {
+pub fn free(ptr: RBPtr) -> Result<(), FreeError> { RAMBLOCK_MANAGER.lock().free(ptr) }
+pub unsafe fn free_raw(ptr: *const u8, size: usize) -> Result<(), FreeError> {
     let ptr = RBPtr::new(ptr, size);
     RAMBLOCK_MANAGER.lock().free(ptr)
 }
-pub fn expand(new_max: usize) { RAMBLOCK_MANAGER.lock().expand(new_max); }
\ No newline at end of file
+pub fn expand(new_max: usize) { RAMBLOCK_MANAGER.lock().expand(new_max); }
+pub fn stats() -> RAMStats { RAMBLOCK_MANAGER.lock().stats() }
+pub fn usage(owner: u16) -> OwnerUsage {
+    return RAMBLOCK_MANAGER.lock().owners.get(owner as usize).copied().unwrap_or_default();
+}
+/// Caps what `owner` may hold at once; allocations past it fail. None lifts the cap.
+pub fn set_quota(owner: u16, quota: Option<usize>) {
+    if owner < owner::MAX { RAMBLOCK_MANAGER.lock().owners[owner as usize].quota = quota; }
+}
+pub fn for_each_block(f: impl FnMut(RAMBlock, bool)) { RAMBLOCK_MANAGER.lock().for_each_block(f); }
+
+const MAX_TYPES: usize = 32;
+
+/// Prints every block of the map, then the totals per type and the statistics.
+pub fn dump() {
+    let mut totals = [(0u32, 0usize, 0usize); MAX_TYPES]; // Type, bytes used, bytes free
+    let mut types = 0;
+
+    printlnk!("RAM map:");
+    for_each_block(|block, reserved| {
+        let state = if reserved { "reserved" } else if block.used() { "used" } else { "free" };
+        printlnk!(
+            "  {:#014x}-{:#014x} {:>10} KiB  {:<22} {}",
+            block.addr(), block.addr() + block.size() - 1, block.size() / 1024, ramtype::name(block.ty()), state
+        );
+
+        let idx = totals[..types].iter().position(|&(ty, _, _)| ty == block.ty()).unwrap_or(types);
+        if idx == MAX_TYPES { return; }
+        if idx == types { totals[idx].0 = block.ty(); types += 1; }
+        if block.used() { totals[idx].1 += block.size(); } else { totals[idx].2 += block.size(); }
+    });
+
+    printlnk!("RAM by type:");
+    for &(ty, used, free) in &totals[..types] {
+        printlnk!("  {:<22} {:>10} KiB used {:>10} KiB free", ramtype::name(ty), used / 1024, free / 1024);
+    }
+
+    printlnk!("RAM by owner:");
+    for owner in 0..owner::MAX {
+        let usage = usage(owner);
+        if usage.peak == 0 { continue; }
+        printlnk!("  {:<22} {:>10} KiB used {:>10} KiB peak", owner::name(owner), usage.used / 1024, usage.peak / 1024);
+    }
+
+    let stats = stats();
+    printlnk!(
+        "RAM: {} KiB total, {} KiB available, {} KiB peak used; {} allocations, {} frees, {} failed",
+        stats.total / 1024, stats.available / 1024, stats.peak_used / 1024, stats.allocs, stats.frees, stats.failed
+    );
+    printlnk!(
+        "RAM available below 16 MiB: {} KiB, below 4 GiB: {} KiB",
+        available_in(DmaZone::Dma16) / 1024, available_in(DmaZone::Dma32) / 1024
+    );
+}
+
+/// Lists what each owner still holds from the allocator, one line per run of
+/// adjacent allocations, to find who is eating memory.
+pub fn leak_report() {
+    printlnk!("Outstanding allocations:");
+    for owner in 0..owner::MAX {
+        let usage = usage(owner);
+        if usage.used == 0 { continue; }
+        match usage.quota {
+            Some(quota) => printlnk!("  {}: {} KiB of {} KiB quota", owner::name(owner), usage.used / 1024, quota / 1024),
+            None => printlnk!("  {}: {} KiB", owner::name(owner), usage.used / 1024)
+        }
+        for_each_block(|block, reserved| {
+            if reserved || block.not_used() || block.owner() != owner { return; }
+            printlnk!(
+                "    {:#014x}-{:#014x} {:>10} KiB  {}",
+                block.addr(), block.addr() + block.size() - 1, block.size() / 1024, ramtype::name(block.ty())
+            );
+        });
+    }
+}
+// Host tests: cargo test -p unix-v11-kernel from the workspace root. Layouts point
+// into a heap arena, as the buddy allocator writes its frame array into RAM.
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::{buddy::MAX_ORDER, hosttest::rng};
+    use alloc::{vec, vec::Vec};
+
+    const PAGE: usize = PAGE_4KIB;
+
+    struct Arena {
+        _mem: Vec<u8>,
+        base: usize
+    }
+
+    impl Arena {
+        fn new(pages: usize) -> Self {
+            let mem = vec![0u8; (pages + 1) * PAGE];
+            let base = align_up(mem.as_ptr() as usize, PAGE);
+            return Self { _mem: mem, base };
+        }
+
+        fn desc(&self, ty: u32, page: usize, pages: usize) -> RAMDescriptor {
+            return RAMDescriptor {
+                ty, reserved: 0, phys_start: (self.base + page * PAGE) as u64,
+                virt_start: 0, page_count: pages as u64, attr: 0, padding: 0
+            };
+        }
+
+        fn addr(&self, page: usize) -> usize { self.base + page * PAGE }
+    }
+
+    // Reserved hole, two adjacent CONVENTIONAL descriptors, firmware between the rest;
+    // listed out of order, as firmware may
+    fn layout(arena: &Arena) -> Vec<RAMDescriptor> {
+        return vec![
+            arena.desc(ramtype::CONVENTIONAL, 12100, 4284),
+            arena.desc(ramtype::RUNTIME_SERVICES_DATA, 12000, 100),
+            arena.desc(ramtype::CONVENTIONAL, 4104, 7896),
+            arena.desc(ramtype::ACPI_RECLAIM, 4096, 8),
+            arena.desc(ramtype::CONVENTIONAL, 1000, 3096),
+            arena.desc(ramtype::CONVENTIONAL, 16, 984),
+            arena.desc(ramtype::RESERVED, 0, 16)
+        ];
+    }
+
+    // Pages of the three merged CONVENTIONAL ranges
+    const RANGES: [(usize, usize); 3] = [(16, 4096), (4104, 12000), (12100, 16384)];
+    const CONVENTIONAL_PAGES: usize = 4080 + 7896 + 4284;
+
+    fn manager(blocks: &mut [RAMBlock]) -> RAMBlockManager {
+        return RAMBlockManager::empty(blocks.as_mut_ptr(), blocks.len());
+    }
+
+    // A manager over `layout`, with the initial block list it points into
+    struct Fixture {
+        arena: Arena,
+        layout: Vec<RAMDescriptor>,
+        _blocks: Vec<RAMBlock>,
+        rbm: RAMBlockManager
+    }
+
+    fn fixture() -> Fixture {
+        let arena = Arena::new(16384);
+        let mut layout = layout(&arena);
+        let mut blocks = vec![RAMBlock::new_invalid(); 16];
+        let mut rbm = manager(&mut blocks);
+        rbm.init_with(&mut layout);
+        return Fixture { arena, layout, _blocks: blocks, rbm };
+    }
+
+    fn meta_pages() -> usize {
+        return align_up(Buddy::meta_size(RANGES.len(), CONVENTIONAL_PAGES), PAGE) / PAGE;
+    }
+
+    fn blocks_sorted(rbm: &RAMBlockManager) -> bool {
+        let blocks: Vec<_> = rbm.blocks_iter().collect();
+        return blocks.windows(2).all(|pair| pair[0].addr() + pair[0].size() <= pair[1].addr());
+    }
+
+    #[test]
+    fn init_builds_map_and_zones() {
+        let mut fixture = fixture();
+        let (arena, rbm) = (&fixture.arena, &mut fixture.rbm);
+
+        assert!(fixture.layout.windows(2).all(|pair| pair[0].phys_start < pair[1].phys_start));
+        assert_eq!(rbm.total(), (CONVENTIONAL_PAGES - meta_pages()) * PAGE);
+        assert_eq!(rbm.available(), rbm.total());
+
+        // Frame array at the start of the lowest range, then the firmware blocks
+        let map: Vec<_> = rbm.blocks_iter().map(|block| (block.addr(), block.size(), block.ty())).collect();
+        assert_eq!(map, vec![
+            (arena.addr(0), 16 * PAGE, ramtype::RESERVED),
+            (arena.addr(16), meta_pages() * PAGE, ramtype::KERNEL_DATA),
+            (arena.addr(4096), 8 * PAGE, ramtype::ACPI_RECLAIM),
+            (arena.addr(12000), 100 * PAGE, ramtype::RUNTIME_SERVICES_DATA)
+        ]);
+    }
+
+    #[test]
+    fn alloc_is_aligned_and_inside_conventional() {
+        let mut fixture = fixture();
+        let (arena, rbm) = (&fixture.arena, &mut fixture.rbm);
+
+        let meta_end = arena.addr(16 + meta_pages());
+        for (pages, align) in [(1, 1), (3, 1), (5, 8), (1, 64), (700, 1), (2, 512), (5000, 1)] {
+            let ptr = rbm.alloc(AllocParams::new(pages * PAGE).align(align * PAGE)).unwrap();
+            assert_eq!(ptr.size(), align_up(pages * PAGE, align * PAGE));
+            assert_eq!(ptr.addr() % (align * PAGE), 0);
+            assert!(ptr.addr() >= meta_end || ptr.addr() + ptr.size() <= arena.addr(16));
+            assert!(RANGES.iter().any(|&(start, end)|
+                ptr.addr() >= arena.addr(start) && ptr.addr() + ptr.size() <= arena.addr(end)
+            ));
+        }
+        assert!(rbm.alloc(AllocParams::new(CONVENTIONAL_PAGES * PAGE)).is_none());
+    }
+
+    #[test]
+    fn at_claims_exact_pages() {
+        let mut fixture = fixture();
+        let (arena, rbm) = (&fixture.arena, &mut fixture.rbm);
+        let total = rbm.total();
+
+        let at = arena.addr(5001) as *mut u8;
+        let ptr = rbm.alloc(AllocParams::new(3 * PAGE).at(at)).unwrap();
+        assert_eq!(ptr.addr(), at as usize);
+        assert_eq!(rbm.available(), total - 3 * PAGE);
+        assert!(rbm.alloc(AllocParams::new(PAGE).at(arena.addr(5003) as *mut u8)).is_none());
+        assert!(rbm.alloc(AllocParams::new(PAGE).at(arena.addr(4097) as *mut u8)).is_none());
+        assert!(rbm.alloc(AllocParams::new(2 * PAGE).at(arena.addr(4095) as *mut u8)).is_none());
+
+        rbm.free(ptr).unwrap();
+        assert_eq!(rbm.available(), total);
+        assert!(rbm.alloc(AllocParams::new(PAGE).at(arena.addr(5003) as *mut u8)).is_some());
+    }
+
+    #[test]
+    fn free_rejects_double_and_foreign() {
+        let mut fixture = fixture();
+        let (arena, rbm) = (&fixture.arena, &mut fixture.rbm);
+
+        let ptr = rbm.alloc(AllocParams::new(4 * PAGE)).unwrap();
+        let addr = ptr.addr();
+        rbm.free(ptr).unwrap();
+        assert_eq!(rbm.free(RBPtr::new(addr as *const u8, 4 * PAGE)), Err(FreeError::NotAllocated(addr)));
+
+        // Past the end of an allocation: nothing may be freed at all
+        let ptr = rbm.alloc(AllocParams::new(2 * PAGE)).unwrap();
+        let available = rbm.available();
+        assert!(rbm.free(RBPtr::new(ptr.ptr::<u8>(), 3 * PAGE)).is_err());
+        assert_eq!(rbm.available(), available);
+
+        // Even when the next allocation follows right after it
+        let a = rbm.alloc(AllocParams::new(2 * PAGE).at(arena.addr(6000) as *mut u8)).unwrap();
+        let b = rbm.alloc(AllocParams::new(2 * PAGE).at(arena.addr(6002) as *mut u8)).unwrap();
+        let available = rbm.available();
+        assert_eq!(rbm.free(RBPtr::new(a.ptr::<u8>(), 3 * PAGE)), Err(FreeError::Overrun(b.addr())));
+        assert_eq!(rbm.free(RBPtr::new((a.addr() + PAGE) as *const u8, 2 * PAGE)), Err(FreeError::Overrun(b.addr())));
+        assert_eq!(rbm.available(), available);
+        rbm.free(a).unwrap();
+        rbm.free(b).unwrap();
+
+        let firmware = arena.addr(4096);
+        assert_eq!(rbm.free(RBPtr::new(firmware as *const u8, PAGE)), Err(FreeError::Unmanaged(firmware)));
+        let meta = arena.addr(16);
+        assert_eq!(rbm.free(RBPtr::new(meta as *const u8, PAGE)), Err(FreeError::Unmanaged(meta)));
+    }
+
+    #[test]
+    fn partial_free_keeps_the_rest() {
+        let mut fixture = fixture();
+        let rbm = &mut fixture.rbm;
+        let total = rbm.total();
+
+        let ptr = rbm.alloc(AllocParams::new(8 * PAGE)).unwrap();
+        let middle = RBPtr::new((ptr.addr() + 3 * PAGE) as *const u8, 2 * PAGE);
+        rbm.free(middle).unwrap();
+        assert_eq!(rbm.available(), total - 6 * PAGE);
+        assert!(rbm.free(RBPtr::new((ptr.addr() + 4 * PAGE) as *const u8, PAGE)).is_err());
+
+        rbm.free(RBPtr::new(ptr.ptr::<u8>(), 3 * PAGE)).unwrap();
+        rbm.free(RBPtr::new((ptr.addr() + 5 * PAGE) as *const u8, 3 * PAGE)).unwrap();
+        assert_eq!(rbm.available(), total);
+    }
+
+    // Model check: random alloc, at, and whole or partial frees against a list of
+    // what should be allocated, checking the invariants after every step
+    #[test]
+    fn random_operations_keep_invariants() {
+        for seed in [0x2545f491, 0x9e3779b9, 0xdeadbeef, 0x12345678] {
+            let mut fixture = fixture();
+            let (arena, rbm) = (&fixture.arena, &mut fixture.rbm);
+            let (total, meta_end) = (rbm.total(), arena.addr(16 + meta_pages()));
+            let initial_blocks: Vec<_> = (0..=MAX_ORDER).map(|order| rbm.buddy.free_blocks(order)).collect();
+
+            let mut state: u64 = seed;
+            let mut model: Vec<(usize, usize)> = Vec::new(); // Address and pages
+            for step in 0..20000 {
+                let roll = rng(&mut state);
+                if roll % 3 != 0 || model.is_empty() {
+                    let pages = match roll % 8 {
+                        0 => (roll >> 8) as usize % 3000 + 1,
+                        1 => (roll >> 8) as usize % 200 + 1,
+                        _ => (roll >> 8) as usize % 8 + 1
+                    };
+                    let align = 1 << ((roll >> 32) % 5);
+                    let mut args = AllocParams::new(pages * PAGE).align(align * PAGE);
+                    if roll % 13 == 0 {
+                        let page = (roll >> 40) as usize % 16384;
+                        args = args.at(arena.addr(page & !(align - 1)) as *mut u8);
+                    }
+                    let Some(ptr) = rbm.alloc(args) else { continue; };
+                    let (addr, pages) = (ptr.addr(), ptr.size() / PAGE);
+                    assert_eq!(addr % (align * PAGE), 0, "step {}", step);
+                    assert!(addr >= meta_end, "step {}: {:#x} inside the frame array", step, addr);
+                    assert!(RANGES.iter().any(|&(start, end)|
+                        addr >= arena.addr(start) && addr + pages * PAGE <= arena.addr(end)
+                    ), "step {}: {:#x} outside conventional RAM", step, addr);
+                    assert!(model.iter().all(|&(other, len)|
+                        addr + pages * PAGE <= other || other + len * PAGE <= addr
+                    ), "step {}: {:#x} handed out twice", step, addr);
+                    model.push((addr, pages));
+                } else {
+                    let (addr, pages) = model.swap_remove((roll >> 16) as usize % model.len());
+                    if roll % 2 == 0 && pages > 2 {
+                        let skip = (roll >> 24) as usize % (pages - 2) + 1;
+                        let len = (roll >> 40) as usize % (pages - skip - 1) + 1;
+                        rbm.free(RBPtr::new((addr + skip * PAGE) as *const u8, len * PAGE)).unwrap();
+                        model.push((addr, skip));
+                        model.push((addr + (skip + len) * PAGE, pages - skip - len));
+                    } else {
+                        rbm.free(RBPtr::new(addr as *const u8, pages * PAGE)).unwrap();
+                        assert!(rbm.free(RBPtr::new(addr as *const u8, PAGE)).is_err(), "step {}", step);
+                    }
+                }
+                let used: usize = model.iter().map(|&(_, pages)| pages * PAGE).sum();
+                assert_eq!(rbm.available() + used, total, "step {}", step);
+            }
+
+            for (addr, pages) in model.drain(..) {
+                rbm.free(RBPtr::new(addr as *const u8, pages * PAGE)).unwrap();
+            }
+            assert_eq!(rbm.available(), total);
+
+            // Everything merged back into the blocks it started as
+            let free_blocks: Vec<_> = (0..=MAX_ORDER).map(|order| rbm.buddy.free_blocks(order)).collect();
+            assert_eq!(free_blocks, initial_blocks);
+        }
+    }
+
+    #[test]
+    fn expand_keeps_blocks_sorted() {
+        // Every other run of pages is firmware, more of them than the initial list holds
+        let arena = Arena::new(1024);
+        let mut layout: Vec<_> = (0..32).map(|idx|
+            arena.desc(if idx % 2 == 0 { ramtype::CONVENTIONAL } else { ramtype::MMIO }, idx * 32, 32)
+        ).collect();
+        let mut blocks = [RAMBlock::new_invalid(); 4];
+        let mut rbm = manager(&mut blocks);
+        rbm.init_with(&mut layout);
+
+        assert_eq!(rbm.count(), 17);
+        assert!(rbm.owned && rbm.max >= 17);
+        assert!(blocks_sorted(&rbm));
+        let list_pages = |max: usize| (max * size_of::<RAMBlock>()).div_ceil(PAGE);
+        assert_eq!(rbm.available(), rbm.total() - list_pages(rbm.max) * PAGE);
+
+        // Growing again gives the previous list back
+        let max = rbm.max * 4;
+        rbm.expand(max);
+        assert_eq!(rbm.max, max);
+        assert_eq!(rbm.count(), 17);
+        assert!(blocks_sorted(&rbm));
+        assert_eq!(rbm.available(), rbm.total() - list_pages(max) * PAGE);
+    }
+
+    #[test]
+    fn add_merges_neighbours() {
+        let mut blocks = [RAMBlock::new_invalid(); 8];
+        let mut rbm = manager(&mut blocks);
+        rbm.add(0x1000 as *const u8, 0x1000, ramtype::MMIO, true, owner::FIRMWARE);
+        rbm.add(0x5000 as *const u8, 0x1000, ramtype::MMIO, true, owner::FIRMWARE);
+        rbm.add(0x9000 as *const u8, 0x1000, ramtype::MMIO, true, owner::FIRMWARE);
+        rbm.add(0x2000 as *const u8, 0x3000, ramtype::MMIO, true, owner::FIRMWARE); // Joins the first two
+        assert_eq!(rbm.count(), 2);
+        rbm.add(0xc000 as *const u8, 0x1000, ramtype::MMIO, true, owner::FIRMWARE); // Must not land in the hole
+        rbm.add(0x7000 as *const u8, 0x1000, ramtype::RESERVED, true, owner::FIRMWARE);
+        let map: Vec<_> = rbm.blocks_iter().map(|block| (block.addr(), block.size())).collect();
+        assert_eq!(map, vec![(0x1000, 0x5000), (0x7000, 0x1000), (0x9000, 0x1000), (0xc000, 0x1000)]);
+    }
+
+    #[test]
+    fn map_walk_covers_layout_and_stats_count() {
+        let mut fixture = fixture();
+        let (arena, rbm) = (&fixture.arena, &mut fixture.rbm);
+
+        let a = rbm.alloc(AllocParams::new(3 * PAGE).as_type(ramtype::PAGE_TABLE)).unwrap();
+        let b = rbm.alloc(AllocParams::new(40 * PAGE).as_type(ramtype::KERNEL_DATA)).unwrap();
+        assert!(rbm.alloc(AllocParams::new(CONVENTIONAL_PAGES * PAGE)).is_none());
+        rbm.free(a).unwrap();
+
+        // The walk tiles the layout in address order
+        let mut walk = Vec::new();
+        rbm.for_each_block(|block, reserved| walk.push((block.addr(), block.size(), block.ty(), block.used(), reserved)));
+        assert!(walk.windows(2).all(|pair| pair[0].0 + pair[0].1 == pair[1].0));
+        assert_eq!(walk[0].0, arena.addr(0));
+        assert_eq!(walk.last().map(|last| last.0 + last.1), Some(arena.addr(16384)));
+        assert!(walk.iter().all(|&(_, _, ty, used, reserved)| reserved || used || ty == ramtype::CONVENTIONAL));
+        assert!(walk.contains(&(b.addr(), b.size(), ramtype::KERNEL_DATA, true, false)));
+        assert!(!walk.iter().any(|&(_, _, ty, _, _)| ty == ramtype::PAGE_TABLE));
+        let free: usize = walk.iter().filter(|&&(_, _, _, used, _)| !used).map(|&(_, size, ..)| size).sum();
+        assert_eq!(free, rbm.available());
+
+        let stats = rbm.stats();
+        assert_eq!((stats.allocs, stats.frees, stats.failed), (2, 1, 1));
+        assert_eq!(stats.peak_used, 43 * PAGE);
+        assert_eq!(stats.total - stats.available, 40 * PAGE);
+    }
+
+    #[test]
+    fn owners_are_tracked_and_capped() {
+        let mut fixture = fixture();
+        let rbm = &mut fixture.rbm;
+        rbm.owners[owner::NVME as usize].quota = Some(10 * PAGE);
+
+        let a = rbm.alloc(AllocParams::new(4 * PAGE).owner(owner::NVME)).unwrap();
+        let b = rbm.alloc(AllocParams::new(PAGE + 1).align(8).owner(owner::NVME)).unwrap();
+        let c = rbm.alloc(AllocParams::new(2 * PAGE).owner(owner::PAGE_TABLE)).unwrap();
+        assert_eq!(rbm.owners[owner::NVME as usize].used, 6 * PAGE);
+        assert!(rbm.alloc(AllocParams::new(5 * PAGE).owner(owner::NVME)).is_none());
+        assert!(rbm.alloc(AllocParams::new(5 * PAGE).owner(owner::HEAP)).is_some());
+        assert_eq!(rbm.stats().failed, 1);
+
+        // Neighbouring allocations of different owners stay apart in the walk
+        let mut runs = Vec::new();
+        rbm.for_each_block(|block, reserved| if !reserved && block.used() { runs.push((block.addr(), block.owner())); });
+        assert!(runs.contains(&(a.addr(), owner::NVME)));
+        assert!(runs.contains(&(c.addr(), owner::PAGE_TABLE)));
+
+        rbm.free(a).unwrap();
+        rbm.free(b).unwrap();
+        assert_eq!(rbm.owners[owner::NVME as usize].used, 0);
+        assert_eq!(rbm.owners[owner::NVME as usize].peak, 6 * PAGE);
+        assert_eq!(rbm.owners[owner::PAGE_TABLE as usize].used, 2 * PAGE);
+        assert!(rbm.alloc(AllocParams::new(10 * PAGE).owner(owner::NVME)).is_some());
+    }
+}
@@ -0,0 +1,253 @@
+// Slab allocator for fixed-size objects. A cache hands out objects of one size from
+// slabs: naturally aligned runs of SLAB_PAGES pages from ramblock, with a header at
+// the start and free objects chained through themselves. Each slab is on the
+// partial or full list of its cache; one empty slab is kept back, and any further
+// empty ones return to ramblock.
+//
+// The kmalloc caches serve the global allocator's small requests. Others are
+// statics named after what they hold, registered with the listing when they first
+// take a slab:
+//
+//     static INODE_CACHE: KmemCache = KmemCache::new("inode", size_of::<Inode>(), align_of::<Inode>());
+use crate::{
+    ember::ramtype, printlnk,
+    ram::PAGE_4KIB,
+    ramblock::{self, owner, AllocParams}
+};
+use core::{alloc::Layout, ptr::{null_mut, NonNull}};
+use spin::Mutex;
+
+const SLAB_PAGES: usize = 4;
+const SLAB_BYTES: usize = SLAB_PAGES * PAGE_4KIB;
+const MAX_NAMED: usize = 32;
+
+/// Largest request the kmalloc caches take; bigger ones go to the heap arenas.
+pub const KMALLOC_MAX: usize = 2048;
+
+#[repr(C)]
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    cache: *const KmemCache,
+    free: *mut FreeObject,
+    in_use: usize
+}
+
+struct FreeObject {
+    next: *mut FreeObject
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub per_slab: usize,
+    pub in_use: usize,   // Objects handed out
+    pub slabs: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    pub grown: usize,    // Slabs taken from ramblock
+    pub released: usize  // Slabs given back
+}
+
+struct Lists {
+    partial: *mut Slab,
+    full: *mut Slab,
+    empty: *mut Slab, // At most one, kept for the next allocation
+    registered: bool,
+    in_use: usize,
+    slabs: usize,
+    allocs: usize,
+    frees: usize,
+    grown: usize,
+    released: usize
+}
+
+unsafe impl Send for Lists {}
+
+pub struct KmemCache {
+    name: &'static str,
+    size: usize,
+    stride: usize,  // Distance between objects
+    offset: usize,  // First object, past the header
+    per_slab: usize,
+    lists: Mutex<Lists>
+}
+
+impl KmemCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        // Free objects hold a pointer, so none is smaller or less aligned than one
+        let align = if align > align_of::<FreeObject>() { align } else { align_of::<FreeObject>() };
+        let size_min = if size > size_of::<FreeObject>() { size } else { size_of::<FreeObject>() };
+        let stride = size_min.next_multiple_of(align);
+        let offset = size_of::<Slab>().next_multiple_of(align);
+        assert!(align <= SLAB_BYTES && offset + stride <= SLAB_BYTES, "object too large for a slab");
+        return Self {
+            name, size, stride, offset,
+            per_slab: (SLAB_BYTES - offset) / stride,
+            lists: Mutex::new(Lists {
+                partial: null_mut(), full: null_mut(), empty: null_mut(), registered: false,
+                in_use: 0, slabs: 0, allocs: 0, frees: 0, grown: 0, released: 0
+            })
+        };
+    }
+
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        let mut lists = self.lists.lock();
+        if lists.partial.is_null() {
+            let slab = if !lists.empty.is_null() {
+                core::mem::replace(&mut lists.empty, null_mut())
+            } else {
+                let slab = self.grow()?;
+                lists.slabs += 1;
+                lists.grown += 1;
+                if !lists.registered { lists.registered = register(self); }
+                slab
+            };
+            unsafe { push(&mut lists.partial, slab); }
+        }
+
+        let slab = lists.partial;
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                unlink(&mut lists.partial, slab);
+                push(&mut lists.full, slab);
+            }
+            lists.in_use += 1;
+            lists.allocs += 1;
+            return NonNull::new(object.cast());
+        }
+    }
+
+    /// Returns an object to the cache. `ptr` must have come from `alloc` of this cache.
+    pub unsafe fn free(&'static self, ptr: NonNull<u8>) {
+        let slab = (ptr.as_ptr() as usize & !(SLAB_BYTES - 1)) as *mut Slab;
+        let mut lists = self.lists.lock();
+        unsafe {
+            if (*slab).cache != self as *const _ {
+                panic!("{:p} freed to slab cache {}, which did not hand it out", ptr, self.name);
+            }
+            let object = ptr.as_ptr() as *mut FreeObject;
+            if (*slab).free.is_null() {
+                unlink(&mut lists.full, slab);
+                push(&mut lists.partial, slab);
+            }
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+            lists.in_use -= 1;
+            lists.frees += 1;
+
+            if (*slab).in_use != 0 { return; }
+            unlink(&mut lists.partial, slab);
+            if lists.empty.is_null() {
+                lists.empty = slab;
+                return;
+            }
+        }
+        if let Err(err) = unsafe { ramblock::free_raw(slab as *const u8, SLAB_BYTES) } {
+            panic!("slab at {:p} of cache {} was not allocated: {}", slab, self.name, err);
+        }
+        lists.slabs -= 1;
+        lists.released += 1;
+    }
+
+    /// Takes a slab from ramblock and chains all of its objects on the free list.
+    fn grow(&'static self) -> Option<*mut Slab> {
+        let args = AllocParams::new(SLAB_BYTES).align(SLAB_BYTES)
+            .as_type(ramtype::KERNEL_DATA).owner(owner::SLAB);
+        let base = ramblock::alloc(args)?.ptr::<u8>();
+        let mut free = null_mut();
+        for idx in (0..self.per_slab).rev() {
+            let object = unsafe { base.add(self.offset + idx * self.stride) } as *mut FreeObject;
+            unsafe { (*object).next = free; }
+            free = object;
+        }
+        let slab = base as *mut Slab;
+        unsafe { slab.write(Slab { next: null_mut(), prev: null_mut(), cache: self, free, in_use: 0 }); }
+        return Some(slab);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lists = self.lists.lock();
+        return CacheStats {
+            name: self.name,
+            object_size: self.size,
+            per_slab: self.per_slab,
+            in_use: lists.in_use,
+            slabs: lists.slabs,
+            allocs: lists.allocs,
+            frees: lists.frees,
+            grown: lists.grown,
+            released: lists.released
+        };
+    }
+}
+
+unsafe fn push(head: &mut *mut Slab, slab: *mut Slab) {
+    unsafe {
+        (*slab).prev = null_mut();
+        (*slab).next = *head;
+        if !head.is_null() { (**head).prev = slab; }
+    }
+    *head = slab;
+}
+
+unsafe fn unlink(head: &mut *mut Slab, slab: *mut Slab) {
+    unsafe {
+        let (next, prev) = ((*slab).next, (*slab).prev);
+        if !prev.is_null() { (*prev).next = next; } else { *head = next; }
+        if !next.is_null() { (*next).prev = prev; }
+    }
+}
+
+static KMALLOC: [KmemCache; 8] = [
+    KmemCache::new("kmalloc-16", 16, 16),
+    KmemCache::new("kmalloc-32", 32, 32),
+    KmemCache::new("kmalloc-64", 64, 64),
+    KmemCache::new("kmalloc-128", 128, 128),
+    KmemCache::new("kmalloc-256", 256, 256),
+    KmemCache::new("kmalloc-512", 512, 512),
+    KmemCache::new("kmalloc-1024", 1024, 1024),
+    KmemCache::new("kmalloc-2048", 2048, 2048)
+];
+
+static NAMED: Mutex<[Option<&'static KmemCache>; MAX_NAMED]> = Mutex::new([None; MAX_NAMED]);
+
+/// Lists a named cache; the kmalloc caches are always listed. False if the listing is full.
+fn register(cache: &'static KmemCache) -> bool {
+    if KMALLOC.iter().any(|kmalloc| core::ptr::eq(kmalloc, cache)) { return true; }
+    let mut named = NAMED.lock();
+    let Some(slot) = named.iter_mut().find(|slot| slot.is_none()) else { return false; };
+    *slot = Some(cache);
+    return true;
+}
+
+/// The kmalloc cache for `layout`: power-of-two sizes hold objects aligned to
+/// their size, which covers any alignment up to it.
+pub fn kmalloc_cache(layout: Layout) -> Option<&'static KmemCache> {
+    let size = layout.size().max(layout.align()).max(16).next_power_of_two();
+    if size > KMALLOC_MAX { return None; }
+    return KMALLOC.get(size.trailing_zeros() as usize - 4);
+}
+
+pub fn for_each_cache(mut f: impl FnMut(CacheStats)) {
+    for cache in &KMALLOC { f(cache.stats()); }
+    let named = *NAMED.lock();
+    for cache in named.iter().flatten() { f(cache.stats()); }
+}
+
+pub fn dump() {
+    printlnk!("Slab caches:");
+    for_each_cache(|stats| {
+        if stats.grown == 0 { return; }
+        printlnk!(
+            "  {:<14} {:>5} B  {:>7} in use  {:>5} slabs of {:>3}  {} allocs, {} frees, {} slabs released",
+            stats.name, stats.object_size, stats.in_use, stats.slabs, stats.per_slab,
+            stats.allocs, stats.frees, stats.released
+        );
+    });
+}
@@ -1,5 +1,7 @@
 use core::cmp::Ordering;
 
+const RUN: usize = 16; // Insertion-sorted before merging
+
 pub trait HeaplessSort<T> {
     fn sort_noheap(&mut self) where T: Ord;
     fn sort_noheap_by<F>(&mut self, cmp: F) where F: FnMut(&T, &T) -> Ordering;
@@ -15,7 +17,7 @@ impl<T> HeaplessSort<T> for &mut [T] {
     fn sort_noheap_by<F>(&mut self, mut cmp: F)
     where F: FnMut(&T, &T) -> Ordering {
         if self.len() <= 1 { return; }
-        block_merge_sort(self, &mut cmp);
+        merge_sort(self, &mut cmp);
     }
 
     fn sort_noheap_by_key<F, K>(&mut self, mut key: F)
@@ -24,89 +26,51 @@ impl<T> HeaplessSort<T> for &mut [T] {
     }
 }
 
-fn block_merge_sort<T, F>(arr: &mut [T], cmp: &mut F)
+fn merge_sort<T, F>(arr: &mut [T], cmp: &mut F)
 where F: FnMut(&T, &T) -> Ordering {
     let len = arr.len();
-    if len <= 16 { insertion_sort(arr, cmp); return; }
-    let block_size = isqrt(len).max(16);
+    for run in arr.chunks_mut(RUN) { insertion_sort(run, cmp); }
 
-    let mut width = 1;
+    let mut width = RUN;
     while width < len {
         let mut start = 0;
-        while start < len {
-            let mid = (start + width).min(len);
+        while start + width < len {
             let end = (start + 2 * width).min(len);
-            if mid < end { block_merge(arr, start, mid, end, block_size, cmp); }
+            merge(&mut arr[start..end], width, cmp);
             start += 2 * width;
         }
         width *= 2;
     }
 }
 
-fn block_merge<T, F>(arr: &mut [T], mut start: usize, mut mid: usize, end: usize, block_size: usize, cmp: &mut F)
-where F: FnMut(&T, &T) -> Ordering {
-    loop {
-        let left_len = mid - start;
-        let right_len = end - mid;
-        if left_len == 0 || right_len == 0 { return };
-
-        let mut left_pos = start;
-        let mut right_pos = mid;
-        let mut should_break = true;
-
-        while left_pos < mid && right_pos < end {
-            let left_block_end = (left_pos + block_size).min(mid);
-            let right_block_end = (right_pos + block_size).min(end);
-
-            if cmp(&arr[left_pos], &arr[right_pos]) != Ordering::Greater { left_pos = left_block_end; }
-            else {
-                rotate_merge(arr, left_pos, right_pos, right_block_end, cmp);
-                let moved_len = right_block_end - right_pos;
-                left_pos += moved_len;
-                right_pos = right_block_end;
-                let new_mid = mid + moved_len;
-                if new_mid <= end {
-                    (start, mid, should_break) = (left_pos, new_mid, false);
-                    break;
-                }
-            }
-        }
-
-        if should_break { break };
-    }
-}
-
-fn rotate_merge<T, F>(arr: &mut [T], start: usize, mid: usize, end: usize, cmp: &mut F)
+// Stable merge of arr[..mid] and arr[mid..] without a buffer: split both halves
+// around a pivot, rotate the middle pieces past each other, and recurse
+fn merge<T, F>(arr: &mut [T], mid: usize, cmp: &mut F)
 where F: FnMut(&T, &T) -> Ordering {
-    let mut left = start;
-    let mut right = mid;
-
-    while left < right && right < end {
-        while left < right && cmp(&arr[left], &arr[right]) != Ordering::Greater { left += 1; }
-        if left == right { break; }
-
-        let mut right_end = right + 1;
-        while right_end < end && cmp(&arr[left], &arr[right_end]) == Ordering::Greater { right_end += 1; }
-
-        reverse_range(arr, left, right);
-        reverse_range(arr, right, right_end);
-        reverse_range(arr, left, right_end);
-
-        let moved_len = right_end - right;
-        left += moved_len;
-        right = right_end;
+    let len = arr.len();
+    if mid == 0 || mid == len { return; }
+    if len == 2 {
+        if cmp(&arr[1], &arr[0]) == Ordering::Less { arr.swap(0, 1); }
+        return;
     }
-}
 
-fn reverse_range<T>(arr: &mut [T], start: usize, end: usize) {
-    if start >= end { return; }
-    let mut left = start;
-    let mut right = end - 1;
-    while left < right {
-        arr.swap(left, right);
-        left += 1;
-        right -= 1;
-    }
+    let (left, right) = arr.split_at(mid);
+    let (cut_left, cut_right) = if left.len() > right.len() {
+        // Right elements smaller than the pivot go before it
+        let cut_left = left.len() / 2;
+        let pivot = &left[cut_left];
+        (cut_left, mid + right.partition_point(|x| cmp(x, pivot) == Ordering::Less))
+    } else {
+        // Left elements not greater than the pivot stay before it
+        let cut_right = right.len() / 2;
+        let pivot = &right[cut_right];
+        (left.partition_point(|x| cmp(x, pivot) != Ordering::Greater), mid + cut_right)
+    };
+
+    arr[cut_left..cut_right].rotate_left(mid - cut_left);
+    let new_mid = cut_left + cut_right - mid;
+    merge(&mut arr[..new_mid], cut_left, cmp);
+    merge(&mut arr[new_mid..], cut_right - new_mid, cmp);
 }
 
 fn insertion_sort<T, F>(arr: &mut [T], cmp: &mut F)
@@ -119,11 +83,78 @@ where F: FnMut(&T, &T) -> Ordering {
     }
 }
 
-fn isqrt(n: usize) -> usize {
-    if n < 2 { return n; }
-    let mut x = 1 << ((n.ilog2() + 1) / 2);
-    loop {
-        let y = (x + n / x) / 2;
-        if y >= x { return x; } x = y;
+// Host tests: cargo test -p unix-v11-kernel from the workspace root
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::hosttest::rng;
+    use alloc::vec::Vec;
+
+    fn random_vec(state: &mut u64, len: usize, keys: u64) -> Vec<u64> {
+        return (0..len).map(|_| rng(state) % keys).collect();
     }
-}
\ No newline at end of file
+
+    // Lengths around the insertion-sorted run length, then larger ones
+    const LENGTHS: [usize; 14] = [0, 1, 2, 15, 16, 17, 31, 33, 64, 100, 257, 1000, 1025, 5000];
+
+    #[test]
+    fn matches_slice_sort() {
+        let mut state = 0x853c49e6748fea9b;
+        for len in LENGTHS {
+            for keys in [2, 10, 1000, u64::MAX] {
+                let mut ours = random_vec(&mut state, len, keys);
+                let mut expected = ours.clone();
+                (&mut ours[..]).sort_noheap();
+                expected.sort();
+                assert_eq!(ours, expected, "len {} keys {}", len, keys);
+            }
+        }
+    }
+
+    #[test]
+    fn is_stable() {
+        let mut state = 0xda3e39cb94b95bdb;
+        for len in LENGTHS {
+            for keys in [1, 3, 50] {
+                let mut ours: Vec<(u64, usize)> = random_vec(&mut state, len, keys).into_iter().zip(0..).collect();
+                let mut expected = ours.clone();
+                (&mut ours[..]).sort_noheap_by_key(|pair| pair.0);
+                expected.sort_by_key(|pair| pair.0);
+                assert_eq!(ours, expected, "len {} keys {}", len, keys);
+            }
+        }
+    }
+
+    #[test]
+    fn follows_the_comparator() {
+        let mut state = 0x9e3779b97f4a7c15;
+        for len in LENGTHS {
+            let mut ours = random_vec(&mut state, len, 100);
+            let mut expected = ours.clone();
+            (&mut ours[..]).sort_noheap_by(|a, b| b.cmp(a));
+            expected.sort_by(|a, b| b.cmp(a));
+            assert_eq!(ours, expected, "len {}", len);
+        }
+    }
+
+    #[test]
+    fn presorted_inputs() {
+        for len in LENGTHS {
+            let ascending: Vec<usize> = (0..len).collect();
+            let mut ours = ascending.clone();
+            (&mut ours[..]).sort_noheap();
+            assert_eq!(ours, ascending);
+
+            let mut ours: Vec<usize> = (0..len).rev().collect();
+            (&mut ours[..]).sort_noheap();
+            assert_eq!(ours, ascending);
+
+            // Sawtooth, which merges runs of equal length
+            let mut ours: Vec<usize> = (0..len).map(|idx| idx % 37).collect();
+            let mut expected = ours.clone();
+            (&mut ours[..]).sort_noheap();
+            expected.sort();
+            assert_eq!(ours, expected);
+        }
+    }
+}
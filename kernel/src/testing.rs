@@ -0,0 +1,222 @@
+// In-kernel test harness for `cargo test --target <arch>-unknown-none`.
+// flame() runs the collected #[test_case] functions once the machine is up,
+// then the result leaves QEMU as its exit status; see qemu-runner.sh.
+
+use crate::{arch, printk, printlnk};
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        printk!("{} ... ", core::any::type_name::<T>());
+        self();
+        printlnk!("[ok]");
+    }
+}
+
+pub fn run(tests: &[&dyn Testable]) {
+    printlnk!("Running {} tests", tests.len());
+    for test in tests { test.run(); }
+    printlnk!("test result: ok. {} passed", tests.len());
+    arch::exit_qemu(true);
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    printlnk!("[failed]");
+    printlnk!("{}", info);
+    arch::exit_qemu(false);
+}
+
+mod cases {
+    use crate::{
+        ember::ramtype, heap,
+        ram::{align_up, PageAligned, PAGE_4KIB},
+        ramblock::{self, owner, AllocParams},
+        slab::{self, KmemCache},
+        vmm::{self, prot, AddressSpace, VmError}
+    };
+    use alloc::{boxed::Box, vec::Vec};
+    use core::alloc::Layout;
+
+    #[test_case]
+    fn ramblock_alloc_free() {
+        let before = ramblock::available();
+        let args = AllocParams::new(0x10000).align(0x10000).as_type(ramtype::KERNEL_DATA).owner(owner::TEST);
+        let ptr = ramblock::alloc(args).expect("no memory for 64 KiB");
+        assert_eq!(ptr.addr() % 0x10000, 0);
+        unsafe {
+            core::ptr::write_bytes(ptr.ptr::<u8>(), 0xa5, ptr.size());
+            assert!(core::slice::from_raw_parts(ptr.ptr::<u8>(), ptr.size()).iter().all(|&b| b == 0xa5));
+        }
+        let (addr, size) = (ptr.ptr::<u8>(), ptr.size());
+        assert_eq!(ramblock::available(), before - size);
+        ramblock::free(ptr).unwrap();
+        assert_eq!(ramblock::available(), before);
+        assert!(unsafe { ramblock::free_raw(addr, size) }.is_err());
+    }
+
+    #[test_case]
+    fn heap_vec() {
+        let mut vec = Vec::new();
+        for i in 0..10000u32 { vec.push(i); }
+        assert_eq!(vec.iter().map(|&i| i as u64).sum::<u64>(), 49995000);
+    }
+
+    #[test_case]
+    fn heap_page_aligned() {
+        let mut buf = PageAligned::new(3 * PAGE_4KIB);
+        assert_eq!(buf.as_ptr() as usize % PAGE_4KIB, 0);
+        buf.fill(0x5a);
+        assert!(buf.iter().all(|&b| b == 0x5a));
+    }
+
+    #[test_case]
+    fn heap_grows_and_shrinks() {
+        let before = heap::stats();
+        let big = PageAligned::new(align_up(before.size, PAGE_4KIB));
+        let grown = heap::stats();
+        assert!(grown.size > before.size && grown.grown > before.grown);
+        drop(big);
+        let after = heap::stats();
+        assert_eq!(after.used, before.used);
+        // A new arena goes back once empty; growth in place stays
+        if grown.arenas > before.arenas { assert_eq!(after.arenas, before.arenas); }
+    }
+
+    // A second arena grown in place is two allocations in ramblock, and both go back
+    #[test_case]
+    fn heap_returns_an_arena_grown_in_place() {
+        let before = heap::stats();
+        let (_, top) = heap::arena(before.arenas - 1).unwrap();
+        let fence = ramblock::alloc(AllocParams::new(PAGE_4KIB).at(top as *mut u8).owner(owner::TEST));
+
+        let first = PageAligned::new(align_up(before.size, PAGE_4KIB));
+        let new_arena = heap::stats();
+        assert_eq!(new_arena.arenas, before.arenas + 1);
+        let second = PageAligned::new(align_up(new_arena.size, PAGE_4KIB));
+        let grown = heap::stats();
+        assert_eq!(grown.arenas, new_arena.arenas);
+        assert!(grown.grown > new_arena.grown);
+
+        let available = ramblock::available();
+        drop(second);
+        drop(first);
+        let after = heap::stats();
+        assert_eq!((after.arenas, after.returned), (before.arenas, before.returned + 1));
+        assert_eq!(ramblock::available(), available + (grown.size - before.size));
+        if let Some(fence) = fence { ramblock::free(fence).unwrap(); }
+    }
+
+    #[test_case]
+    fn slab_cache_reuses_and_releases() {
+        static CACHE: KmemCache = KmemCache::new("test", 200, 8);
+        let per_slab = CACHE.stats().per_slab;
+        let mut objects = Vec::new();
+        for _ in 0..3 * per_slab {
+            let ptr = CACHE.alloc().unwrap();
+            assert_eq!(ptr.as_ptr() as usize % 8, 0);
+            objects.push(ptr);
+        }
+        let full = CACHE.stats();
+        assert_eq!((full.in_use, full.slabs), (3 * per_slab, 3));
+
+        for ptr in objects.drain(..) { unsafe { CACHE.free(ptr); } }
+        let empty = CACHE.stats();
+        assert_eq!((empty.in_use, empty.slabs, empty.released), (0, 1, 2));
+        let again = CACHE.alloc().unwrap();
+        assert_eq!(CACHE.stats().grown, full.grown);
+        unsafe { CACHE.free(again); }
+    }
+
+    #[test_case]
+    fn small_boxes_come_from_kmalloc() {
+        let cache = slab::kmalloc_cache(Layout::new::<[u64; 8]>()).unwrap();
+        let before = cache.stats();
+        let boxes: Vec<_> = (0..100u64).map(|i| Box::new([i; 8])).collect();
+        assert_eq!(cache.stats().in_use, before.in_use + 100);
+        assert!(boxes.iter().enumerate().all(|(i, b)| b[7] == i as u64));
+        drop(boxes);
+        assert_eq!(cache.stats().in_use, before.in_use);
+    }
+
+    #[test_case]
+    fn address_space_maps_and_reclaims_tables() {
+        let tables = || ramblock::usage(owner::PAGE_TABLE).used;
+        let before = tables();
+        let frame = ramblock::alloc(AllocParams::new(PAGE_4KIB).owner(owner::TEST)).unwrap();
+        let mut space = AddressSpace::new(vmm::VMALLOC..vmm::VMALLOC + vmm::VMALLOC_SIZE).unwrap();
+        let virt = space.alloc_range(2 * PAGE_4KIB).unwrap();
+
+        space.map(virt, frame.addr(), PAGE_4KIB, prot::READ | prot::WRITE).unwrap();
+        assert_eq!(tables(), before + 4 * PAGE_4KIB);
+        assert_eq!(space.translate(virt + 0x123), Some(frame.addr() + 0x123));
+        assert_eq!(space.translate(virt + PAGE_4KIB), None);
+        assert_eq!(space.map(virt, frame.addr(), PAGE_4KIB, prot::READ), Err(VmError::Mapped(virt)));
+        space.protect(virt, PAGE_4KIB, prot::READ).unwrap();
+        assert_eq!(space.protect(virt, 2 * PAGE_4KIB, prot::READ), Err(VmError::NotMapped(virt + PAGE_4KIB)));
+
+        space.unmap(virt, PAGE_4KIB);
+        assert_eq!(space.translate(virt), None);
+        assert_eq!(tables(), before + PAGE_4KIB);
+        drop(space);
+        assert_eq!(tables(), before);
+        ramblock::free(frame).unwrap();
+    }
+
+    #[test_case]
+    fn vmm_block_splits_on_unmap() {
+        const BLOCK: usize = 0x200000;
+        let tables = || ramblock::usage(owner::PAGE_TABLE).used;
+        let before = tables();
+        let mut space = AddressSpace::new(vmm::VMALLOC..vmm::VMALLOC + vmm::VMALLOC_SIZE).unwrap();
+        let virt = align_up(space.alloc_range(2 * BLOCK).unwrap(), BLOCK);
+        let phys = 0x4000_0000; // Never touched, only translated
+
+        space.map_block(virt, phys, prot::READ | prot::WRITE).unwrap();
+        assert_eq!(tables(), before + 3 * PAGE_4KIB);
+        assert_eq!(space.translate(virt + 0x12345), Some(phys + 0x12345));
+        assert_eq!(space.map(virt + PAGE_4KIB, phys, PAGE_4KIB, prot::READ), Err(VmError::Mapped(virt + PAGE_4KIB)));
+        assert_eq!(space.map_block(virt, phys, prot::READ), Err(VmError::Mapped(virt)));
+
+        space.unmap(virt + PAGE_4KIB, PAGE_4KIB);
+        assert_eq!(tables(), before + 4 * PAGE_4KIB);
+        assert_eq!(space.translate(virt + PAGE_4KIB), None);
+        assert_eq!(space.translate(virt + 0x123), Some(phys + 0x123));
+        assert_eq!(space.translate(virt + BLOCK - 1), Some(phys + BLOCK - 1));
+        drop(space);
+        assert_eq!(tables(), before);
+    }
+
+    #[test_case]
+    fn stack_in_kernel_data() {
+        let stack = crate::arch::stack_ptr() as usize;
+        let base = crate::EMBER.lock().stack_base;
+        assert!(stack < base && base - stack < crate::ram::STACK_SIZE);
+    }
+
+    // The guard of the running stack, and of one made now, is gone from the live
+    // tables under both of its addresses; aarch64 has no kernel space yet
+    #[cfg(target_arch = "x86_64")]
+    #[test_case]
+    fn kernel_stack_guard_is_unmapped() {
+        let base = crate::EMBER.lock().stack_base;
+        let bottom = base - crate::ram::STACK_SIZE;
+        let stack = crate::ram::alloc_stack(PAGE_4KIB);
+        for (guard, bottom) in [(bottom - PAGE_4KIB, bottom), (stack.addr(), stack.addr() + PAGE_4KIB)] {
+            assert!(crate::ram::is_stack_guard(guard));
+            assert!(!crate::ram::is_stack_guard(bottom));
+            assert_eq!(vmm::translate(guard), None);
+            assert_eq!(vmm::translate(vmm::DIRECT_MAP + guard), None);
+            assert_eq!(vmm::translate(bottom), Some(bottom));
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test_case]
+    fn breakpoint_returns() {
+        x86_64::instructions::interrupts::int3();
+    }
+}
@@ -0,0 +1,532 @@
+// Virtual memory. An address space is a tree of four levels of 4 KiB tables,
+// shaped alike on amd64 and aarch64; the arch modules only encode entries and
+// switch roots. Leaves are 4 KiB pages, or 2 MiB blocks one level up, which
+// unmapping splits into pages. Tables come from ramblock, and go back to it when
+// unmapping clears their last entry.
+//
+// The kernel space keeps the identity map of RAM the kernel runs on, and adds
+// in the higher half:
+//
+//     DIRECT_MAP   0xffff_8000_0000_0000  all RAM, at DIRECT_MAP + phys
+//     VMALLOC      0xffff_c000_0000_0000  ranges handed out by alloc_range
+//
+// Scope: this is a kernel address space for amd64, not a higher-half kernel. The
+// image still runs where the loader relocated it; moving it up needs the loader
+// to relocate it for a higher base and enter it through these tables. amd64
+// switches to the kernel space on every boot, unless `nopaging` is on the command
+// line. aarch64 keeps the firmware's tables, without a direct map or guard pages,
+// until `activate` brings its MMU up.
+use crate::{
+    arch,
+    ember::{ramtype, segflag},
+    printlnk,
+    ram::{self, align_up, PAGE_4KIB},
+    ramblock::{self, owner, AllocParams},
+    EMBER
+};
+use core::{fmt, ops::Range, sync::atomic::{AtomicBool, Ordering}};
+use spin::Mutex;
+
+pub const DIRECT_MAP: usize      = 0xffff_8000_0000_0000;
+pub const DIRECT_MAP_SIZE: usize = 0x0000_4000_0000_0000;
+pub const VMALLOC: usize         = 0xffff_c000_0000_0000;
+pub const VMALLOC_SIZE: usize    = 0x0000_1000_0000_0000;
+
+pub mod prot {
+    pub const READ: u8   = 1 << 0;
+    pub const WRITE: u8  = 1 << 1;
+    pub const EXEC: u8   = 1 << 2;
+    pub const USER: u8   = 1 << 3;
+    pub const DEVICE: u8 = 1 << 4; // Uncached, for MMIO
+}
+
+const LEVELS: usize = 4;
+const BLOCK_LEVEL: usize = LEVELS - 2;
+const BLOCK_SIZE: usize = 0x200000;
+const ENTRIES: usize = 512;
+const MAX_RANGES: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmError {
+    NoMemory,
+    Misaligned(usize),
+    Mapped(usize),
+    NotMapped(usize)
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoMemory => write!(f, "no memory for a page table"),
+            Self::Misaligned(addr) => write!(f, "{:#x} is not page aligned", addr),
+            Self::Mapped(addr) => write!(f, "{:#x} is already mapped", addr),
+            Self::NotMapped(addr) => write!(f, "{:#x} is not mapped", addr)
+        }
+    }
+}
+
+fn index(level: usize, virt: usize) -> usize {
+    return (virt >> (39 - 9 * level)) & (ENTRIES - 1);
+}
+
+/// A table by its physical address, reached through the direct map once the
+/// kernel space is live.
+fn table(phys: usize) -> *mut u64 {
+    return phys_to_virt(phys) as *mut u64;
+}
+
+fn alloc_table() -> Result<usize, VmError> {
+    let args = AllocParams::new(PAGE_4KIB).as_type(ramtype::PAGE_TABLE).owner(owner::PAGE_TABLE);
+    let phys = ramblock::alloc(args).ok_or(VmError::NoMemory)?.addr();
+    unsafe { core::ptr::write_bytes(table(phys), 0, ENTRIES); }
+    return Ok(phys);
+}
+
+fn free_table(phys: usize) {
+    if let Err(err) = unsafe { ramblock::free_raw(phys as *const u8, PAGE_4KIB) } {
+        panic!("page table at {:#x} was not allocated: {}", phys, err);
+    }
+}
+
+fn is_empty(phys: usize) -> bool {
+    let entries = unsafe { core::slice::from_raw_parts(table(phys), ENTRIES) };
+    return entries.iter().all(|&entry| entry & arch::PTE_VALID == 0);
+}
+
+/// Free virtual ranges, sorted and coalesced, handed out first fit.
+struct VRanges {
+    free: [(usize, usize); MAX_RANGES],
+    count: usize
+}
+
+impl VRanges {
+    fn new(window: Range<usize>) -> Self {
+        let mut free = [(0, 0); MAX_RANGES];
+        free[0] = (window.start, window.end);
+        return Self { free, count: (window.start < window.end) as usize };
+    }
+
+    fn alloc(&mut self, size: usize) -> Option<usize> {
+        let idx = self.free[..self.count].iter().position(|&(start, end)| end - start >= size)?;
+        let addr = self.free[idx].0;
+        self.free[idx].0 += size;
+        if self.free[idx].0 == self.free[idx].1 {
+            self.free.copy_within(idx + 1..self.count, idx);
+            self.count -= 1;
+        }
+        return Some(addr);
+    }
+
+    /// False if the range overlaps a free one, or if it has to be dropped for
+    /// want of room in the table.
+    fn free(&mut self, addr: usize, size: usize) -> bool {
+        let end = addr + size;
+        let idx = self.free[..self.count].partition_point(|&(start, _)| start < addr);
+        let prev = idx.checked_sub(1).map(|prev| self.free[prev]);
+        let next = self.free[..self.count].get(idx).copied();
+        if prev.is_some_and(|(_, prev_end)| prev_end > addr) || next.is_some_and(|(next_start, _)| next_start < end) {
+            return false;
+        }
+
+        match (prev.is_some_and(|(_, prev_end)| prev_end == addr), next.is_some_and(|(next_start, _)| next_start == end)) {
+            (true, true) => {
+                self.free[idx - 1].1 = self.free[idx].1;
+                self.free.copy_within(idx + 1..self.count, idx);
+                self.count -= 1;
+            }
+            (true, false) => self.free[idx - 1].1 = end,
+            (false, true) => self.free[idx].0 = addr,
+            (false, false) => {
+                if self.count == MAX_RANGES { return false; }
+                self.free.copy_within(idx..self.count, idx + 1);
+                self.free[idx] = (addr, end);
+                self.count += 1;
+            }
+        }
+        return true;
+    }
+}
+
+pub struct AddressSpace {
+    root: usize,
+    ranges: VRanges
+}
+
+impl AddressSpace {
+    /// An empty space whose `alloc_range` hands out addresses in `window`.
+    pub fn new(window: Range<usize>) -> Result<Self, VmError> {
+        return Ok(Self { root: alloc_table()?, ranges: VRanges::new(window) });
+    }
+
+    pub fn root(&self) -> usize { self.root }
+
+    /// The entry for `virt` at `depth`; missing tables are made if `create`.
+    /// A block on the way holds `virt` already.
+    fn entry(&self, virt: usize, depth: usize, create: bool) -> Result<*mut u64, VmError> {
+        let mut phys = self.root;
+        for level in 0..depth {
+            let entry = unsafe { table(phys).add(index(level, virt)) };
+            if unsafe { *entry } & arch::PTE_VALID == 0 {
+                if !create { return Err(VmError::NotMapped(virt)); }
+                let next = alloc_table()?;
+                unsafe { *entry = arch::table_entry(next); }
+            } else if arch::is_block(unsafe { *entry }) {
+                return Err(VmError::Mapped(virt));
+            }
+            phys = unsafe { (*entry & arch::PTE_ADDR) as usize };
+        }
+        return Ok(unsafe { table(phys).add(index(depth, virt)) });
+    }
+
+    /// The last-level entry for `virt`; missing tables are made if `create`.
+    fn leaf(&self, virt: usize, create: bool) -> Result<*mut u64, VmError> {
+        return self.entry(virt, LEVELS - 1, create);
+    }
+
+    /// Maps the 2 MiB at `virt` to `phys` with one block; both must be 2 MiB aligned,
+    /// and nothing may be mapped there yet.
+    pub fn map_block(&mut self, virt: usize, phys: usize, prot: u8) -> Result<(), VmError> {
+        if !virt.is_multiple_of(BLOCK_SIZE) { return Err(VmError::Misaligned(virt)); }
+        if !phys.is_multiple_of(BLOCK_SIZE) { return Err(VmError::Misaligned(phys)); }
+        let entry = self.entry(virt, BLOCK_LEVEL, true)?;
+        unsafe {
+            if *entry & arch::PTE_VALID != 0 { return Err(VmError::Mapped(virt)); }
+            *entry = arch::block_entry(phys, prot);
+        }
+        arch::sync_tables();
+        return Ok(());
+    }
+
+    /// Replaces the block in `slot` with a table of its pages.
+    fn split(&mut self, slot: *mut u64) {
+        let block = unsafe { *slot };
+        let Ok(pages) = alloc_table() else { panic!("no memory to split a block of the kernel space"); };
+        let entries = unsafe { core::slice::from_raw_parts_mut(table(pages), ENTRIES) };
+        for (index, entry) in entries.iter_mut().enumerate() { *entry = arch::block_page(block, index); }
+        unsafe { *slot = arch::table_entry(pages); }
+        arch::sync_tables();
+    }
+
+    /// Maps `size` bytes at `virt` to `phys`. Nothing stays mapped on failure.
+    pub fn map(&mut self, virt: usize, phys: usize, size: usize, prot: u8) -> Result<(), VmError> {
+        if !virt.is_multiple_of(PAGE_4KIB) { return Err(VmError::Misaligned(virt)); }
+        if !phys.is_multiple_of(PAGE_4KIB) { return Err(VmError::Misaligned(phys)); }
+        for offset in (0..align_up(size, PAGE_4KIB)).step_by(PAGE_4KIB) {
+            let result = self.leaf(virt + offset, true).and_then(|entry| unsafe {
+                if *entry & arch::PTE_VALID != 0 { return Err(VmError::Mapped(virt + offset)); }
+                *entry = arch::leaf_entry(phys + offset, prot);
+                Ok(())
+            });
+            if let Err(err) = result {
+                self.unmap(virt, offset);
+                return Err(err);
+            }
+        }
+        arch::sync_tables();
+        return Ok(());
+    }
+
+    /// Unmaps `size` bytes at `virt`, skipping holes. Tables left empty are
+    /// freed, all but the root.
+    pub fn unmap(&mut self, virt: usize, size: usize) {
+        let start = virt & !(PAGE_4KIB - 1);
+        for page in (start..align_up(virt + size, PAGE_4KIB)).step_by(PAGE_4KIB) {
+            self.unmap_page(page);
+        }
+    }
+
+    fn unmap_page(&mut self, virt: usize) {
+        let mut path = [0; LEVELS];
+        let mut phys = self.root;
+        for level in 0..LEVELS {
+            path[level] = phys;
+            let slot = unsafe { table(phys).add(index(level, virt)) };
+            if unsafe { *slot } & arch::PTE_VALID == 0 { return; }
+            if level < LEVELS - 1 && arch::is_block(unsafe { *slot }) { self.split(slot); }
+            phys = (unsafe { *slot } & arch::PTE_ADDR) as usize;
+        }
+
+        unsafe { *table(path[LEVELS - 1]).add(index(LEVELS - 1, virt)) = 0; }
+        for level in (1..LEVELS).rev() {
+            if !is_empty(path[level]) { break; }
+            unsafe { *table(path[level - 1]).add(index(level - 1, virt)) = 0; }
+            free_table(path[level]);
+        }
+        arch::flush_page(virt);
+    }
+
+    /// Changes the protection of `size` bytes at `virt`, which must be mapped.
+    #[allow(dead_code)] // Only the tests change protection so far
+    pub fn protect(&mut self, virt: usize, size: usize, prot: u8) -> Result<(), VmError> {
+        let start = virt & !(PAGE_4KIB - 1);
+        for page in (start..align_up(virt + size, PAGE_4KIB)).step_by(PAGE_4KIB) {
+            let entry = self.leaf(page, false)?;
+            unsafe {
+                if *entry & arch::PTE_VALID == 0 { return Err(VmError::NotMapped(page)); }
+                *entry = arch::leaf_entry((*entry & arch::PTE_ADDR) as usize, prot);
+            }
+            arch::flush_page(page);
+        }
+        return Ok(());
+    }
+
+    pub fn translate(&self, virt: usize) -> Option<usize> {
+        let mut phys = self.root;
+        for level in 0..LEVELS {
+            let entry = unsafe { *table(phys).add(index(level, virt)) };
+            if entry & arch::PTE_VALID == 0 { return None; }
+            let span = match level {
+                BLOCK_LEVEL if arch::is_block(entry) => BLOCK_SIZE,
+                _ if level == LEVELS - 1 => PAGE_4KIB,
+                _ => { phys = (entry & arch::PTE_ADDR) as usize; continue; }
+            };
+            return Some((entry & arch::PTE_ADDR) as usize & !(span - 1) | (virt & (span - 1)));
+        }
+        return None;
+    }
+
+    /// Reserves `size` bytes of addresses in the window; nothing is mapped.
+    pub fn alloc_range(&mut self, size: usize) -> Option<usize> {
+        return self.ranges.alloc(align_up(size, PAGE_4KIB));
+    }
+
+    /// Returns a range from `alloc_range`. Whatever is mapped there stays so.
+    #[allow(dead_code)] // MMIO mappings are never torn down so far
+    pub fn free_range(&mut self, virt: usize, size: usize) {
+        if !self.ranges.free(virt, align_up(size, PAGE_4KIB)) {
+            printlnk!("vmm: lost range {:#x}+{:#x}", virt, size);
+        }
+    }
+}
+
+fn free_tree(phys: usize, level: usize) {
+    if level < LEVELS - 1 {
+        let entries = unsafe { core::slice::from_raw_parts(table(phys), ENTRIES) };
+        for &entry in entries.iter().filter(|&&entry| entry & arch::PTE_VALID != 0 && !arch::is_block(entry)) {
+            free_tree((entry & arch::PTE_ADDR) as usize, level + 1);
+        }
+    }
+    free_table(phys);
+}
+
+/// Frees the tables; the memory they mapped is the owner's to free.
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(self.root != arch::id_map_ptr() as usize, "dropped the live address space");
+        free_tree(self.root, 0);
+    }
+}
+
+static KERNEL_SPACE: Mutex<Option<AddressSpace>> = Mutex::new(None);
+static DIRECT_MAP_LIVE: AtomicBool = AtomicBool::new(false);
+
+/// Where the kernel reaches RAM at `phys`.
+pub fn phys_to_virt(phys: usize) -> usize {
+    if DIRECT_MAP_LIVE.load(Ordering::Acquire) { return DIRECT_MAP + phys; }
+    return phys;
+}
+
+fn prot_for(ty: u32) -> u8 {
+    use prot::*;
+    match ty {
+        ramtype::RUNTIME_SERVICES_CODE => READ | WRITE | EXEC,
+        ramtype::CONVENTIONAL
+        | ramtype::LOADER_CODE
+        | ramtype::LOADER_DATA
+        | ramtype::BOOT_SERVICES_CODE
+        | ramtype::BOOT_SERVICES_DATA
+        | ramtype::RUNTIME_SERVICES_DATA
+        | ramtype::ACPI_RECLAIM
+        | ramtype::DEVICE_TREE
+        | ramtype::INITRD
+        | ramtype::KERNEL_DATA
+        | ramtype::EMBER
+        | ramtype::EFI_RAM_LAYOUT
+        | ramtype::PAGE_TABLE => READ | WRITE,
+        _ => READ | WRITE | DEVICE
+    }
+}
+
+// text: R-X, rodata: R--, data/bss: RW-
+fn kernel_prot(seg_flags: u64) -> u8 {
+    let mut prot = prot::READ;
+    if seg_flags & segflag::W != 0 { prot |= prot::WRITE; }
+    if seg_flags & segflag::X != 0 { prot |= prot::EXEC; }
+    return prot;
+}
+
+const MMIO_PROT: u8 = prot::READ | prot::WRITE | prot::DEVICE;
+
+/// Identity-maps MMIO the kernel reaches by physical address; pages already
+/// mapped are left as they are.
+fn map_identity(space: &mut AddressSpace, phys: usize, size: usize) {
+    let start = phys & !(PAGE_4KIB - 1);
+    for page in (start..align_up(phys + size, PAGE_4KIB)).step_by(PAGE_4KIB) {
+        if space.translate(page).is_some() { continue; }
+        if let Err(err) = space.map(page, page, PAGE_4KIB, MMIO_PROT) {
+            panic!("cannot map MMIO at {:#x}: {}", page, err);
+        }
+    }
+}
+
+/// Maps `size` bytes of MMIO at `phys` into VMALLOC and returns where. Before
+/// the kernel space exists, MMIO is reached by its physical address.
+pub fn map_mmio(phys: usize, size: usize) -> usize {
+    let mut space = KERNEL_SPACE.lock();
+    let Some(space) = space.as_mut() else { return phys; };
+    let offset = phys & (PAGE_4KIB - 1);
+    let virt = space.alloc_range(offset + size).expect("VMALLOC is full");
+    if let Err(err) = space.map(virt, phys - offset, offset + size, MMIO_PROT) {
+        panic!("cannot map MMIO at {:#x}: {}", phys, err);
+    }
+    return virt + offset;
+}
+
+/// Takes the guard page of a new stack out of the kernel space, if there is one.
+pub fn unmap_guard(addr: usize) {
+    let mut space = KERNEL_SPACE.lock();
+    let Some(space) = space.as_mut() else { return; };
+    space.unmap(addr, PAGE_4KIB);
+    if addr < DIRECT_MAP_SIZE { space.unmap(DIRECT_MAP + addr, PAGE_4KIB); }
+}
+
+/// Where `virt` leads in the live kernel space; None before there is one.
+#[allow(dead_code)] // Only the tests look into the kernel space so far
+pub fn translate(virt: usize) -> Option<usize> {
+    return KERNEL_SPACE.lock().as_ref()?.translate(virt);
+}
+
+fn map_page(space: &mut AddressSpace, virt: usize, phys: usize, prot: u8) {
+    if let Err(err) = space.map(virt, phys, PAGE_4KIB, prot) { panic!("cannot build the kernel space: {}", err); }
+}
+
+/// Maps `start..end` at `offset + phys`, with 2 MiB blocks where they fit and
+/// pages elsewhere. Pages already mapped, as where descriptors share a page,
+/// are left as they are.
+fn map_run(space: &mut AddressSpace, offset: usize, start: usize, end: usize, prot: u8) {
+    let mut phys = start;
+    while phys < end {
+        if phys.is_multiple_of(BLOCK_SIZE) && phys + BLOCK_SIZE <= end && space.map_block(offset + phys, phys, prot).is_ok() {
+            phys += BLOCK_SIZE;
+            continue;
+        }
+        if !ram::is_stack_guard(phys) && space.translate(offset + phys).is_none() {
+            map_page(space, offset + phys, phys, prot);
+        }
+        phys += PAGE_4KIB;
+    }
+}
+
+/// Gathers touching descriptors of the same protection into one run, so blocks
+/// can span them.
+struct Runs {
+    offset: usize,
+    run: Option<(usize, usize, u8)>
+}
+
+impl Runs {
+    fn add(&mut self, space: &mut AddressSpace, start: usize, end: usize, prot: u8) {
+        if let Some((run_start, run_end, run_prot)) = self.run {
+            if run_prot == prot && (run_start..=run_end).contains(&start) {
+                self.run = Some((run_start, run_end.max(end), prot));
+                return;
+            }
+            self.flush(space);
+        }
+        self.run = Some((start, end, prot));
+    }
+
+    fn flush(&mut self, space: &mut AddressSpace) {
+        if let Some((start, end, prot)) = self.run.take() { map_run(space, self.offset, start, end, prot); }
+    }
+}
+
+/// Builds the kernel space and switches to it.
+pub fn init() {
+    if cfg!(target_arch = "aarch64") {
+        printlnk!("Paging: no kernel space on aarch64 yet, staying on the firmware's tables");
+        return;
+    }
+    let ember = EMBER.lock();
+    let mut space = AddressSpace::new(VMALLOC..VMALLOC + VMALLOC_SIZE).expect("no memory for the kernel space");
+
+    // The kernel image goes in page by page first, so its segments keep their
+    // own protection and no block covers them
+    for desc in ember.efi_ram_layout().iter().filter(|desc| desc.ty == ramtype::KERNEL) {
+        let start = desc.phys_start as usize;
+        for phys in (start..start + desc.page_count as usize * PAGE_4KIB).step_by(PAGE_4KIB) {
+            if ram::is_stack_guard(phys) || space.translate(phys).is_some() { continue; }
+            let prot = kernel_prot(ember.kernel_page_flags(phys as u64));
+            map_page(&mut space, phys, phys, prot);
+            if phys < DIRECT_MAP_SIZE { map_page(&mut space, DIRECT_MAP + phys, phys, prot & !prot::EXEC); }
+        }
+    }
+
+    let mut identity = Runs { offset: 0, run: None };
+    let mut direct = Runs { offset: DIRECT_MAP, run: None };
+    for desc in ember.efi_ram_layout().iter().filter(|desc| desc.ty != ramtype::KERNEL) {
+        let start = desc.phys_start as usize;
+        let end = start + desc.page_count as usize * PAGE_4KIB;
+        let prot = prot_for(desc.ty);
+        identity.add(&mut space, start, end, prot);
+        if prot & prot::DEVICE == 0 && start < DIRECT_MAP_SIZE {
+            direct.add(&mut space, start, end.min(DIRECT_MAP_SIZE), prot & !prot::EXEC);
+        }
+    }
+    identity.flush(&mut space);
+    direct.flush(&mut space);
+
+    // Blocks may have swept over guard pages
+    for guard in ram::guard_pages() {
+        space.unmap(guard, PAGE_4KIB);
+        if guard < DIRECT_MAP_SIZE { space.unmap(DIRECT_MAP + guard, PAGE_4KIB); }
+    }
+
+    // Firmware seldom lists the framebuffer in its memory map
+    if let Some(fb) = ember.framebuffer { map_identity(&mut space, fb.base as usize, fb.size as usize); }
+    for &(base, size) in arch::FIXED_MMIO { map_identity(&mut space, base, size); }
+    drop(ember);
+
+    let root = space.root();
+    *KERNEL_SPACE.lock() = Some(space);
+
+    unsafe { arch::activate(root); }
+    DIRECT_MAP_LIVE.store(true, Ordering::Release);
+    printlnk!(
+        "Paging: kernel space at {:#x}, {} KiB of page tables",
+        root, ramblock::usage(owner::PAGE_TABLE).used / 1024
+    );
+}
+
+// Pure, but vmm needs arch, so these run in QEMU
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ranges_are_first_fit_and_coalesce() {
+        let mut ranges = VRanges::new(0x10000..0x20000);
+        let a = ranges.alloc(0x1000).unwrap();
+        let b = ranges.alloc(0x3000).unwrap();
+        let c = ranges.alloc(0x1000).unwrap();
+        assert_eq!((a, b, c), (0x10000, 0x11000, 0x14000));
+        assert_eq!(ranges.alloc(0x10000), None);
+
+        assert!(ranges.free(b, 0x3000));
+        assert!(!ranges.free(b + 0x1000, 0x1000));
+        assert_eq!(ranges.alloc(0x2000), Some(b));
+        assert!(ranges.free(a, 0x1000));
+        assert!(ranges.free(b, 0x2000));
+        assert_eq!(ranges.count, 2);
+        assert!(ranges.free(c, 0x1000));
+        assert_eq!((ranges.count, ranges.free[0]), (1, (0x10000, 0x20000)));
+    }
+
+    #[test_case]
+    fn indices_follow_the_four_levels() {
+        let virt = DIRECT_MAP | (3 << 30) | (5 << 21) | (7 << 12) | 0x123;
+        assert_eq!([0, 1, 2, 3].map(|level| index(level, virt)), [256, 3, 5, 7]);
+        assert_eq!(index(0, 0xffff_ffff_8000_0000), 511);
+        assert_eq!(index(1, 0xffff_ffff_8000_0000), 510);
+    }
+}
@@ -0,0 +1,69 @@
+// COM1 and the VGA text screen; machines booting this way may lack either one.
+
+use core::{fmt, sync::atomic::{AtomicUsize, Ordering}};
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3f8;
+
+const VGA_TEXT: *mut u16 = 0xb8000 as *mut u16;
+const VGA_COLS: usize = 80;
+const VGA_ROWS: usize = 25;
+const VGA_ATTR: u16   = 0x0f00; // White on black
+
+static CURSOR: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init() {
+    unsafe {
+        Port::new(COM1 + 1).write(0x00u8); // Disable all interrupts
+        Port::new(COM1 + 3).write(0x80u8); // Enable DLAB (set baud rate divisor)
+        Port::new(COM1 + 0).write(0x03u8); // Set divisor to 3 (lo byte) 38400 baud
+        Port::new(COM1 + 1).write(0x00u8); //                  (hi byte)
+        Port::new(COM1 + 3).write(0x03u8); // 8 bits, no parity, one stop bit
+        Port::new(COM1 + 2).write(0xc7u8); // Enable FIFO, clear them, with 14-byte threshold
+        Port::new(COM1 + 4).write(0x0bu8); // IRQs enabled, RTS/DSR set
+    }
+    for cell in 0..VGA_COLS * VGA_ROWS {
+        unsafe { VGA_TEXT.add(cell).write_volatile(VGA_ATTR | b' ' as u16); }
+    }
+}
+
+fn serial_putchar(byte: u8) {
+    unsafe {
+        while Port::<u8>::new(COM1 + 5).read() & 0x20 == 0 { core::hint::spin_loop(); }
+        Port::<u8>::new(COM1).write(byte);
+    }
+}
+
+fn vga_putchar(byte: u8) {
+    let mut cursor = CURSOR.load(Ordering::Relaxed);
+    match byte {
+        b'\r' => cursor -= cursor % VGA_COLS,
+        b'\n' => cursor += VGA_COLS,
+        _ => {
+            unsafe { VGA_TEXT.add(cursor).write_volatile(VGA_ATTR | byte as u16); }
+            cursor += 1;
+        }
+    }
+    if cursor >= VGA_COLS * VGA_ROWS {
+        unsafe {
+            core::ptr::copy(VGA_TEXT.add(VGA_COLS), VGA_TEXT, VGA_COLS * (VGA_ROWS - 1));
+            for col in 0..VGA_COLS {
+                VGA_TEXT.add(VGA_COLS * (VGA_ROWS - 1) + col).write_volatile(VGA_ATTR | b' ' as u16);
+            }
+        }
+        cursor -= VGA_COLS;
+    }
+    CURSOR.store(cursor, Ordering::Relaxed);
+}
+
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            serial_putchar(byte);
+            vga_putchar(byte);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+// Real-mode entry of the second stage, jumped to by Ignitor_legacy.asm at 0x8000.
+// BIOS calls only work down here, so the memory map and the kernel file are fetched
+// first; the kernel is read in chunks through a low bounce buffer and copied above
+// 1 MiB in unreal mode. Then it is protected mode, long mode, and Rust.
+
+use core::slice;
+
+// Boot block left behind by Ignitor_legacy.asm at 0x7c00 + 0x1a0
+const MBR_INFO: usize = 0x7da0;
+const MBR_DRIVE: usize          = MBR_INFO + 2;  // u8
+const MBR_KERNEL_LBA: usize     = MBR_INFO + 4;  // u32
+const MBR_KERNEL_SECTORS: usize = MBR_INFO + 8;  // u32
+
+// Low memory the real-mode code works in, below the stage itself
+const E820_COUNT: usize = 0x0500; // u16
+const DAP: usize        = 0x0510; // Disk address packet for int 13h, ah = 42h
+const E820_MAP: usize   = 0x1000;
+const E820_MAX: usize   = 128;
+const BOUNCE: usize     = 0x70000;
+const CHUNK: usize      = 64;     // Sectors per read, 32 KiB of bounce buffer
+
+/// Where the kernel file is read to.
+pub const FILE_BASE: usize = 0x100000;
+pub const SECTOR_SIZE: usize = 512;
+
+const STACK_SIZE: usize = 0x10000;
+
+#[repr(C, align(4096))]
+struct Pages<T>(T);
+
+static mut STACK: Pages<[u8; STACK_SIZE]> = Pages([0; STACK_SIZE]);
+static mut TABLES: Pages<[[u64; 512]; 6]> = Pages([[0; 512]; 6]); // PML4, PDPT, 4 page directories
+
+/// One entry of the BIOS memory map, as int 15h, eax = E820h returns it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct E820Entry {
+    pub base: u64,
+    pub len: u64,
+    pub ty: u32,
+    pub attr: u32
+}
+
+pub mod e820 {
+    pub const AVAILABLE: u32        = 1;
+    pub const ACPI_RECLAIMABLE: u32 = 3;
+    pub const ACPI_NVS: u32         = 4;
+    pub const BAD: u32              = 5;
+}
+
+pub fn memory_map() -> &'static [E820Entry] {
+    let count = unsafe { (E820_COUNT as *const u16).read() } as usize;
+    return unsafe { slice::from_raw_parts(E820_MAP as *const E820Entry, count.min(E820_MAX)) };
+}
+
+/// The kernel file as read from disk, padded to whole sectors.
+pub fn kernel_file() -> &'static [u8] {
+    let sectors = unsafe { (MBR_KERNEL_SECTORS as *const u32).read() } as usize;
+    return unsafe { slice::from_raw_parts(FILE_BASE as *const u8, sectors * SECTOR_SIZE) };
+}
+
+/// Top of the stack the stage runs on, and the kernel starts on.
+pub fn stack_top() -> usize { unsafe { (&raw const STACK).add(1) as usize } }
+
+core::arch::global_asm!(
+    ".section .text.entry, \"ax\"",
+    ".code16",
+    ".global stage2_start",
+    "stage2_start:",
+    "cli",
+    "xor ax, ax",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov sp, 0x7c00",
+    "sti",
+    "in al, 0x92",                                      // Fast A20
+    "or al, 2",
+    "out 0x92, al",
+
+    // BIOS memory map
+    "xor ebx, ebx",
+    "xor bp, bp",
+    "mov di, {e820_map}",
+    ".Le820:",
+    "mov eax, 0xe820",
+    "mov ecx, 24",
+    "mov edx, 0x534d4150",                              // "SMAP"
+    "mov dword ptr [di + 20], 1",                       // For BIOSes that fill only 20 bytes
+    "int 0x15",
+    "jc .Le820_done",
+    "cmp eax, 0x534d4150",
+    "jne .Le820_done",
+    "add di, 24",
+    "inc bp",
+    "cmp bp, {e820_max}",
+    "jae .Le820_done",
+    "test ebx, ebx",
+    "jnz .Le820",
+    ".Le820_done:",
+    "mov word ptr [{e820_count}], bp",
+
+    // Kernel file, a chunk at a time
+    "mov eax, dword ptr [{kernel_lba}]",
+    "mov ecx, dword ptr [{kernel_sectors}]",
+    "mov edi, {file_base}",
+    ".Lload:",
+    "test ecx, ecx",
+    "jz .Lloaded",
+    "mov edx, ecx",
+    "cmp edx, {chunk}",
+    "jbe .Lread",
+    "mov edx, {chunk}",
+    ".Lread:",
+    "mov word ptr [{dap}], 0x10",
+    "mov word ptr [{dap} + 2], dx",
+    "mov dword ptr [{dap} + 4], {bounce} << 12",       // Offset 0, segment BOUNCE >> 4
+    "mov dword ptr [{dap} + 8], eax",
+    "mov dword ptr [{dap} + 12], 0",
+    "pushad",
+    "mov si, {dap}",
+    "mov dl, byte ptr [{drive}]",
+    "mov ah, 0x42",
+    "int 0x13",
+    "popad",
+    "jc .Ldisk_error",
+    "call .Lunreal",                                    // Again, since the BIOS may have undone it
+    "push ecx",
+    "mov esi, {bounce}",
+    "mov ecx, edx",
+    "shl ecx, 7",                                       // Sectors to dwords
+    "rep movsd dword ptr es:[edi], dword ptr [esi]",
+    "pop ecx",
+    "add eax, edx",
+    "sub ecx, edx",
+    "jmp .Lload",
+
+    // Unreal mode: load 4 GiB data segments in protected mode, keep them in real mode
+    ".Lunreal:",
+    "pushad",
+    "push ds",
+    "push es",
+    "cli",
+    "lgdt [.Lgdt_desc]",
+    "mov eax, cr0",
+    "or al, 1",
+    "mov cr0, eax",
+    "mov bx, 0x10",
+    "mov ds, bx",
+    "mov es, bx",
+    "and al, 0xfe",
+    "mov cr0, eax",
+    "sti",
+    "pop es",
+    "pop ds",
+    "popad",
+    "ret",
+
+    ".Ldisk_error:",
+    "mov si, offset .Ldisk_message",
+    ".Lputs:",
+    "lodsb",
+    "test al, al",
+    "jz .Lhang16",
+    "mov ah, 0x0e",
+    "int 0x10",
+    "jmp .Lputs",
+    ".Lhang16:",
+    "hlt",
+    "jmp .Lhang16",
+    ".Ldisk_message:",
+    ".asciz \"Failed to read the kernel\"",
+
+    ".Lloaded:",
+    "cli",
+    "lgdt [.Lgdt_desc]",
+    "mov eax, cr0",
+    "or al, 1",
+    "mov cr0, eax",
+    "ljmp 0x08, offset .Lprotected",
+
+    ".balign 8",
+    ".Lgdt:",
+    ".quad 0",
+    ".quad 0x00cf9a000000ffff",                         // 0x08: 32-bit code
+    ".quad 0x00cf92000000ffff",                         // 0x10: data
+    ".quad 0x00209a0000000000",                         // 0x18: 64-bit code
+    ".Lgdt_desc:",
+    ".short .Lgdt_desc - .Lgdt - 1",
+    ".long .Lgdt",
+
+    ".code32",
+    ".Lprotected:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov ss, ax",
+    "mov esp, offset {stack} + {stack_size}",
+
+    // The flat image stops where .bss begins
+    "mov edi, offset __bss_start",
+    "mov ecx, offset __bss_end",
+    "sub ecx, edi",
+    "xor eax, eax",
+    "rep stosb",
+
+    // Identity map the low 4 GiB with 2 MiB pages
+    "mov ebx, offset {tables}",
+    "lea eax, [ebx + 0x1003]",                          // PML4[0] -> PDPT
+    "mov [ebx], eax",
+    "lea eax, [ebx + 0x2003]",                          // PDPT[0..4] -> page directories
+    "mov [ebx + 0x1000], eax",
+    "add eax, 0x1000",
+    "mov [ebx + 0x1008], eax",
+    "add eax, 0x1000",
+    "mov [ebx + 0x1010], eax",
+    "add eax, 0x1000",
+    "mov [ebx + 0x1018], eax",
+    "lea edi, [ebx + 0x2000]",
+    "mov eax, 0x83",                                    // PRESENT | WRITABLE | HUGE
+    "mov ecx, 4 * 512",
+    ".Lfill:",
+    "mov [edi], eax",
+    "add eax, 0x200000",
+    "add edi, 8",
+    "dec ecx",
+    "jnz .Lfill",
+
+    // PAE, then long mode and NX in EFER, then paging
+    "mov cr3, ebx",
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, 1 << 31",
+    "mov cr0, eax",
+    "ljmp 0x18, offset .Llong_mode",
+
+    ".code64",
+    ".Llong_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov esp, esp",                                     // Upper halves are undefined after the switch
+    "call {main}",
+    "ud2",
+    ".previous",
+
+    e820_map = const E820_MAP,
+    e820_max = const E820_MAX,
+    e820_count = const E820_COUNT,
+    kernel_lba = const MBR_KERNEL_LBA,
+    kernel_sectors = const MBR_KERNEL_SECTORS,
+    drive = const MBR_DRIVE,
+    file_base = const FILE_BASE,
+    chunk = const CHUNK,
+    dap = const DAP,
+    bounce = const BOUNCE,
+    stack = sym STACK,
+    stack_size = const STACK_SIZE,
+    tables = sym TABLES,
+    main = sym crate::ignite
+);
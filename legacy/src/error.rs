@@ -0,0 +1,47 @@
+use crate::reloc::RelocError;
+use core::fmt;
+use unix_v11_ember::EmberError;
+use xmas_elf::header::Machine;
+
+#[derive(Debug)]
+pub enum StageError {
+    NoMemoryMap,
+    EmptyFile,
+    NoRoom(usize),
+    Elf(&'static str),
+    Machine(Machine),
+    NotDynamic,
+    NoSegments,
+    SegmentBounds(usize),
+    TooManySegments(usize),
+    Entry(u64),
+    Reloc(RelocError),
+    Ember(EmberError)
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoMemoryMap => write!(f, "the BIOS returned no E820 memory map"),
+            Self::EmptyFile => write!(f, "the boot block lists no kernel sectors"),
+            Self::NoRoom(size) => write!(f, "no free memory below 4 GiB for a {} KiB kernel", size / 1024),
+            Self::Elf(reason) => write!(f, "kernel is not a valid ELF file: {}", reason),
+            Self::Machine(machine) => write!(f, "kernel is built for {:?}, not X86_64", machine),
+            Self::NotDynamic => write!(f, "kernel is not position independent (ET_DYN)"),
+            Self::NoSegments => write!(f, "kernel has no loadable segments"),
+            Self::SegmentBounds(index) => write!(f, "kernel segment {} lies outside the file", index),
+            Self::TooManySegments(max) => write!(f, "kernel has more than {} loadable segments", max),
+            Self::Entry(entry) => write!(f, "kernel entry point {:#x} lies outside the image", entry),
+            Self::Reloc(err) => write!(f, "cannot relocate kernel: {}", err),
+            Self::Ember(err) => write!(f, "cannot build the handoff: {}", err)
+        }
+    }
+}
+
+impl From<RelocError> for StageError {
+    fn from(err: RelocError) -> Self { Self::Reloc(err) }
+}
+
+impl From<EmberError> for StageError {
+    fn from(err: EmberError) -> Self { Self::Ember(err) }
+}
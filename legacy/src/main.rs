@@ -0,0 +1,227 @@
+//!                         Legacy BIOS Second Stage                         !//
+//!
+//! Description: BIOS Bootloader of Research UNIX Version 11
+//! Licence: Public Domain
+
+#![no_std]
+#![no_main]
+
+mod console;
+mod entry;
+mod error;
+#[path = "../../efi/src/reloc.rs"]
+mod reloc;
+
+use core::{convert::Infallible, panic::PanicInfo};
+use entry::{e820, E820Entry, FILE_BASE};
+use error::StageError;
+use unix_v11_ember::{Acpi, EmberWriter, Kernel, RAMDescriptor, RAMLayout, Segment, Stack};
+use x86_64::instructions::{hlt, interrupts};
+use xmas_elf::{header::{self, Class, Machine}, program::Type, ElfFile};
+
+const PAGE_4KIB: usize = 0x1000;
+const MAX_SEGMENTS: usize = 16;
+const MAX_DESCRIPTORS: usize = 128;
+const LOW_4GIB: u64 = 1 << 32;
+
+// UEFI memory types, which is what the kernel expects in a RAMDescriptor
+mod efitype {
+    pub const RESERVED: u32          = 0x00;
+    pub const LOADER_CODE: u32       = 0x01;
+    pub const CONVENTIONAL: u32      = 0x07;
+    pub const UNUSABLE: u32          = 0x08;
+    pub const ACPI_RECLAIM: u32      = 0x09;
+    pub const ACPI_NON_VOLATILE: u32 = 0x0a;
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {{
+        use core::fmt::Write;
+        let _ = core::write!($crate::console::Console, $($arg)*);
+    }};
+}
+
+#[macro_export]
+macro_rules! println {
+    () => { $crate::print!("\r\n"); };
+    ($($arg:tt)*) => { $crate::print!("{}\r\n", format_args!($($arg)*)) };
+}
+
+const EMPTY_DESCRIPTOR: RAMDescriptor = RAMDescriptor {
+    ty: 0, reserved: 0, phys_start: 0, virt_start: 0, page_count: 0, attr: 0, padding: 0
+};
+
+// Both stay below 1 MiB, which the kernel never hands out
+static mut LAYOUT: [RAMDescriptor; MAX_DESCRIPTORS] = [EMPTY_DESCRIPTOR; MAX_DESCRIPTORS];
+static mut HANDOFF: [u8; PAGE_4KIB] = [0; PAGE_4KIB];
+
+pub fn align_up(val: usize, align: usize) -> usize {
+    if align == 0 { return val; }
+    return val + (align - val % align) % align;
+}
+
+pub fn halt() {
+    interrupts::disable();
+    hlt();
+}
+
+/// Same checks as the EFI loader, before anything is copied.
+fn check_elf(elf: &ElfFile, file_size: usize) -> Result<(), StageError> {
+    header::sanity_check(elf).map_err(StageError::Elf)?;
+    if elf.header.pt1.class() != Class::SixtyFour { return Err(StageError::Elf("not a 64-bit image")); }
+
+    let machine = elf.header.pt2.machine().as_machine();
+    if machine != Machine::X86_64 { return Err(StageError::Machine(machine)); }
+    if elf.header.pt2.type_().as_type() != header::Type::SharedObject { return Err(StageError::NotDynamic); }
+
+    for (index, ph) in elf.program_iter().enumerate() {
+        if ph.get_type() != Ok(Type::Load) { continue; }
+        let file_end = ph.offset().checked_add(ph.file_size());
+        let mem_end = ph.virtual_addr().checked_add(ph.mem_size());
+        if file_end.is_none_or(|end| end > file_size as u64) || mem_end.is_none() || ph.file_size() > ph.mem_size() {
+            return Err(StageError::SegmentBounds(index));
+        }
+    }
+    return Ok(());
+}
+
+/// First page-aligned stretch of available memory above `after` that holds `size` bytes.
+/// Only the low 4 GiB is mapped while we run.
+fn find_room(map: &[E820Entry], after: usize, size: usize) -> Option<usize> {
+    return map.iter()
+        .filter(|entry| entry.ty == e820::AVAILABLE)
+        .find_map(|entry| {
+            let start = align_up((entry.base as usize).max(after), PAGE_4KIB);
+            let end = (entry.base + entry.len).min(LOW_4GIB) as usize;
+            return (start + size <= end).then_some(start);
+        });
+}
+
+fn rsdp_at(addr: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, 20) };
+    return &bytes[..8] == b"RSD PTR " && bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0;
+}
+
+/// The RSDP sits on a 16-byte boundary in the first KiB of the EBDA or in the BIOS ROM.
+fn find_rsdp() -> Option<usize> {
+    let ebda = (unsafe { (0x40e as *const u16).read() } as usize) << 4;
+    let areas = [(ebda, ebda + 0x400), (0xe0000, 0x100000)];
+    return areas.into_iter()
+        .filter(|&(start, _)| start != 0)
+        .flat_map(|(start, end)| (start..end).step_by(16))
+        .find(|&addr| rsdp_at(addr));
+}
+
+/// Converts the E820 map into descriptors, with the kernel image split out of its region.
+fn translate(map: &[E820Entry], kernel_start: u64, kernel_end: u64) -> &'static [RAMDescriptor] {
+    let layout = unsafe { &mut *&raw mut LAYOUT };
+    let mut count = 0;
+    let mut push = |start: u64, end: u64, ty: u32| {
+        if start >= end || count == MAX_DESCRIPTORS { return; }
+        layout[count] = RAMDescriptor {
+            ty, reserved: 0, phys_start: start, virt_start: 0,
+            page_count: (end - start) / PAGE_4KIB as u64, attr: 0, padding: 0
+        };
+        count += 1;
+    };
+
+    let page = PAGE_4KIB as u64;
+    for entry in map {
+        let ty = match entry.ty {
+            e820::AVAILABLE        => efitype::CONVENTIONAL,
+            e820::ACPI_RECLAIMABLE => efitype::ACPI_RECLAIM,
+            e820::ACPI_NVS         => efitype::ACPI_NON_VOLATILE,
+            e820::BAD              => efitype::UNUSABLE,
+            _                      => efitype::RESERVED
+        };
+        if ty != efitype::CONVENTIONAL {
+            push(entry.base & !(page - 1), (entry.base + entry.len + page - 1) & !(page - 1), ty);
+            continue;
+        }
+
+        let start = (entry.base + page - 1) & !(page - 1);
+        let end = (entry.base + entry.len) & !(page - 1);
+        if kernel_start >= end || kernel_end <= start {
+            push(start, end, ty);
+            continue;
+        }
+        push(start, kernel_start.max(start), ty);
+        push(kernel_start.max(start), kernel_end.min(end), efitype::LOADER_CODE);
+        push(kernel_end.min(end), end, ty);
+    }
+    return &layout[..count];
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn ignite() -> ! {
+    console::init();
+    let Err(err) = boot();
+    println!("Cannot boot Research UNIX Version 11: {}", err);
+    loop { halt(); }
+}
+
+fn boot() -> Result<Infallible, StageError> {
+    let map = entry::memory_map();
+    if map.is_empty() { return Err(StageError::NoMemoryMap); }
+    let file_binary = entry::kernel_file();
+    if file_binary.is_empty() { return Err(StageError::EmptyFile); }
+
+    let elf = ElfFile::new(file_binary).map_err(StageError::Elf)?;
+    check_elf(&elf, file_binary.len())?;
+
+    let kernel_size = elf.program_iter()
+        .filter(|ph| ph.get_type() == Ok(Type::Load))
+        .map(|ph| ph.virtual_addr() + ph.mem_size())
+        .max().ok_or(StageError::NoSegments)? as usize;
+    let entry = elf.header.pt2.entry_point();
+    if entry >= kernel_size as u64 { return Err(StageError::Entry(entry)); }
+
+    let kernel_size = align_up(kernel_size, PAGE_4KIB);
+    let kernel_base = find_room(map, FILE_BASE + file_binary.len(), kernel_size)
+        .ok_or(StageError::NoRoom(kernel_size))?;
+    unsafe { core::ptr::write_bytes(kernel_base as *mut u8, 0, kernel_size); }
+
+    let mut segments = [Segment { addr: 0, size: 0, flags: 0 }; MAX_SEGMENTS];
+    let mut segment_count = 0;
+    for ph in elf.program_iter() {
+        if let Ok(Type::Load) = ph.get_type() {
+            let offset = ph.offset() as usize;
+            let file_size = ph.file_size() as usize;
+            let phys_addr = (kernel_base + ph.virtual_addr() as usize) as *mut u8;
+            unsafe { core::ptr::copy(file_binary[offset..offset + file_size].as_ptr(), phys_addr, file_size); }
+
+            if segment_count == MAX_SEGMENTS { return Err(StageError::TooManySegments(MAX_SEGMENTS)); }
+            segments[segment_count] = Segment {
+                addr: phys_addr as u64, size: ph.mem_size(), flags: ph.flags().0 as u64
+            };
+            segment_count += 1;
+        }
+    }
+
+    reloc::relocate(&elf, kernel_base)?;
+
+    let kernel_end = (kernel_base + kernel_size) as u64;
+    let layout = translate(map, kernel_base as u64, kernel_end);
+    let mut ember = EmberWriter::new(unsafe { &mut *&raw mut HANDOFF })?;
+    if let Some(rsdp) = find_rsdp() { ember.push(&Acpi { rsdp: rsdp as u64 })?; }
+    ember.push(&Kernel { base: kernel_base as u64, size: kernel_size as u64 })?;
+    ember.push_slice(&segments[..segment_count])?;
+    ember.push(&Stack { base: entry::stack_top() as u64 })?;
+    ember.push(&RAMLayout {
+        ptr: layout.as_ptr() as u64,
+        len: layout.len() as u64,
+        desc_size: size_of::<RAMDescriptor>() as u64
+    })?;
+    let ember = ember.finish()?;
+
+    let entrypoint = entry as usize + kernel_base;
+    let spark: extern "efiapi" fn(*const u8) -> ! = unsafe { core::mem::transmute(entrypoint) };
+    spark(ember.as_ptr());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("Panic: {}", info);
+    loop { halt(); }
+}
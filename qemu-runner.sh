@@ -0,0 +1,70 @@
+#!/bin/zsh
+
+# Cargo runner for the kernel: `cargo run` or `cargo test` in kernel/ boots the
+# built image in QEMU through the EFI loader, from a directory served as a FAT disk.
+# Test builds leave QEMU with their result and are stopped after $QEMU_TIMEOUT
+# seconds (300 by default); other builds run until QEMU is closed.
+
+set -e
+
+kernel=${1:A}
+cd ${0:A:h}
+
+case $kernel in
+    *aarch64*) arch=aarch64; efi_arch=aa64 ;;
+    *)         arch=x86_64;  efi_arch=x64  ;;
+esac
+
+cd efi; cargo build -r --target $arch-unknown-uefi; cd ..
+
+dist=$(mktemp -d)
+trap 'rm -rf $dist' EXIT
+mkdir -p $dist/efi/boot
+cp target/$arch-unknown-uefi/release/unix-v11-efi.efi $dist/efi/boot/boot$efi_arch.efi
+cp $kernel $dist/unix-v11
+echo 'timeout 0' > $dist/unix-v11.cfg
+
+drive=(-drive file=fat:$dist,if=none,id=drv0,format=raw -device nvme,drive=drv0,serial=unixv11nvme)
+if [[ $arch == x86_64 ]]; then
+    qemu=(qemu-system-x86_64 -cpu Skylake-Client -machine q35 -smp 1 -bios OVMF-AMD64.fd $drive -m 512M
+          -device isa-debug-exit,iobase=0xf4,iosize=0x04 -serial stdio -display none -no-reboot)
+else
+    qemu=(qemu-system-aarch64 -cpu cortex-a72 -machine virt,accel=tcg -smp 1 -bios OVMF-AArch64.fd $drive -m 512M
+          -semihosting -serial stdio -display none -no-reboot)
+fi
+
+# Cargo puts test binaries under target/<triple>/<profile>/deps/. Those get a
+# watchdog here rather than timeout(1), which macOS does not ship
+testing=0
+[[ $kernel == */deps/* ]] && testing=1
+result=0
+if (( testing )); then
+    $qemu &
+    pid=$!
+    {
+        sleep ${QEMU_TIMEOUT:-300}
+        touch $dist/timed-out
+        kill $pid; sleep 10; kill -9 $pid
+    } </dev/null >/dev/null 2>&1 &
+    watchdog=$!
+    wait $pid || result=$?
+    kill $watchdog 2>/dev/null || true
+else
+    $qemu || result=$?
+fi
+
+# The harness leaves with 33 or 35, see exit_qemu. A test kernel passes only with 33:
+# a triple fault or reset under -no-reboot also ends QEMU with 0
+if (( testing )); then
+    [[ -e $dist/timed-out ]] && echo "qemu-runner: test kernel timed out" >&2
+    case $result in
+        33) result=0 ;;
+        *)  result=1 ;;
+    esac
+else
+    case $result in
+        33) result=0 ;;
+        35) result=1 ;;
+    esac
+fi
+exit $result